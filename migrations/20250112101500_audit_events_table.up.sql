@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE audit_events (
+    id UUID NOT NULL PRIMARY KEY DEFAULT (uuid_generate_v4()),
+    action VARCHAR(64) NOT NULL,
+    actor_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    target_id UUID,
+    ip_address VARCHAR(64),
+    user_agent TEXT,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_events_created_at_index ON audit_events(created_at, id);
+CREATE INDEX audit_events_actor_id_index ON audit_events(actor_id);
+CREATE INDEX audit_events_target_id_index ON audit_events(target_id);
+CREATE INDEX audit_events_action_index ON audit_events(action);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS users_deleted_at_index;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS deleted_at,
+    DROP COLUMN IF EXISTS disabled;
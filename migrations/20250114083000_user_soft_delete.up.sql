@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE users
+    ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX users_deleted_at_index ON users(deleted_at) WHERE deleted_at IS NOT NULL;
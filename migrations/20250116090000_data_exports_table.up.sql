@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TYPE data_export_status AS ENUM ('pending', 'ready', 'failed');
+
+CREATE TABLE data_exports (
+    id UUID NOT NULL PRIMARY KEY DEFAULT (uuid_generate_v4()),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    status data_export_status NOT NULL DEFAULT 'pending',
+    payload JSONB,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    completed_at TIMESTAMP WITH TIME ZONE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX data_exports_user_id_created_at_index ON data_exports(user_id, created_at DESC);
+CREATE INDEX data_exports_expires_at_index ON data_exports(expires_at);
+-- At most one export per user is built at a time.
+CREATE UNIQUE INDEX data_exports_one_pending_per_user ON data_exports(user_id) WHERE status = 'pending';
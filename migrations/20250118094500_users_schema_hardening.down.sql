@@ -0,0 +1,19 @@
+-- Add down migration script here
+UPDATE users u
+SET email = c.email
+FROM user_email_conflicts c
+WHERE u.id = c.user_id
+  AND c.reason = 'case_duplicate'
+  AND u.email = 'conflict-' || u.id || '@invalid';
+
+DROP INDEX IF EXISTS users_email_lower_unique;
+DROP TABLE IF EXISTS user_email_conflicts;
+ALTER TABLE users ADD CONSTRAINT users_email_key UNIQUE (email);
+CREATE INDEX IF NOT EXISTS users_email_index ON users(email);
+
+DROP TRIGGER IF EXISTS users_set_updated_at ON users;
+DROP FUNCTION IF EXISTS set_updated_at();
+
+ALTER TABLE users
+    ALTER COLUMN created_at DROP NOT NULL,
+    ALTER COLUMN updated_at DROP NOT NULL;
@@ -0,0 +1,63 @@
+-- Add up migration script here
+UPDATE users SET created_at = NOW() WHERE created_at IS NULL;
+UPDATE users SET updated_at = created_at WHERE updated_at IS NULL;
+
+ALTER TABLE users
+    ALTER COLUMN created_at SET NOT NULL,
+    ALTER COLUMN updated_at SET NOT NULL;
+
+CREATE OR REPLACE FUNCTION set_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = NOW();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_set_updated_at
+    BEFORE UPDATE ON users
+    FOR EACH ROW
+    WHEN (OLD.* IS DISTINCT FROM NEW.*)
+    EXECUTE FUNCTION set_updated_at();
+
+-- Emails that only differ by case belong to the same person and have to be
+-- merged by hand. Rather than fail the migration, every account involved is
+-- recorded in user_email_conflicts with the address it had. The oldest of each
+-- group keeps the address; the others are renamed to conflict-<id>@invalid, so
+-- that every address is unique whatever its case and a login by email finds
+-- at most one account.
+CREATE TABLE user_email_conflicts (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    email VARCHAR(255) NOT NULL,
+    normalized_email VARCHAR(255) NOT NULL,
+    reason VARCHAR(32) NOT NULL,
+    detected_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO user_email_conflicts (user_id, email, normalized_email, reason)
+SELECT id, email, lower(email), 'case_duplicate'
+FROM users
+WHERE lower(email) IN (SELECT lower(email) FROM users GROUP BY lower(email) HAVING COUNT(*) > 1);
+
+UPDATE users u
+SET email = 'conflict-' || u.id || '@invalid'
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY lower(email) ORDER BY created_at, id) AS n
+    FROM users
+    WHERE id IN (SELECT user_id FROM user_email_conflicts)
+) ranked
+WHERE u.id = ranked.id
+  AND ranked.n > 1;
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM user_email_conflicts) THEN
+        RAISE WARNING 'users shared an email that only differs by case; see user_email_conflicts';
+    END IF;
+END $$;
+
+CREATE UNIQUE INDEX users_email_lower_unique ON users (lower(email));
+
+-- Lookups by email now go through lower(email), and the index above covers
+-- what the case-sensitive constraint did.
+DROP INDEX IF EXISTS users_email_index;
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_email_key;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DELETE FROM user_email_conflicts WHERE reason = 'normalization';
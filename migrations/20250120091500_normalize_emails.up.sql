@@ -0,0 +1,47 @@
+-- Add up migration script here
+-- Brings stored emails into the form the app now normalizes to: surrounding
+-- whitespace dropped and the domain lowercased. Addresses that would collide
+-- with another user once normalized are left untouched and recorded in
+-- user_email_conflicts, next to the case-only duplicates found earlier, for an
+-- operator to resolve.
+CREATE TEMPORARY TABLE normalized_emails AS
+SELECT
+    id,
+    email,
+    CASE
+        WHEN position('@' IN btrim(email)) > 0 THEN
+            substring(btrim(email) FROM '^(.*)@[^@]*$')
+            || '@'
+            || lower(substring(btrim(email) FROM '@([^@]*)$'))
+        ELSE btrim(email)
+    END AS normalized
+FROM users;
+
+INSERT INTO user_email_conflicts (user_id, email, normalized_email, reason)
+SELECT n.id, n.email, n.normalized, 'normalization'
+FROM normalized_emails n
+WHERE EXISTS (
+    SELECT 1
+    FROM normalized_emails other
+    WHERE other.id <> n.id
+      AND lower(other.normalized) = lower(n.normalized)
+)
+ON CONFLICT (user_id) DO NOTHING;
+
+UPDATE users u
+SET email = n.normalized
+FROM normalized_emails n
+WHERE u.id = n.id
+  AND u.email <> n.normalized
+  AND NOT EXISTS (SELECT 1 FROM user_email_conflicts c WHERE c.user_id = u.id);
+
+DO $$
+DECLARE
+    conflicts INTEGER;
+BEGIN
+    SELECT count(*) INTO conflicts FROM user_email_conflicts WHERE reason = 'normalization';
+    IF conflicts > 0 THEN
+        RAISE WARNING '% users have emails that collide once normalized; see user_email_conflicts', conflicts;
+    END IF;
+END $$;
+DROP TABLE normalized_emails;
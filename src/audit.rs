@@ -0,0 +1,90 @@
+use actix_web::{http::header, HttpRequest};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    db::{AuditExt, NewAuditEvent},
+    models::AuditAction,
+    utils, AppState,
+};
+
+/// Records security-relevant events together with where the request came
+/// from. Recording never fails the request: a lost audit row is logged, but
+/// the user's action has already happened and should still get its response.
+/// Use [`record_in`](Self::record_in) when the action must not happen without
+/// its audit event.
+pub struct AuditRecorder<'a> {
+    app_state: &'a AppState,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl<'a> AuditRecorder<'a> {
+    pub fn new(app_state: &'a AppState, req: &HttpRequest) -> Self {
+        AuditRecorder {
+            app_state,
+            ip_address: utils::client::address(req, &app_state.env.trusted_proxies)
+                .map(|ip| ip.to_string()),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+
+    /// A recorder for events that happen outside of any request, such as
+    /// background jobs.
+    pub fn system(app_state: &'a AppState) -> Self {
+        AuditRecorder {
+            app_state,
+            ip_address: None,
+            user_agent: None,
+        }
+    }
+
+    pub async fn record(
+        &self,
+        action: AuditAction,
+        actor_id: Option<Uuid>,
+        target_id: Option<Uuid>,
+        metadata: Value,
+    ) {
+        let event = self.event(action, actor_id, target_id, metadata);
+
+        if let Err(e) = self.app_state.db_client.save_audit_event(event).await {
+            tracing::error!(error = %e, action = action.to_str(), "Failed to record audit event");
+        }
+    }
+
+    /// Records the event in `tx`, typically a transaction that also makes the
+    /// change being audited, and returns any error so that it fails as one.
+    pub async fn record_in<R: AuditExt + ?Sized>(
+        &self,
+        tx: &R,
+        action: AuditAction,
+        actor_id: Option<Uuid>,
+        target_id: Option<Uuid>,
+        metadata: Value,
+    ) -> Result<(), sqlx::Error> {
+        tx.save_audit_event(self.event(action, actor_id, target_id, metadata))
+            .await
+    }
+
+    fn event(
+        &self,
+        action: AuditAction,
+        actor_id: Option<Uuid>,
+        target_id: Option<Uuid>,
+        metadata: Value,
+    ) -> NewAuditEvent<'static> {
+        NewAuditEvent {
+            action: action.to_str(),
+            actor_id,
+            target_id,
+            ip_address: self.ip_address.clone(),
+            user_agent: self.user_agent.clone(),
+            metadata,
+        }
+    }
+}
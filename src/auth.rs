@@ -1,13 +1,16 @@
 use std::{
+    fmt,
+    net::IpAddr,
     rc::Rc,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use actix_web::{
     body,
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
     error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
-    http, web, FromRequest, HttpMessage,
+    http, web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
 };
 use futures_util::{
     future::{ready, LocalBoxFuture, Ready},
@@ -15,30 +18,39 @@ use futures_util::{
 };
 
 use crate::{
-    db::UserExt,
+    csrf,
     error::{ErrorMessage, ErrorResponse, HttpError},
+    metrics::METRICS,
     models::{User, UserRole},
-    utils, AppState,
+    request_id,
+    utils::{
+        self,
+        authorization::{self, Credentials},
+    },
+    AppState,
 };
 
+/// The authenticated user. Works behind [`RequireAuth`], which has already
+/// resolved the user, or on its own, in which case it authenticates the
+/// request itself and answers 401 when no valid credentials were sent.
 pub struct Authenticated(User);
 
 impl FromRequest for Authenticated {
     type Error = actix_web::Error;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
     fn from_request(
         req: &actix_web::HttpRequest,
         _payload: &mut actix_web::dev::Payload,
     ) -> Self::Future {
-        let value = req.extensions().get::<User>().cloned();
-        let result = match value {
-            Some(user) => Ok(Authenticated(user)),
-            None => Err(ErrorInternalServerError(HttpError::server_error(
-                "Authentication error",
-            ))),
-        };
-        ready(result)
+        let req = req.clone();
+        async move {
+            current_user(&req)
+                .await?
+                .map(Authenticated)
+                .ok_or_else(|| unauthorized(ErrorMessage::TokenNotProvided))
+        }
+        .boxed_local()
     }
 }
 
@@ -50,6 +62,45 @@ impl std::ops::Deref for Authenticated {
     }
 }
 
+/// Like [`Authenticated`], but anonymous requests get `None` instead of a 401.
+/// Credentials that are sent but invalid are still rejected.
+pub struct OptionalAuthenticated(pub Option<User>);
+
+impl FromRequest for OptionalAuthenticated {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(
+        req: &actix_web::HttpRequest,
+        _payload: &mut actix_web::dev::Payload,
+    ) -> Self::Future {
+        let req = req.clone();
+        async move { current_user(&req).await.map(OptionalAuthenticated) }.boxed_local()
+    }
+}
+
+impl std::ops::Deref for OptionalAuthenticated {
+    type Target = Option<User>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Returns the user already resolved for this request, or authenticates it
+/// and remembers the result so later extractors don't hit the database again.
+async fn current_user(req: &HttpRequest) -> Result<Option<User>, actix_web::Error> {
+    if let Some(user) = req.extensions().get::<User>().cloned() {
+        return Ok(Some(user));
+    }
+
+    let user = authenticate(req).await?;
+    if let Some(user) = &user {
+        req.extensions_mut().insert::<User>(user.clone());
+    }
+    Ok(user)
+}
+
 pub struct RequireAuth {
     pub allaow_roles: Rc<Vec<UserRole>>,
 }
@@ -103,53 +154,13 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let token = req
-            .cookie("token")
-            .map(|c| c.value().to_string())
-            .or_else(|| {
-                req.headers()
-                    .get(http::header::AUTHORIZATION)
-                    .map(|h| h.to_str().unwrap().split_at(7).1.to_string())
-            });
-
-        if token.is_none() {
-            let json_error = ErrorResponse {
-                status: "fail".to_string(),
-                message: ErrorMessage::TokenNotProvided.to_string(),
-            };
-            return Box::pin(ready(Err(ErrorUnauthorized(json_error))));
-        }
-
-        let app_state = req.app_data::<web::Data<AppState>>().unwrap();
-        let user_id = match utils::token::decode_token(
-            &token.unwrap(),
-            app_state.env.jwt_secret.as_bytes(),
-        ) {
-            Ok(id) => id,
-            Err(e) => {
-                return Box::pin(ready(Err(ErrorUnauthorized(ErrorResponse {
-                    status: "fail".to_string(),
-                    message: e.message,
-                }))));
-            }
-        };
-
-        let cloned_app_state = app_state.clone();
         let allow_roles = self.allow_roles.clone();
         let srv = Rc::clone(&self.service);
 
         async move {
-            let user_id = uuid::Uuid::parse_str(user_id.as_str()).unwrap();
-            let result = cloned_app_state
-                .db_client
-                .get_user(Some(user_id.clone()), None, None)
-                .await
-                .map_err(|e| ErrorInternalServerError(HttpError::server_error(e.to_string())))?;
-
-            let user = result.ok_or(ErrorUnauthorized(ErrorResponse {
-                status: "fail".to_string(),
-                message: ErrorMessage::UserNoLongerExist.to_string(),
-            }))?;
+            let user = authenticate(req.request())
+                .await?
+                .ok_or_else(|| unauthorized(ErrorMessage::TokenNotProvided))?;
 
             if allow_roles.contains(&user.role) {
                 req.extensions_mut().insert::<User>(user);
@@ -166,3 +177,195 @@ where
         .boxed_local()
     }
 }
+
+/// Where the credentials of a request came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthSource {
+    Cookie,
+    Header,
+}
+
+/// Basic auth refused because the client failed too often recently.
+#[derive(Debug)]
+struct TooManyFailedAttempts {
+    retry_after: Duration,
+}
+
+impl fmt::Display for TooManyFailedAttempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", ErrorMessage::TooManyFailedAttempts)
+    }
+}
+
+impl ResponseError for TooManyFailedAttempts {
+    fn error_response(&self) -> HttpResponse<body::BoxBody> {
+        HttpResponse::TooManyRequests()
+            .insert_header((
+                http::header::RETRY_AFTER,
+                self.retry_after.as_secs().max(1).to_string(),
+            ))
+            .json(ErrorResponse {
+                status: "fail".to_string(),
+                message: ErrorMessage::TooManyFailedAttempts.to_string(),
+            })
+    }
+}
+
+fn unauthorized(message: ErrorMessage) -> actix_web::Error {
+    ErrorUnauthorized(ErrorResponse {
+        status: "fail".to_string(),
+        message: message.to_string(),
+    })
+}
+
+/// Picks the credentials to authenticate `req` with. An `Authorization` header
+/// always takes precedence over the auth cookie: a client that sends one is
+/// explicitly choosing who it acts as, while the cookie may just be left over
+/// in the browser. A malformed header is rejected rather than falling back to
+/// the cookie.
+fn request_credentials(
+    req: &HttpRequest,
+    app_state: &AppState,
+) -> Result<Option<(Credentials, AuthSource)>, actix_web::Error> {
+    if let Some(header) = req.headers().get(http::header::AUTHORIZATION) {
+        let credentials = authorization::parse(header).map_err(unauthorized)?;
+        return Ok(Some((credentials, AuthSource::Header)));
+    }
+
+    if app_state.env.cookie.enabled {
+        if let Some(cookie) = req.cookie(&app_state.env.cookie.cookie_name()) {
+            return Ok(Some((
+                Credentials::Bearer(cookie.value().to_string()),
+                AuthSource::Cookie,
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Authenticates `req` from its credentials. Returns `None` when it carries
+/// none, and an error when they are invalid.
+pub async fn authenticate(req: &HttpRequest) -> Result<Option<User>, actix_web::Error> {
+    let app_state = req
+        .app_data::<web::Data<AppState>>()
+        .ok_or_else(|| ErrorInternalServerError(HttpError::server_error("Missing app state")))?
+        .clone();
+
+    let Some((credentials, source)) = request_credentials(req, &app_state)? else {
+        return Ok(None);
+    };
+
+    // Browsers attach cookies to cross-site requests on their own, so
+    // state-changing requests authenticated that way must prove they came
+    // from our frontend. Header credentials are never sent automatically.
+    if source == AuthSource::Cookie
+        && app_state.env.csrf.enabled
+        && !csrf::is_safe_method(req.method())
+        && !csrf::verify(req, &app_state.env)
+    {
+        return Err(ErrorForbidden(ErrorResponse {
+            status: "fail".to_string(),
+            message: ErrorMessage::CsrfTokenInvalid.to_string(),
+        }));
+    }
+
+    let client = utils::client::address(req, &app_state.env.trusted_proxies);
+    let user = resolve_user(&app_state, credentials, client).await?;
+    request_id::record_user(user.id);
+    Ok(Some(user))
+}
+
+async fn resolve_user(
+    app_state: &AppState,
+    credentials: Credentials,
+    client: Option<IpAddr>,
+) -> Result<User, actix_web::Error> {
+    let server_error =
+        |e: sqlx::Error| ErrorInternalServerError(HttpError::server_error(e.to_string()));
+
+    match credentials {
+        Credentials::Bearer(token) => {
+            let user_id = utils::token::decode_token(token, app_state.env.jwt.secret.as_bytes())
+                .map_err(|e| {
+                    METRICS.token_validation_failure("invalid_token");
+                    ErrorUnauthorized(ErrorResponse {
+                        status: "fail".to_string(),
+                        message: e.message,
+                    })
+                })?;
+            let user_id = uuid::Uuid::parse_str(&user_id).map_err(|_| {
+                METRICS.token_validation_failure("invalid_token");
+                unauthorized(ErrorMessage::InvalidToken)
+            })?;
+
+            app_state
+                .db_client
+                .get_user(Some(user_id), None, None)
+                .await
+                .map_err(server_error)?
+                .ok_or_else(|| {
+                    METRICS.token_validation_failure("unknown_user");
+                    unauthorized(ErrorMessage::UserNoLongerExist)
+                })
+        }
+        Credentials::ApiKey(key) => app_state
+            .db_client
+            .get_user_by_api_key(&utils::api_key::hash(&key))
+            .await
+            .map_err(server_error)?
+            .ok_or_else(|| {
+                METRICS.token_validation_failure("invalid_api_key");
+                unauthorized(ErrorMessage::InvalidToken)
+            }),
+        Credentials::Basic { email, password } => {
+            // Without an address there is nothing to throttle by, so refuse
+            // rather than let guesses through unlimited.
+            let Some(client) = client else {
+                METRICS.token_validation_failure("basic_auth_unknown_client");
+                return Err(unauthorized(ErrorMessage::ClientAddressUnknown));
+            };
+            let throttle = &app_state.basic_auth_failures;
+            if let Err(retry_after) = throttle.reserve(client, &app_state.env.basic_auth) {
+                METRICS.token_validation_failure("basic_auth_throttled");
+                return Err(TooManyFailedAttempts { retry_after }.into());
+            }
+
+            let email = utils::email::normalize(&email, &app_state.env.email);
+            let user = app_state
+                .db_client
+                .get_user(None, None, Some(&email))
+                .await
+                .map_err(|e| {
+                    throttle.release(client);
+                    server_error(e)
+                })?;
+
+            let password_match = match &user {
+                Some(user) => utils::password::compare_blocking(
+                    password,
+                    user.password.clone(),
+                    &app_state.env.password,
+                )
+                .await
+                .unwrap_or(false),
+                None => {
+                    utils::password::dummy_compare_blocking(password, &app_state.env.password)
+                        .await;
+                    false
+                }
+            };
+
+            match user.filter(|_| password_match) {
+                Some(user) => {
+                    throttle.release(client);
+                    Ok(user)
+                }
+                None => {
+                    METRICS.token_validation_failure("invalid_basic_credentials");
+                    Err(unauthorized(ErrorMessage::WrongCredentials))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::{net::Ipv6Addr, rc::Rc};
+
+use actix_cors::Cors;
+use actix_web::http::{header::HeaderName, Method};
+
+use crate::config::CorsConfig;
+
+/// An allowed origin such as `https://app.example.com`, or a wildcard pattern
+/// such as `https://*.example.com` that matches any subdomain (but not the
+/// bare domain itself).
+#[derive(Debug, Clone, PartialEq)]
+pub struct OriginPattern {
+    scheme: String,
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HostPattern {
+    Exact(String),
+    Subdomain(String),
+}
+
+impl OriginPattern {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (scheme, rest) = value
+            .split_once("://")
+            .ok_or_else(|| format!("{:?} is missing a scheme", value))?;
+
+        let scheme = scheme.to_ascii_lowercase();
+        if scheme != "http" && scheme != "https" {
+            return Err(format!("{:?} must use http or https", value));
+        }
+
+        if rest.contains(['/', '?', '#', '@']) {
+            return Err(format!(
+                "{:?} must not contain a path, query or credentials",
+                value
+            ));
+        }
+
+        // A bracketed IPv6 host contains colons of its own, so the port is
+        // whatever follows the closing bracket.
+        let (host, port) = match rest.strip_prefix('[') {
+            Some(bracketed) => {
+                let (address, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("{:?} has an invalid host", value))?;
+                let port = match port {
+                    "" => None,
+                    port => Some(
+                        port.strip_prefix(':')
+                            .ok_or_else(|| format!("{:?} has an invalid port", value))?,
+                    ),
+                };
+                (parse_ipv6_host(address, value)?, port)
+            }
+            None => match rest.rsplit_once(':') {
+                Some((host, port)) => (parse_host(host, value)?, Some(port)),
+                None => (parse_host(rest, value)?, None),
+            },
+        };
+        let port = port
+            .map(|port| {
+                port.parse::<u16>()
+                    .map_err(|_| format!("{:?} has an invalid port", value))
+            })
+            .transpose()?;
+
+        Ok(OriginPattern { scheme, host, port })
+    }
+
+    pub fn matches(&self, origin: &str) -> bool {
+        let Ok(origin) = OriginPattern::parse(origin) else {
+            return false;
+        };
+
+        if origin.scheme != self.scheme || origin.port != self.port {
+            return false;
+        }
+
+        match (&self.host, &origin.host) {
+            (HostPattern::Exact(allowed), HostPattern::Exact(host)) => allowed == host,
+            (HostPattern::Subdomain(domain), HostPattern::Exact(host)) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            _ => false,
+        }
+    }
+}
+
+/// A DNS name or IPv4 address, or `*.` followed by a domain.
+fn parse_host(host: &str, value: &str) -> Result<HostPattern, String> {
+    let host = host.to_ascii_lowercase();
+    let host = match host.strip_prefix("*.") {
+        Some(domain) => HostPattern::Subdomain(domain.to_string()),
+        None => HostPattern::Exact(host),
+    };
+
+    let domain = match &host {
+        HostPattern::Exact(domain) | HostPattern::Subdomain(domain) => domain,
+    };
+    let valid_host = !domain.is_empty()
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid_host {
+        return Err(format!("{:?} has an invalid host", value));
+    }
+
+    Ok(host)
+}
+
+/// The inside of `[...]`, kept in canonical form so that equivalent spellings
+/// of one address match.
+fn parse_ipv6_host(address: &str, value: &str) -> Result<HostPattern, String> {
+    let address = address
+        .parse::<Ipv6Addr>()
+        .map_err(|_| format!("{:?} has an invalid host", value))?;
+    Ok(HostPattern::Exact(format!("[{}]", address)))
+}
+
+/// Appends a message to `errors` for every value in `config` that would make
+/// the CORS middleware misbehave, so bad settings are caught at startup.
+pub fn validate(config: &CorsConfig, errors: &mut Vec<String>) {
+    let any_origin = config.allowed_origins.iter().any(|origin| origin == "*");
+
+    for origin in config
+        .allowed_origins
+        .iter()
+        .filter(|origin| *origin != "*")
+    {
+        if let Err(e) = OriginPattern::parse(origin) {
+            errors.push(format!("cors.allowed_origins: {}", e));
+        }
+    }
+
+    if any_origin && config.allow_credentials {
+        errors.push(
+            "cors.allowed_origins: \"*\" cannot be combined with cors.allow_credentials"
+                .to_string(),
+        );
+    }
+
+    for method in &config.allowed_methods {
+        if Method::from_bytes(method.as_bytes()).is_err() {
+            errors.push(format!(
+                "cors.allowed_methods: {:?} is not a method",
+                method
+            ));
+        }
+    }
+
+    for header in config
+        .allowed_headers
+        .iter()
+        .chain(config.exposed_headers.iter())
+    {
+        if HeaderName::from_bytes(header.as_bytes()).is_err() {
+            errors.push(format!("cors: {:?} is not a valid header name", header));
+        }
+    }
+}
+
+/// Builds the CORS middleware. `config` must have passed [`validate`].
+pub fn build(config: &CorsConfig) -> Cors {
+    let mut cors = if config.allowed_origins.iter().any(|origin| origin == "*") {
+        Cors::default().allow_any_origin()
+    } else {
+        let patterns: Rc<Vec<OriginPattern>> = Rc::new(
+            config
+                .allowed_origins
+                .iter()
+                .filter_map(|origin| OriginPattern::parse(origin).ok())
+                .collect(),
+        );
+
+        Cors::default().allowed_origin_fn(move |origin, _req| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| patterns.iter().any(|pattern| pattern.matches(origin)))
+        })
+    };
+
+    cors = cors
+        .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(config.allowed_headers.iter().map(String::as_str))
+        .max_age(config.max_age);
+
+    if !config.exposed_headers.is_empty() {
+        cors = cors.expose_headers(config.exposed_headers.iter().map(String::as_str));
+    }
+
+    if config.allow_credentials {
+        cors = cors.supports_credentials();
+    }
+
+    cors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(value: &str) -> OriginPattern {
+        OriginPattern::parse(value).unwrap()
+    }
+
+    #[test]
+    fn exact_origin_matches_itself_only() {
+        let allowed = pattern("https://app.example.com");
+        assert!(allowed.matches("https://app.example.com"));
+        assert!(allowed.matches("HTTPS://App.Example.com"));
+        assert!(!allowed.matches("https://other.example.com"));
+        assert!(!allowed.matches("https://app.example.com.evil.com"));
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_but_not_the_bare_domain() {
+        let allowed = pattern("https://*.example.com");
+        assert!(allowed.matches("https://app.example.com"));
+        assert!(allowed.matches("https://a.b.example.com"));
+        assert!(!allowed.matches("https://example.com"));
+        assert!(!allowed.matches("https://badexample.com"));
+    }
+
+    #[test]
+    fn port_must_match() {
+        let allowed = pattern("http://localhost:3000");
+        assert!(allowed.matches("http://localhost:3000"));
+        assert!(!allowed.matches("http://localhost:3001"));
+        assert!(!allowed.matches("http://localhost"));
+        assert!(!pattern("http://localhost").matches("http://localhost:3000"));
+    }
+
+    #[test]
+    fn scheme_must_match() {
+        let allowed = pattern("https://app.example.com");
+        assert!(!allowed.matches("http://app.example.com"));
+        assert!(!pattern("https://*.example.com").matches("http://app.example.com"));
+    }
+
+    #[test]
+    fn bracketed_ipv6_hosts_are_parsed() {
+        let allowed = pattern("http://[::1]:3000");
+        assert!(allowed.matches("http://[::1]:3000"));
+        assert!(allowed.matches("http://[0:0:0:0:0:0:0:1]:3000"));
+        assert!(!allowed.matches("http://[::1]:3001"));
+        assert!(!allowed.matches("http://[::2]:3000"));
+        assert!(pattern("http://[::1]").matches("http://[::1]"));
+    }
+
+    #[test]
+    fn invalid_origins_are_rejected() {
+        for value in [
+            "app.example.com",
+            "ftp://app.example.com",
+            "https://app.example.com/path",
+            "https://app.example.com:port",
+            "https://app.example.com:70000",
+            "https://",
+            "https://app..example.com",
+            "http://[::1",
+            "http://[::1]3000",
+            "http://[not-an-address]:3000",
+            "http://::1:3000",
+        ] {
+            assert!(
+                OriginPattern::parse(value).is_err(),
+                "{} was accepted",
+                value
+            );
+        }
+    }
+}
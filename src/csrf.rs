@@ -0,0 +1,50 @@
+//! Double-submit-cookie CSRF protection. `GET /api/auth/csrf` sets a random
+//! token in a cookie readable by the frontend, which must echo it back in the
+//! CSRF header on every unsafe request authenticated through the auth cookie.
+//! A cross-site attacker can make the browser send the cookies but cannot read
+//! them, so it cannot produce the header.
+
+use actix_web::{http::Method, HttpRequest};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+pub fn generate_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+pub fn cookie_name(config: &Config) -> String {
+    if config.cookie.host_prefix {
+        format!("__Host-{}", config.csrf.cookie_name)
+    } else {
+        config.csrf.cookie_name.clone()
+    }
+}
+
+pub fn is_safe_method(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Returns `true` when the request carries a CSRF header matching its CSRF
+/// cookie.
+pub fn verify(req: &HttpRequest, config: &Config) -> bool {
+    let Some(cookie) = req.cookie(&cookie_name(config)) else {
+        return false;
+    };
+    let Some(header) = req
+        .headers()
+        .get(config.csrf.header_name.as_str())
+        .and_then(|h| h.to_str().ok())
+    else {
+        return false;
+    };
+
+    !cookie.value().is_empty() && constant_time_eq(cookie.value().as_bytes(), header.as_bytes())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,17 +1,296 @@
-use crate::models::{User, UserRole};
+use crate::{
+    migrations::{self, MigrationStatus},
+    models::{ApiKey, AuditEvent, DataExport, DataExportStatus, User, UserRole},
+};
 
+use std::{
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::rt;
 use async_trait::async_trait;
-use sqlx::{Pool, Postgres};
+use chrono::{DateTime, Utc};
+use futures_util::lock::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+use sqlx::{migrate::MigrateError, pool::PoolConnection, PgConnection, PgExecutor, Pool, Postgres};
 use uuid::Uuid;
 
+#[cfg(test)]
+pub mod memory;
+pub mod replica;
+
+use replica::Replicas;
+
+/// Everything the handlers need from persistence. `AppState` holds it as a
+/// trait object, so the Postgres-backed [`DbClient`] can be swapped for the
+/// in-memory store in tests.
+pub trait Repository:
+    UserExt
+    + ApiKeyExt
+    + AuditExt
+    + DataExportExt
+    + HealthExt
+    + TransactionExt
+    + fmt::Debug
+    + Send
+    + Sync
+{
+}
+
+impl<T> Repository for T where
+    T: UserExt
+        + ApiKeyExt
+        + AuditExt
+        + DataExportExt
+        + HealthExt
+        + TransactionExt
+        + fmt::Debug
+        + Send
+        + Sync
+{
+}
+
+/// Postgres-backed repository. Writes go to `pool`, the primary; user
+/// lookups and listings go to a read replica when there is one. A client
+/// returned by [`TransactionExt::begin`] runs everything, reads included, in
+/// its transaction on the primary.
 #[derive(Debug, Clone)]
 pub struct DbClient {
     pub pool: Pool<Postgres>,
+    pub replicas: Replicas,
+    tx: Option<TxHandle>,
 }
 
 impl DbClient {
     pub fn new(pool: Pool<Postgres>) -> Self {
-        Self { pool }
+        Self {
+            pool,
+            replicas: Replicas::default(),
+            tx: None,
+        }
+    }
+
+    pub fn with_replicas(mut self, replicas: Replicas) -> Self {
+        self.replicas = replicas;
+        self
+    }
+
+    /// A connection to run the next query on: the transaction's, or one from
+    /// the pool.
+    async fn conn(&self) -> Result<Conn<'_>, sqlx::Error> {
+        match &self.tx {
+            None => Ok(Conn::Pooled(self.pool.acquire().await?)),
+            Some(handle) => {
+                let guard = handle.tx.lock().await;
+                if guard.is_none() {
+                    return Err(transaction_finished());
+                }
+                Ok(Conn::Transaction(guard))
+            }
+        }
+    }
+
+    /// The replica for a read that may be slightly stale. Never one inside a
+    /// transaction.
+    fn replica(&self) -> Option<&replica::Replica> {
+        match self.tx {
+            Some(_) => None,
+            None => self.replicas.pick(),
+        }
+    }
+}
+
+/// The transaction a [`DbClient`] runs its queries in. Empty once committed
+/// or rolled back.
+#[derive(Clone)]
+struct TxHandle {
+    tx: Arc<AsyncMutex<Option<sqlx::Transaction<'static, Postgres>>>>,
+    /// False when `begin` was called inside a transaction: the handle joins
+    /// the outer transaction, which commits or rolls back for both.
+    owner: bool,
+}
+
+impl fmt::Debug for TxHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TxHandle")
+            .field("owner", &self.owner)
+            .finish_non_exhaustive()
+    }
+}
+
+enum Conn<'a> {
+    Pooled(PoolConnection<Postgres>),
+    Transaction(AsyncMutexGuard<'a, Option<sqlx::Transaction<'static, Postgres>>>),
+}
+
+impl Deref for Conn<'_> {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        match self {
+            Conn::Pooled(conn) => conn,
+            Conn::Transaction(tx) => tx.as_ref().expect("checked in DbClient::conn"),
+        }
+    }
+}
+
+impl DerefMut for Conn<'_> {
+    fn deref_mut(&mut self) -> &mut PgConnection {
+        match self {
+            Conn::Pooled(conn) => conn,
+            Conn::Transaction(tx) => tx.as_mut().expect("checked in DbClient::conn"),
+        }
+    }
+}
+
+fn transaction_finished() -> sqlx::Error {
+    sqlx::Error::Protocol("the transaction was already committed or rolled back".to_string())
+}
+
+/// Transaction isolation level, see the Postgres documentation on
+/// `SET TRANSACTION`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Isolation {
+    #[default]
+    ReadCommitted,
+    Serializable,
+}
+
+impl Isolation {
+    fn statement(self) -> &'static str {
+        match self {
+            Isolation::ReadCommitted => "SET TRANSACTION ISOLATION LEVEL READ COMMITTED",
+            Isolation::Serializable => "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+        }
+    }
+}
+
+/// Runs several repository calls as one unit. Most callers want
+/// [`transaction`], which also commits and retries.
+#[async_trait]
+pub trait TransactionExt {
+    /// Starts a transaction. Every call on the returned repository runs in it
+    /// until [`UnitOfWork::commit`] or [`UnitOfWork::rollback`]. Inside a
+    /// transaction, this joins it instead and `isolation` is ignored.
+    async fn begin(&self, isolation: Isolation) -> Result<Arc<dyn UnitOfWork>, sqlx::Error>;
+}
+
+/// A repository whose calls all run in one transaction.
+#[async_trait]
+pub trait UnitOfWork: Repository {
+    /// Does nothing on a repository that joined an outer transaction.
+    async fn commit(&self) -> Result<(), sqlx::Error>;
+
+    /// Does nothing on a repository that joined an outer transaction, or
+    /// once the transaction is finished.
+    async fn rollback(&self) -> Result<(), sqlx::Error>;
+}
+
+/// Attempts at a transaction that keeps failing with a serialization error.
+const MAX_TRANSACTION_ATTEMPTS: u32 = 5;
+
+/// SQLSTATEs for a transaction Postgres aborted because of concurrent ones:
+/// `serialization_failure` and `deadlock_detected`. Running it again usually
+/// succeeds.
+const RETRYABLE_SQLSTATES: [&str; 2] = ["40001", "40P01"];
+
+/// Runs `work` in a transaction and commits it, or rolls it back if `work`
+/// fails. When Postgres aborts the transaction with a serialization failure
+/// or deadlock, `work` runs again from the start in a new transaction, so it
+/// should not have effects outside the database.
+pub async fn transaction<R, T, F, Fut>(
+    repo: &R,
+    isolation: Isolation,
+    mut work: F,
+) -> Result<T, sqlx::Error>
+where
+    R: TransactionExt + ?Sized,
+    F: FnMut(Arc<dyn UnitOfWork>) -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        let tx = repo.begin(isolation).await?;
+        let result = match work(tx.clone()).await {
+            Ok(value) => tx.commit().await.map(|_| value),
+            Err(e) => {
+                if let Err(rollback) = tx.rollback().await {
+                    tracing::warn!(error = %rollback, "Failed to roll back transaction");
+                }
+                Err(e)
+            }
+        };
+
+        match result {
+            Err(e) if attempt < MAX_TRANSACTION_ATTEMPTS && is_retryable(&e) => {
+                tracing::info!(error = %e, attempt, "Transaction aborted, retrying");
+                rt::time::sleep(Duration::from_millis(10 << attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_retryable(error: &sqlx::Error) -> bool {
+    error
+        .as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|code| RETRYABLE_SQLSTATES.contains(&code.as_ref()))
+}
+
+#[async_trait]
+impl TransactionExt for DbClient {
+    async fn begin(&self, isolation: Isolation) -> Result<Arc<dyn UnitOfWork>, sqlx::Error> {
+        let handle = match &self.tx {
+            Some(outer) => TxHandle {
+                tx: outer.tx.clone(),
+                owner: false,
+            },
+            None => {
+                let mut tx = self.pool.begin().await?;
+                if isolation != Isolation::ReadCommitted {
+                    sqlx::query(isolation.statement()).execute(&mut *tx).await?;
+                }
+                TxHandle {
+                    tx: Arc::new(AsyncMutex::new(Some(tx))),
+                    owner: true,
+                }
+            }
+        };
+
+        Ok(Arc::new(DbClient {
+            tx: Some(handle),
+            ..self.clone()
+        }))
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for DbClient {
+    async fn commit(&self) -> Result<(), sqlx::Error> {
+        let Some(handle) = self.tx.as_ref().filter(|handle| handle.owner) else {
+            return Ok(());
+        };
+        let tx = handle.tx.lock().await.take();
+        match tx {
+            Some(tx) => tx.commit().await,
+            None => Err(transaction_finished()),
+        }
+    }
+
+    async fn rollback(&self) -> Result<(), sqlx::Error> {
+        let Some(handle) = self.tx.as_ref().filter(|handle| handle.owner) else {
+            return Ok(());
+        };
+        let tx = handle.tx.lock().await.take();
+        match tx {
+            Some(tx) => tx.rollback().await,
+            None => Ok(()),
+        }
     }
 }
 
@@ -26,19 +305,63 @@ pub trait UserExt {
 
     async fn get_users(&self, page: u32, limit: usize) -> Result<Vec<User>, sqlx::Error>;
 
-    async fn save_user<T: Into<String> + Send>(
+    /// Like `get_user` by id, but also finds disabled and soft-deleted users.
+    async fn get_user_including_inactive(
         &self,
-        name: T,
-        email: T,
-        password: T,
-    ) -> Result<User, sqlx::Error>;
+        user_id: Option<Uuid>,
+        email: Option<&str>,
+    ) -> Result<Option<User>, sqlx::Error>;
+
+    async fn save_user(&self, name: &str, email: &str, password: &str)
+        -> Result<User, sqlx::Error>;
 
-    async fn save_admin_user<T: Into<String> + Send>(
+    #[allow(dead_code)]
+    async fn save_admin_user(
         &self,
-        name: T,
-        email: T,
-        password: T,
+        name: &str,
+        email: &str,
+        password: &str,
     ) -> Result<User, sqlx::Error>;
+
+    /// Updates the password. When `expected_versions` is given, the update
+    /// only happens if `updated_at` is one of them; returns whether it did.
+    async fn update_user_password(
+        &self,
+        user_id: Uuid,
+        password: &str,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<bool, sqlx::Error>;
+
+    async fn update_user_role(
+        &self,
+        user_id: Uuid,
+        role: UserRole,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<Option<User>, sqlx::Error>;
+
+    async fn update_user_photo(
+        &self,
+        user_id: Uuid,
+        photo: &str,
+    ) -> Result<Option<User>, sqlx::Error>;
+
+    async fn set_user_disabled(
+        &self,
+        user_id: Uuid,
+        disabled: bool,
+    ) -> Result<Option<User>, sqlx::Error>;
+
+    async fn soft_delete_user(
+        &self,
+        user_id: Uuid,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<Option<User>, sqlx::Error>;
+
+    async fn restore_user(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error>;
+
+    /// Permanently removes users soft-deleted more than `retention_days` ago
+    /// and returns them, so files they own can be cleaned up too.
+    async fn purge_deleted_users(&self, retention_days: i32) -> Result<Vec<User>, sqlx::Error>;
 }
 
 #[async_trait]
@@ -48,72 +371,758 @@ impl UserExt for DbClient {
         user_id: Option<Uuid>,
         name: Option<&str>,
         email: Option<&str>,
+    ) -> Result<Option<User>, sqlx::Error> {
+        let replica = match user_id {
+            Some(user_id) if self.replicas.recently_wrote(user_id) => None,
+            _ => self.replica(),
+        };
+        let Some(replica) = replica else {
+            return find_user(&mut *self.conn().await?, user_id, name, email).await;
+        };
+
+        match find_user(replica.pool(), user_id, name, email).await {
+            Ok(Some(user)) if !self.replicas.recently_wrote(user.id) => Ok(Some(user)),
+            // A miss may be a user the replica has not seen yet, e.g. one who
+            // just registered, and a recently written user may be stale.
+            Ok(_) => find_user(&mut *self.conn().await?, user_id, name, email).await,
+            Err(e) => {
+                self.replicas.mark_unhealthy(replica, &e);
+                find_user(&mut *self.conn().await?, user_id, name, email).await
+            }
+        }
+    }
+
+    async fn get_users(&self, page: u32, limit: usize) -> Result<Vec<User>, sqlx::Error> {
+        let Some(replica) = self.replica() else {
+            return find_users(&mut *self.conn().await?, page, limit).await;
+        };
+
+        match find_users(replica.pool(), page, limit).await {
+            Ok(users) => Ok(users),
+            Err(e) => {
+                self.replicas.mark_unhealthy(replica, &e);
+                find_users(&mut *self.conn().await?, page, limit).await
+            }
+        }
+    }
+
+    async fn get_user_including_inactive(
+        &self,
+        user_id: Option<Uuid>,
+        email: Option<&str>,
     ) -> Result<Option<User>, sqlx::Error> {
         let mut user: Option<User> = None;
 
         if let Some(user_id) = user_id {
             user = sqlx::query_as!(
                 User,
-                r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole" FROM users WHERE id = $1"#,
+                r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at FROM users WHERE id = $1"#,
                 user_id
-            ).fetch_optional(&self.pool).await?;
-        } else if let Some(name) = name {
-            user = sqlx::query_as!(
-                User,
-                r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole" FROM users WHERE name = $1"#,
-                name
-            ).fetch_optional(&self.pool).await?;
+            ).fetch_optional(&mut *self.conn().await?).await?;
         } else if let Some(email) = email {
             user = sqlx::query_as!(
                 User,
-                r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole" FROM users WHERE email = $1"#,
+                r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at FROM users WHERE lower(email) = lower($1)"#,
                 email
-            ).fetch_optional(&self.pool).await?;
+            ).fetch_optional(&mut *self.conn().await?).await?;
         }
         Ok(user)
     }
 
-    async fn get_users(&self, page: u32, limit: usize) -> Result<Vec<User>, sqlx::Error> {
-        let offset = (page - 1) * limit as u32;
-        let users = sqlx::query_as!(
-            User,
-            r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole" FROM users ORDER BY created_at DESC LIMIT $1 OFFSET $2"#,
-            limit as i64,
-            offset as i64
-        ).fetch_all(&self.pool).await?;
-        Ok(users)
-    }
-
-    async fn save_user<T: Into<String> + Send>(
+    async fn save_user(
         &self,
-        name: T,
-        email: T,
-        password: T,
+        name: &str,
+        email: &str,
+        password: &str,
     ) -> Result<User, sqlx::Error> {
         let user = sqlx::query_as!(
           User,
-          r#"INSERT INTO users (name, email, password) VALUES ($1, $2, $3) RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole""#,
-          name.into(),
-          email.into(),
-          password.into(),
-        ).fetch_one(&self.pool).await?;
+          r#"INSERT INTO users (name, email, password) VALUES ($1, $2, $3) RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+          name,
+          email,
+          password,
+        ).fetch_one(&mut *self.conn().await?).await?;
+        self.replicas.wrote(user.id);
         Ok(user)
     }
 
-    async fn save_admin_user<T: Into<String> + Send>(
+    async fn save_admin_user(
         &self,
-        name: T,
-        email: T,
-        password: T,
+        name: &str,
+        email: &str,
+        password: &str,
     ) -> Result<User, sqlx::Error> {
         let user = sqlx::query_as!(
           User,
-          r#"INSERT INTO users (name, email, password, role) VALUES ($1, $2, $3, $4) RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole""#,
-          name.into(),
-          email.into(),
-          password.into(),
+          r#"INSERT INTO users (name, email, password, role) VALUES ($1, $2, $3, $4) RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+          name,
+          email,
+          password,
           UserRole::Admin as UserRole
-        ).fetch_one(&self.pool).await?;
+        ).fetch_one(&mut *self.conn().await?).await?;
+        self.replicas.wrote(user.id);
+        Ok(user)
+    }
+
+    async fn update_user_password(
+        &self,
+        user_id: Uuid,
+        password: &str,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"UPDATE users SET password = $1 WHERE id = $2 AND ($3::timestamptz[] IS NULL OR updated_at = ANY($3))"#,
+            password,
+            user_id,
+            expected_versions
+        )
+        .execute(&mut *self.conn().await?)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.replicas.wrote(user_id);
+        Ok(true)
+    }
+
+    async fn update_user_role(
+        &self,
+        user_id: Uuid,
+        role: UserRole,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<Option<User>, sqlx::Error> {
+        let user = sqlx::query_as!(
+            User,
+            r#"UPDATE users SET role = $1 WHERE id = $2 AND deleted_at IS NULL AND ($3::timestamptz[] IS NULL OR updated_at = ANY($3)) RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+            role as UserRole,
+            user_id,
+            expected_versions
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        if let Some(user) = &user {
+            self.replicas.wrote(user.id);
+        }
         Ok(user)
     }
+
+    async fn update_user_photo(
+        &self,
+        user_id: Uuid,
+        photo: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        let user = sqlx::query_as!(
+            User,
+            r#"UPDATE users SET photo = $1 WHERE id = $2 AND deleted_at IS NULL RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+            photo,
+            user_id
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        if let Some(user) = &user {
+            self.replicas.wrote(user.id);
+        }
+        Ok(user)
+    }
+
+    async fn set_user_disabled(
+        &self,
+        user_id: Uuid,
+        disabled: bool,
+    ) -> Result<Option<User>, sqlx::Error> {
+        let user = sqlx::query_as!(
+            User,
+            r#"UPDATE users SET disabled = $1 WHERE id = $2 AND deleted_at IS NULL RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+            disabled,
+            user_id
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        if let Some(user) = &user {
+            self.replicas.wrote(user.id);
+        }
+        Ok(user)
+    }
+
+    async fn soft_delete_user(
+        &self,
+        user_id: Uuid,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<Option<User>, sqlx::Error> {
+        let user = sqlx::query_as!(
+            User,
+            r#"UPDATE users SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL AND ($2::timestamptz[] IS NULL OR updated_at = ANY($2)) RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+            user_id,
+            expected_versions
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        if let Some(user) = &user {
+            self.replicas.wrote(user.id);
+        }
+        Ok(user)
+    }
+
+    async fn restore_user(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error> {
+        let user = sqlx::query_as!(
+            User,
+            r#"UPDATE users SET deleted_at = NULL WHERE id = $1 AND deleted_at IS NOT NULL RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+            user_id
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        if let Some(user) = &user {
+            self.replicas.wrote(user.id);
+        }
+        Ok(user)
+    }
+
+    async fn purge_deleted_users(&self, retention_days: i32) -> Result<Vec<User>, sqlx::Error> {
+        let users = sqlx::query_as!(
+            User,
+            r#"DELETE FROM users WHERE deleted_at < NOW() - make_interval(days => $1) RETURNING id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at"#,
+            retention_days
+        )
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(users)
+    }
+}
+
+/// The active user with the given id, name or email, tried in that order.
+async fn find_user<'e>(
+    executor: impl PgExecutor<'e>,
+    user_id: Option<Uuid>,
+    name: Option<&str>,
+    email: Option<&str>,
+) -> Result<Option<User>, sqlx::Error> {
+    let mut user: Option<User> = None;
+
+    if let Some(user_id) = user_id {
+        user = sqlx::query_as!(
+            User,
+            r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at FROM users WHERE id = $1 AND deleted_at IS NULL AND NOT disabled"#,
+            user_id
+        ).fetch_optional(executor).await?;
+    } else if let Some(name) = name {
+        user = sqlx::query_as!(
+            User,
+            r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at FROM users WHERE name = $1 AND deleted_at IS NULL AND NOT disabled"#,
+            name
+        ).fetch_optional(executor).await?;
+    } else if let Some(email) = email {
+        user = sqlx::query_as!(
+            User,
+            r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at FROM users WHERE lower(email) = lower($1) AND deleted_at IS NULL AND NOT disabled"#,
+            email
+        ).fetch_optional(executor).await?;
+    }
+    Ok(user)
+}
+
+async fn find_users<'e>(
+    executor: impl PgExecutor<'e>,
+    page: u32,
+    limit: usize,
+) -> Result<Vec<User>, sqlx::Error> {
+    let offset = (page - 1) * limit as u32;
+    let users = sqlx::query_as!(
+        User,
+        r#"SELECT id, name, email, password, photo, verified, created_at, updated_at, role as "role: UserRole", disabled, deleted_at FROM users WHERE deleted_at IS NULL AND NOT disabled ORDER BY created_at DESC LIMIT $1 OFFSET $2"#,
+        limit as i64,
+        offset as i64
+    ).fetch_all(executor).await?;
+    Ok(users)
+}
+
+#[async_trait]
+pub trait ApiKeyExt {
+    async fn save_api_key(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<ApiKey, sqlx::Error>;
+
+    async fn get_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKey>, sqlx::Error>;
+
+    /// Looks up the owner of a live (not revoked) key and records the use.
+    async fn get_user_by_api_key(&self, key_hash: &str) -> Result<Option<User>, sqlx::Error>;
+
+    async fn revoke_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool, sqlx::Error>;
+}
+
+#[async_trait]
+impl ApiKeyExt for DbClient {
+    async fn save_api_key(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<ApiKey, sqlx::Error> {
+        let api_key = sqlx::query_as!(
+            ApiKey,
+            r#"INSERT INTO api_keys (user_id, name, prefix, key_hash) VALUES ($1, $2, $3, $4) RETURNING id, user_id, name, prefix, created_at, last_used_at, revoked_at"#,
+            user_id,
+            name,
+            prefix,
+            key_hash
+        )
+        .fetch_one(&mut *self.conn().await?)
+        .await?;
+        Ok(api_key)
+    }
+
+    async fn get_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKey>, sqlx::Error> {
+        let api_keys = sqlx::query_as!(
+            ApiKey,
+            r#"SELECT id, user_id, name, prefix, created_at, last_used_at, revoked_at FROM api_keys WHERE user_id = $1 ORDER BY created_at DESC"#,
+            user_id
+        )
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(api_keys)
+    }
+
+    async fn get_user_by_api_key(&self, key_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        let user = sqlx::query_as!(
+            User,
+            r#"WITH used AS (UPDATE api_keys SET last_used_at = NOW() WHERE key_hash = $1 AND revoked_at IS NULL RETURNING user_id)
+            SELECT u.id, u.name, u.email, u.password, u.photo, u.verified, u.created_at, u.updated_at, u.role as "role: UserRole", u.disabled, u.deleted_at FROM users u JOIN used ON used.user_id = u.id WHERE u.deleted_at IS NULL AND NOT u.disabled"#,
+            key_hash
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        Ok(user)
+    }
+
+    async fn revoke_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL"#,
+            key_id,
+            user_id
+        )
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+pub struct NewAuditEvent<'a> {
+    pub action: &'a str,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: serde_json::Value,
+}
+
+/// Narrows an audit log query. Every `None` field matches anything.
+#[derive(Debug, Default, Clone)]
+pub struct AuditFilter {
+    pub action: Option<String>,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    /// Matches events where this user is either the actor or the target.
+    pub involving: Option<Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[async_trait]
+pub trait AuditExt {
+    async fn save_audit_event(&self, event: NewAuditEvent<'_>) -> Result<(), sqlx::Error>;
+
+    async fn get_audit_events(
+        &self,
+        filter: &AuditFilter,
+        page: u32,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error>;
+
+    async fn count_audit_events(&self, filter: &AuditFilter) -> Result<i64, sqlx::Error>;
+
+    /// Counts the events an export will contain and returns its upper bound:
+    /// `filter.to` capped at the database's `now()`.
+    async fn count_audit_events_until_now(
+        &self,
+        filter: &AuditFilter,
+    ) -> Result<(i64, DateTime<Utc>), sqlx::Error>;
+
+    /// Events ordered newest first by `(created_at, id)`, starting after the
+    /// given key. Unlike offset pages, each one costs the same to read.
+    async fn get_audit_events_before(
+        &self,
+        filter: &AuditFilter,
+        before: Option<(DateTime<Utc>, Uuid)>,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error>;
+}
+
+#[async_trait]
+impl AuditExt for DbClient {
+    async fn save_audit_event(&self, event: NewAuditEvent<'_>) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"INSERT INTO audit_events (action, actor_id, target_id, ip_address, user_agent, metadata) VALUES ($1, $2, $3, $4, $5, $6)"#,
+            event.action,
+            event.actor_id,
+            event.target_id,
+            event.ip_address,
+            event.user_agent,
+            event.metadata
+        )
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_audit_events(
+        &self,
+        filter: &AuditFilter,
+        page: u32,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        let offset = (page - 1) * limit as u32;
+        let events = sqlx::query_as!(
+            AuditEvent,
+            r#"SELECT id, action, actor_id, target_id, ip_address, user_agent, metadata, created_at FROM audit_events
+            WHERE ($1::text IS NULL OR action = $1)
+              AND ($2::uuid IS NULL OR actor_id = $2)
+              AND ($3::uuid IS NULL OR target_id = $3)
+              AND ($4::timestamptz IS NULL OR created_at >= $4)
+              AND ($5::timestamptz IS NULL OR created_at < $5)
+              AND ($6::uuid IS NULL OR actor_id = $6 OR target_id = $6)
+            ORDER BY created_at DESC, id LIMIT $7 OFFSET $8"#,
+            filter.action,
+            filter.actor_id,
+            filter.target_id,
+            filter.from,
+            filter.to,
+            filter.involving,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(events)
+    }
+
+    async fn count_audit_events(&self, filter: &AuditFilter) -> Result<i64, sqlx::Error> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM audit_events
+            WHERE ($1::text IS NULL OR action = $1)
+              AND ($2::uuid IS NULL OR actor_id = $2)
+              AND ($3::uuid IS NULL OR target_id = $3)
+              AND ($4::timestamptz IS NULL OR created_at >= $4)
+              AND ($5::timestamptz IS NULL OR created_at < $5)
+              AND ($6::uuid IS NULL OR actor_id = $6 OR target_id = $6)"#,
+            filter.action,
+            filter.actor_id,
+            filter.target_id,
+            filter.from,
+            filter.to,
+            filter.involving
+        )
+        .fetch_one(&mut *self.conn().await?)
+        .await?;
+        Ok(count)
+    }
+
+    async fn count_audit_events_until_now(
+        &self,
+        filter: &AuditFilter,
+    ) -> Result<(i64, DateTime<Utc>), sqlx::Error> {
+        let row = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!", LEAST($5::timestamptz, now()) as "until!" FROM audit_events
+            WHERE ($1::text IS NULL OR action = $1)
+              AND ($2::uuid IS NULL OR actor_id = $2)
+              AND ($3::uuid IS NULL OR target_id = $3)
+              AND ($4::timestamptz IS NULL OR created_at >= $4)
+              AND created_at < LEAST($5::timestamptz, now())
+              AND ($6::uuid IS NULL OR actor_id = $6 OR target_id = $6)"#,
+            filter.action,
+            filter.actor_id,
+            filter.target_id,
+            filter.from,
+            filter.to,
+            filter.involving
+        )
+        .fetch_one(&mut *self.conn().await?)
+        .await?;
+        Ok((row.count, row.until))
+    }
+
+    async fn get_audit_events_before(
+        &self,
+        filter: &AuditFilter,
+        before: Option<(DateTime<Utc>, Uuid)>,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        let (before_created_at, before_id) = before.unzip();
+        let events = sqlx::query_as!(
+            AuditEvent,
+            r#"SELECT id, action, actor_id, target_id, ip_address, user_agent, metadata, created_at FROM audit_events
+            WHERE ($1::text IS NULL OR action = $1)
+              AND ($2::uuid IS NULL OR actor_id = $2)
+              AND ($3::uuid IS NULL OR target_id = $3)
+              AND ($4::timestamptz IS NULL OR created_at >= $4)
+              AND ($5::timestamptz IS NULL OR created_at < $5)
+              AND ($6::uuid IS NULL OR actor_id = $6 OR target_id = $6)
+              AND ($7::timestamptz IS NULL OR (created_at, id) < ($7, $8::uuid))
+            ORDER BY created_at DESC, id DESC LIMIT $9"#,
+            filter.action,
+            filter.actor_id,
+            filter.target_id,
+            filter.from,
+            filter.to,
+            filter.involving,
+            before_created_at,
+            before_id,
+            limit as i64
+        )
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(events)
+    }
+}
+
+#[async_trait]
+pub trait DataExportExt {
+    async fn save_data_export(
+        &self,
+        user_id: Uuid,
+        status: DataExportStatus,
+        payload: Option<serde_json::Value>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<DataExport, sqlx::Error>;
+
+    /// Records a pending export for the user, or returns `None` when one is
+    /// already pending. The check and the insert are a single statement.
+    async fn start_data_export(
+        &self,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Option<DataExport>, sqlx::Error>;
+
+    async fn get_data_export(
+        &self,
+        user_id: Uuid,
+        export_id: Uuid,
+    ) -> Result<Option<DataExport>, sqlx::Error>;
+
+    /// The most recent export requested by the user, expired or not.
+    async fn get_latest_data_export(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<DataExport>, sqlx::Error>;
+
+    async fn finish_data_export(
+        &self,
+        export_id: Uuid,
+        status: DataExportStatus,
+        payload: Option<serde_json::Value>,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Marks exports pending for longer than `timeout_seconds` as failed.
+    /// Their build was lost, e.g. to a restart, and would otherwise stay
+    /// pending.
+    async fn fail_stale_data_exports(&self, timeout_seconds: i64) -> Result<u64, sqlx::Error>;
+
+    async fn delete_expired_data_exports(&self) -> Result<u64, sqlx::Error>;
+}
+
+#[async_trait]
+impl DataExportExt for DbClient {
+    async fn save_data_export(
+        &self,
+        user_id: Uuid,
+        status: DataExportStatus,
+        payload: Option<serde_json::Value>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<DataExport, sqlx::Error> {
+        let data_export = sqlx::query_as!(
+            DataExport,
+            r#"INSERT INTO data_exports (user_id, status, payload, completed_at, expires_at) VALUES ($1, $2, $3, CASE WHEN $2 = 'pending'::data_export_status THEN NULL ELSE NOW() END, $4) RETURNING id, user_id, status as "status: DataExportStatus", payload, created_at, completed_at, expires_at"#,
+            user_id,
+            status as DataExportStatus,
+            payload,
+            expires_at
+        )
+        .fetch_one(&mut *self.conn().await?)
+        .await?;
+        Ok(data_export)
+    }
+
+    async fn start_data_export(
+        &self,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Option<DataExport>, sqlx::Error> {
+        let data_export = sqlx::query_as!(
+            DataExport,
+            r#"INSERT INTO data_exports (user_id, status, expires_at) VALUES ($1, 'pending', $2) ON CONFLICT (user_id) WHERE status = 'pending' DO NOTHING RETURNING id, user_id, status as "status: DataExportStatus", payload, created_at, completed_at, expires_at"#,
+            user_id,
+            expires_at
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        Ok(data_export)
+    }
+
+    async fn get_data_export(
+        &self,
+        user_id: Uuid,
+        export_id: Uuid,
+    ) -> Result<Option<DataExport>, sqlx::Error> {
+        let data_export = sqlx::query_as!(
+            DataExport,
+            r#"SELECT id, user_id, status as "status: DataExportStatus", payload, created_at, completed_at, expires_at FROM data_exports WHERE id = $1 AND user_id = $2 AND expires_at > NOW()"#,
+            export_id,
+            user_id
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        Ok(data_export)
+    }
+
+    async fn get_latest_data_export(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<DataExport>, sqlx::Error> {
+        let data_export = sqlx::query_as!(
+            DataExport,
+            r#"SELECT id, user_id, status as "status: DataExportStatus", payload, created_at, completed_at, expires_at FROM data_exports WHERE user_id = $1 ORDER BY created_at DESC LIMIT 1"#,
+            user_id
+        )
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        Ok(data_export)
+    }
+
+    async fn finish_data_export(
+        &self,
+        export_id: Uuid,
+        status: DataExportStatus,
+        payload: Option<serde_json::Value>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"UPDATE data_exports SET status = $1, payload = $2, completed_at = NOW() WHERE id = $3"#,
+            status as DataExportStatus,
+            payload,
+            export_id
+        )
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn fail_stale_data_exports(&self, timeout_seconds: i64) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"UPDATE data_exports SET status = 'failed', completed_at = NOW() WHERE status = 'pending' AND created_at < NOW() - make_interval(secs => $1)"#,
+            timeout_seconds as f64
+        )
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_expired_data_exports(&self) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(r#"DELETE FROM data_exports WHERE expires_at <= NOW()"#)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// Connection pool usage at one point in time.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    /// Open connections, idle or in use.
+    pub size: u32,
+    pub idle: u32,
+    pub max: u32,
+}
+
+impl PoolStats {
+    fn of(pool: &Pool<Postgres>) -> Self {
+        PoolStats {
+            size: pool.size(),
+            idle: pool.num_idle() as u32,
+            max: pool.options().get_max_connections(),
+        }
+    }
+
+    pub fn in_use(&self) -> u32 {
+        self.size.saturating_sub(self.idle)
+    }
+}
+
+/// One read replica, as `/readyz` and `/metrics` report it.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicaStats {
+    /// Position in `database.replica_urls`.
+    pub index: usize,
+    /// Whether reads are routed to it; see [`replica::Replica::in_rotation`].
+    pub in_rotation: bool,
+    pub pool: PoolStats,
+}
+
+/// What readiness needs to know about the database.
+#[async_trait]
+pub trait HealthExt {
+    /// A round trip to the database.
+    async fn ping(&self) -> Result<(), sqlx::Error>;
+
+    /// `None` when there is no connection pool.
+    fn pool_stats(&self) -> Option<PoolStats>;
+
+    /// A round trip to each read replica, in the order of
+    /// `database.replica_urls`. One that fails is taken out of rotation as if
+    /// a read had failed on it.
+    async fn ping_replicas(&self) -> Vec<Result<(), sqlx::Error>>;
+
+    /// Empty when there are no read replicas.
+    fn replica_stats(&self) -> Vec<ReplicaStats>;
+
+    async fn migration_status(&self) -> Result<MigrationStatus, MigrateError>;
+}
+
+#[async_trait]
+impl HealthExt for DbClient {
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        Some(PoolStats::of(&self.pool))
+    }
+
+    async fn ping_replicas(&self) -> Vec<Result<(), sqlx::Error>> {
+        futures_util::future::join_all(self.replicas.iter().map(|replica| async move {
+            let result = sqlx::query("SELECT 1").execute(replica.pool()).await;
+            if let Err(e) = &result {
+                self.replicas.mark_unhealthy(replica, e);
+            }
+            result.map(|_| ())
+        }))
+        .await
+    }
+
+    fn replica_stats(&self) -> Vec<ReplicaStats> {
+        self.replicas
+            .iter()
+            .map(|replica| ReplicaStats {
+                index: replica.index(),
+                in_rotation: replica.in_rotation(),
+                pool: PoolStats::of(replica.pool()),
+            })
+            .collect()
+    }
+
+    async fn migration_status(&self) -> Result<MigrationStatus, MigrateError> {
+        migrations::status(&self.pool).await
+    }
 }
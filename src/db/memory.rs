@@ -0,0 +1,727 @@
+//! A [`Repository`](super::Repository) kept in memory, for tests that exercise
+//! the HTTP surface without Postgres. It mirrors the semantics of the SQL in
+//! [`DbClient`](super::DbClient): soft-deleted and disabled users are hidden
+//! from normal lookups, emails are unique case-insensitively and every update
+//! bumps `updated_at` like the trigger does. A transaction keeps a snapshot
+//! of every table to restore on rollback, but is not isolated from concurrent
+//! callers: rolling back also undoes whatever they wrote in the meantime.
+//! Tests that roll back must not write from elsewhere while the transaction
+//! is open.
+
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    error::Error as StdError,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use sqlx::{
+    error::{DatabaseError, ErrorKind},
+    migrate::MigrateError,
+};
+use uuid::Uuid;
+
+use super::{
+    ApiKeyExt, AuditExt, AuditFilter, DataExportExt, HealthExt, Isolation, NewAuditEvent,
+    PoolStats, ReplicaStats, TransactionExt, UnitOfWork, UserExt,
+};
+use crate::{
+    migrations::MigrationStatus,
+    models::{ApiKey, AuditEvent, DataExport, DataExportStatus, User, UserRole},
+    storage::DEFAULT_PHOTO,
+};
+
+#[derive(Debug, Default, Clone)]
+struct Tables {
+    users: Vec<User>,
+    api_keys: Vec<(ApiKey, String)>,
+    audit_events: Vec<AuditEvent>,
+    data_exports: Vec<DataExport>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryDb {
+    tables: Arc<Mutex<Tables>>,
+    /// The tables as they were when this repository's transaction began.
+    /// `None` outside a transaction, or one joined from an outer one.
+    snapshot: Option<Arc<Mutex<Option<Tables>>>>,
+}
+
+impl InMemoryDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Tables) -> T) -> T {
+        f(&mut self.tables.lock().unwrap())
+    }
+}
+
+/// What Postgres reports for `users_email_lower_unique`, so handlers take the
+/// same path as against the real database.
+#[derive(Debug)]
+struct UniqueViolation(&'static str);
+
+impl fmt::Display for UniqueViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "duplicate key value violates unique constraint \"{}\"",
+            self.0
+        )
+    }
+}
+
+impl StdError for UniqueViolation {}
+
+impl DatabaseError for UniqueViolation {
+    fn message(&self) -> &str {
+        "duplicate key value violates unique constraint"
+    }
+
+    fn constraint(&self) -> Option<&str> {
+        Some(self.0)
+    }
+
+    fn code(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed("23505"))
+    }
+
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::UniqueViolation
+    }
+
+    fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+        self
+    }
+}
+
+fn is_active(user: &User) -> bool {
+    user.deleted_at.is_none() && !user.disabled
+}
+
+/// The current time at the precision Postgres stores, so versions survive
+/// the round trip through an ETag.
+fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(6)
+}
+
+fn version_matches(user: &User, expected_versions: Option<&[DateTime<Utc>]>) -> bool {
+    expected_versions.is_none_or(|versions| versions.contains(&user.updated_at))
+}
+
+fn email_eq(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+fn matches(event: &AuditEvent, filter: &AuditFilter) -> bool {
+    filter.action.as_ref().is_none_or(|a| &event.action == a)
+        && filter.actor_id.is_none_or(|id| event.actor_id == Some(id))
+        && filter
+            .target_id
+            .is_none_or(|id| event.target_id == Some(id))
+        && filter.from.is_none_or(|from| event.created_at >= from)
+        && filter.to.is_none_or(|to| event.created_at < to)
+        && filter
+            .involving
+            .is_none_or(|id| event.actor_id == Some(id) || event.target_id == Some(id))
+}
+
+fn page<T: Clone>(items: Vec<&T>, page: u32, limit: usize) -> Vec<T> {
+    let offset = (page as usize - 1) * limit;
+    items
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+impl Tables {
+    fn user_mut(&mut self, user_id: Uuid) -> Option<&mut User> {
+        self.users.iter_mut().find(|user| user.id == user_id)
+    }
+
+    fn insert_user(
+        &mut self,
+        name: &str,
+        email: &str,
+        password: &str,
+        role: UserRole,
+    ) -> Result<User, sqlx::Error> {
+        if self.users.iter().any(|user| email_eq(&user.email, email)) {
+            return Err(sqlx::Error::Database(Box::new(UniqueViolation(
+                "users_email_lower_unique",
+            ))));
+        }
+
+        let now = now();
+        let user = User {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            email: email.to_string(),
+            role,
+            photo: DEFAULT_PHOTO.to_string(),
+            verified: false,
+            password: password.to_string(),
+            created_at: now,
+            updated_at: now,
+            disabled: false,
+            deleted_at: None,
+        };
+        self.users.push(user.clone());
+        Ok(user)
+    }
+
+    /// Applies `update` to a user passing `filter` and bumps `updated_at` if
+    /// that changed anything, like `UPDATE ... RETURNING` with the
+    /// `users_set_updated_at` trigger.
+    fn update_user(
+        &mut self,
+        user_id: Uuid,
+        filter: impl FnOnce(&User) -> bool,
+        update: impl FnOnce(&mut User),
+    ) -> Option<User> {
+        let user = self.user_mut(user_id).filter(|user| filter(user))?;
+        let before = user.clone();
+        update(user);
+        if *user != before {
+            user.updated_at = now();
+        }
+        Some(user.clone())
+    }
+}
+
+#[async_trait]
+impl UserExt for InMemoryDb {
+    async fn get_user(
+        &self,
+        user_id: Option<Uuid>,
+        name: Option<&str>,
+        email: Option<&str>,
+    ) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables
+                .users
+                .iter()
+                .filter(|user| is_active(user))
+                .find(|user| {
+                    if let Some(user_id) = user_id {
+                        user.id == user_id
+                    } else if let Some(name) = name {
+                        user.name == name
+                    } else if let Some(email) = email {
+                        email_eq(&user.email, email)
+                    } else {
+                        false
+                    }
+                })
+                .cloned()
+        }))
+    }
+
+    async fn get_users(&self, page: u32, limit: usize) -> Result<Vec<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            let mut users: Vec<&User> =
+                tables.users.iter().rev().filter(|u| is_active(u)).collect();
+            users.sort_by_key(|user| std::cmp::Reverse(user.created_at));
+            self::page(users, page, limit)
+        }))
+    }
+
+    async fn get_user_including_inactive(
+        &self,
+        user_id: Option<Uuid>,
+        email: Option<&str>,
+    ) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables
+                .users
+                .iter()
+                .find(|user| {
+                    if let Some(user_id) = user_id {
+                        user.id == user_id
+                    } else if let Some(email) = email {
+                        email_eq(&user.email, email)
+                    } else {
+                        false
+                    }
+                })
+                .cloned()
+        }))
+    }
+
+    async fn save_user(
+        &self,
+        name: &str,
+        email: &str,
+        password: &str,
+    ) -> Result<User, sqlx::Error> {
+        self.with(|tables| tables.insert_user(name, email, password, UserRole::User))
+    }
+
+    async fn save_admin_user(
+        &self,
+        name: &str,
+        email: &str,
+        password: &str,
+    ) -> Result<User, sqlx::Error> {
+        self.with(|tables| tables.insert_user(name, email, password, UserRole::Admin))
+    }
+
+    async fn update_user_password(
+        &self,
+        user_id: Uuid,
+        password: &str,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables
+                .update_user(
+                    user_id,
+                    |user| version_matches(user, expected_versions),
+                    |user| user.password = password.to_string(),
+                )
+                .is_some()
+        }))
+    }
+
+    async fn update_user_role(
+        &self,
+        user_id: Uuid,
+        role: UserRole,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables.update_user(
+                user_id,
+                |user| user.deleted_at.is_none() && version_matches(user, expected_versions),
+                |user| user.role = role,
+            )
+        }))
+    }
+
+    async fn update_user_photo(
+        &self,
+        user_id: Uuid,
+        photo: &str,
+    ) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables.update_user(
+                user_id,
+                |user| user.deleted_at.is_none(),
+                |user| user.photo = photo.to_string(),
+            )
+        }))
+    }
+
+    async fn set_user_disabled(
+        &self,
+        user_id: Uuid,
+        disabled: bool,
+    ) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables.update_user(
+                user_id,
+                |user| user.deleted_at.is_none(),
+                |user| user.disabled = disabled,
+            )
+        }))
+    }
+
+    async fn soft_delete_user(
+        &self,
+        user_id: Uuid,
+        expected_versions: Option<&[DateTime<Utc>]>,
+    ) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables.update_user(
+                user_id,
+                |user| user.deleted_at.is_none() && version_matches(user, expected_versions),
+                |user| user.deleted_at = Some(now()),
+            )
+        }))
+    }
+
+    async fn restore_user(&self, user_id: Uuid) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables.update_user(
+                user_id,
+                |user| user.deleted_at.is_some(),
+                |user| user.deleted_at = None,
+            )
+        }))
+    }
+
+    async fn purge_deleted_users(&self, retention_days: i32) -> Result<Vec<User>, sqlx::Error> {
+        let cutoff = now() - Duration::days(retention_days.into());
+
+        Ok(self.with(|tables| {
+            let (purged, kept) = std::mem::take(&mut tables.users)
+                .into_iter()
+                .partition(|user| user.deleted_at.is_some_and(|at| at < cutoff));
+            tables.users = kept;
+
+            let purged: Vec<User> = purged;
+            let is_purged = |id: Uuid| purged.iter().any(|user| user.id == id);
+            tables.api_keys.retain(|(key, _)| !is_purged(key.user_id));
+            tables
+                .data_exports
+                .retain(|export| !is_purged(export.user_id));
+            purged
+        }))
+    }
+}
+
+#[async_trait]
+impl ApiKeyExt for InMemoryDb {
+    async fn save_api_key(
+        &self,
+        user_id: Uuid,
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<ApiKey, sqlx::Error> {
+        let api_key = ApiKey {
+            id: Uuid::new_v4(),
+            user_id,
+            name: name.to_string(),
+            prefix: prefix.to_string(),
+            created_at: now(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+
+        self.with(|tables| {
+            tables
+                .api_keys
+                .push((api_key.clone(), key_hash.to_string()))
+        });
+        Ok(api_key)
+    }
+
+    async fn get_api_keys(&self, user_id: Uuid) -> Result<Vec<ApiKey>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            let mut api_keys: Vec<ApiKey> = tables
+                .api_keys
+                .iter()
+                .rev()
+                .filter(|(key, _)| key.user_id == user_id)
+                .map(|(key, _)| key.clone())
+                .collect();
+            api_keys.sort_by_key(|key| std::cmp::Reverse(key.created_at));
+            api_keys
+        }))
+    }
+
+    async fn get_user_by_api_key(&self, key_hash: &str) -> Result<Option<User>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            let (key, _) = tables
+                .api_keys
+                .iter_mut()
+                .find(|(key, hash)| hash == key_hash && key.revoked_at.is_none())?;
+            key.last_used_at = Some(now());
+            let user_id = key.user_id;
+
+            tables
+                .users
+                .iter()
+                .find(|user| user.id == user_id && is_active(user))
+                .cloned()
+        }))
+    }
+
+    async fn revoke_api_key(&self, user_id: Uuid, key_id: Uuid) -> Result<bool, sqlx::Error> {
+        Ok(self.with(|tables| {
+            match tables.api_keys.iter_mut().find(|(key, _)| {
+                key.id == key_id && key.user_id == user_id && key.revoked_at.is_none()
+            }) {
+                Some((key, _)) => {
+                    key.revoked_at = Some(now());
+                    true
+                }
+                None => false,
+            }
+        }))
+    }
+}
+
+#[async_trait]
+impl AuditExt for InMemoryDb {
+    async fn save_audit_event(&self, event: NewAuditEvent<'_>) -> Result<(), sqlx::Error> {
+        let event = AuditEvent {
+            id: Uuid::new_v4(),
+            action: event.action.to_string(),
+            actor_id: event.actor_id,
+            target_id: event.target_id,
+            ip_address: event.ip_address,
+            user_agent: event.user_agent,
+            metadata: event.metadata,
+            created_at: now(),
+        };
+
+        self.with(|tables| tables.audit_events.push(event));
+        Ok(())
+    }
+
+    async fn get_audit_events(
+        &self,
+        filter: &AuditFilter,
+        page: u32,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            let mut events: Vec<&AuditEvent> = tables
+                .audit_events
+                .iter()
+                .filter(|event| matches(event, filter))
+                .collect();
+            events.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(a.id.cmp(&b.id)));
+            self::page(events, page, limit)
+        }))
+    }
+
+    async fn count_audit_events(&self, filter: &AuditFilter) -> Result<i64, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables
+                .audit_events
+                .iter()
+                .filter(|event| matches(event, filter))
+                .count() as i64
+        }))
+    }
+
+    async fn count_audit_events_until_now(
+        &self,
+        filter: &AuditFilter,
+    ) -> Result<(i64, DateTime<Utc>), sqlx::Error> {
+        let now = now();
+        let until = filter.to.map_or(now, |to| to.min(now));
+        let filter = AuditFilter {
+            to: Some(until),
+            ..filter.clone()
+        };
+        Ok((self.count_audit_events(&filter).await?, until))
+    }
+
+    async fn get_audit_events_before(
+        &self,
+        filter: &AuditFilter,
+        before: Option<(DateTime<Utc>, Uuid)>,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            let mut events: Vec<&AuditEvent> = tables
+                .audit_events
+                .iter()
+                .filter(|event| matches(event, filter))
+                .filter(|event| before.is_none_or(|key| (event.created_at, event.id) < key))
+                .collect();
+            events.sort_by_key(|event| Reverse((event.created_at, event.id)));
+            self::page(events, 1, limit)
+        }))
+    }
+}
+
+#[async_trait]
+impl DataExportExt for InMemoryDb {
+    async fn save_data_export(
+        &self,
+        user_id: Uuid,
+        status: DataExportStatus,
+        payload: Option<serde_json::Value>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<DataExport, sqlx::Error> {
+        let now = now();
+        let data_export = DataExport {
+            id: Uuid::new_v4(),
+            user_id,
+            status,
+            payload,
+            created_at: now,
+            completed_at: (status != DataExportStatus::Pending).then_some(now),
+            expires_at,
+        };
+
+        self.with(|tables| tables.data_exports.push(data_export.clone()));
+        Ok(data_export)
+    }
+
+    async fn start_data_export(
+        &self,
+        user_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Option<DataExport>, sqlx::Error> {
+        let data_export = DataExport {
+            id: Uuid::new_v4(),
+            user_id,
+            status: DataExportStatus::Pending,
+            payload: None,
+            created_at: now(),
+            completed_at: None,
+            expires_at,
+        };
+
+        Ok(self.with(|tables| {
+            let pending = tables.data_exports.iter().any(|export| {
+                export.user_id == user_id && export.status == DataExportStatus::Pending
+            });
+            if pending {
+                return None;
+            }
+            tables.data_exports.push(data_export.clone());
+            Some(data_export)
+        }))
+    }
+
+    async fn get_data_export(
+        &self,
+        user_id: Uuid,
+        export_id: Uuid,
+    ) -> Result<Option<DataExport>, sqlx::Error> {
+        let now = now();
+
+        Ok(self.with(|tables| {
+            tables
+                .data_exports
+                .iter()
+                .find(|export| {
+                    export.id == export_id && export.user_id == user_id && export.expires_at > now
+                })
+                .cloned()
+        }))
+    }
+
+    async fn get_latest_data_export(
+        &self,
+        user_id: Uuid,
+    ) -> Result<Option<DataExport>, sqlx::Error> {
+        Ok(self.with(|tables| {
+            tables
+                .data_exports
+                .iter()
+                .filter(|export| export.user_id == user_id)
+                .max_by_key(|export| export.created_at)
+                .cloned()
+        }))
+    }
+
+    async fn finish_data_export(
+        &self,
+        export_id: Uuid,
+        status: DataExportStatus,
+        payload: Option<serde_json::Value>,
+    ) -> Result<(), sqlx::Error> {
+        self.with(|tables| {
+            if let Some(export) = tables.data_exports.iter_mut().find(|e| e.id == export_id) {
+                export.status = status;
+                export.payload = payload;
+                export.completed_at = Some(now());
+            }
+        });
+        Ok(())
+    }
+
+    async fn fail_stale_data_exports(&self, timeout_seconds: i64) -> Result<u64, sqlx::Error> {
+        let now = now();
+        let cutoff = now - Duration::seconds(timeout_seconds);
+
+        Ok(self.with(|tables| {
+            let mut failed = 0;
+            for export in tables.data_exports.iter_mut().filter(|export| {
+                export.status == DataExportStatus::Pending && export.created_at < cutoff
+            }) {
+                export.status = DataExportStatus::Failed;
+                export.completed_at = Some(now);
+                failed += 1;
+            }
+            failed
+        }))
+    }
+
+    async fn delete_expired_data_exports(&self) -> Result<u64, sqlx::Error> {
+        let now = now();
+
+        Ok(self.with(|tables| {
+            let before = tables.data_exports.len();
+            tables.data_exports.retain(|export| export.expires_at > now);
+            (before - tables.data_exports.len()) as u64
+        }))
+    }
+}
+
+#[async_trait]
+impl HealthExt for InMemoryDb {
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+
+    async fn ping_replicas(&self) -> Vec<Result<(), sqlx::Error>> {
+        Vec::new()
+    }
+
+    fn replica_stats(&self) -> Vec<ReplicaStats> {
+        Vec::new()
+    }
+
+    /// There is no schema to migrate.
+    async fn migration_status(&self) -> Result<MigrationStatus, MigrateError> {
+        Ok(MigrationStatus::default())
+    }
+}
+
+#[async_trait]
+impl TransactionExt for InMemoryDb {
+    async fn begin(&self, _isolation: Isolation) -> Result<Arc<dyn UnitOfWork>, sqlx::Error> {
+        let snapshot = match self.snapshot {
+            Some(_) => None,
+            None => {
+                let tables = self.with(|tables| tables.clone());
+                Some(Arc::new(Mutex::new(Some(tables))))
+            }
+        };
+
+        Ok(Arc::new(InMemoryDb {
+            tables: self.tables.clone(),
+            snapshot,
+        }))
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for InMemoryDb {
+    async fn commit(&self) -> Result<(), sqlx::Error> {
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.lock().unwrap().take();
+        }
+        Ok(())
+    }
+
+    /// Puts back the tables as they were at `begin`, discarding writes made
+    /// outside the transaction since then as well as its own.
+    async fn rollback(&self) -> Result<(), sqlx::Error> {
+        let snapshot = self
+            .snapshot
+            .as_ref()
+            .and_then(|snapshot| snapshot.lock().unwrap().take());
+        if let Some(snapshot) = snapshot {
+            self.with(|tables| *tables = snapshot);
+        }
+        Ok(())
+    }
+}
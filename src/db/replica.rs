@@ -0,0 +1,161 @@
+//! Read replica routing for [`DbClient`](super::DbClient). Reads that may be
+//! slightly stale go to a replica, picked round-robin among the healthy ones.
+//! A replica that fails a query is skipped for a while and the query is run
+//! on the primary instead. Users written recently are read from the primary,
+//! so they see their own changes despite replication lag.
+//!
+//! Recent writes are only known to the instance that made them. Another
+//! instance may read a stale copy of the user within the replica's lag,
+//! including for authentication: a password change, role change or
+//! suspension can take that long to apply everywhere.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Default)]
+pub struct Replicas {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    replicas: Vec<Replica>,
+    next: AtomicUsize,
+    /// When each recently written user was last written.
+    recent_writes: Mutex<HashMap<Uuid, Instant>>,
+    read_your_writes: Duration,
+    retry_after: Duration,
+}
+
+#[derive(Debug)]
+pub struct Replica {
+    index: usize,
+    pool: Pool<Postgres>,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Replica {
+    /// Position in `database.replica_urls`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn pool(&self) -> &Pool<Postgres> {
+        &self.pool
+    }
+
+    /// Whether reads are routed here, i.e. it has not failed within
+    /// `database.replica_retry_seconds`.
+    pub fn in_rotation(&self) -> bool {
+        !matches!(*self.unhealthy_until.lock().unwrap(), Some(t) if t > Instant::now())
+    }
+}
+
+impl Replicas {
+    pub fn new(
+        pools: Vec<Pool<Postgres>>,
+        read_your_writes: Duration,
+        retry_after: Duration,
+    ) -> Self {
+        let replicas = pools
+            .into_iter()
+            .enumerate()
+            .map(|(index, pool)| Replica {
+                index,
+                pool,
+                unhealthy_until: Mutex::new(None),
+            })
+            .collect();
+
+        Replicas {
+            inner: Arc::new(Inner {
+                replicas,
+                read_your_writes,
+                retry_after,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.replicas.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Replica> {
+        self.inner.replicas.iter()
+    }
+
+    /// The next healthy replica, or `None` when reads should go to the
+    /// primary.
+    pub fn pick(&self) -> Option<&Replica> {
+        let replicas = &self.inner.replicas;
+        if replicas.is_empty() {
+            return None;
+        }
+
+        let start = self.inner.next.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        (0..replicas.len())
+            .map(|offset| &replicas[(start + offset) % replicas.len()])
+            .find(|replica| {
+                let mut until = replica.unhealthy_until.lock().unwrap();
+                match *until {
+                    Some(t) if t > now => false,
+                    Some(_) => {
+                        tracing::info!(replica = replica.index, "Retrying read replica");
+                        *until = None;
+                        true
+                    }
+                    None => true,
+                }
+            })
+    }
+
+    /// Takes `replica` out of rotation for `database.replica_retry_seconds`
+    /// after it failed a query.
+    pub fn mark_unhealthy(&self, replica: &Replica, error: &sqlx::Error) {
+        let mut until = replica.unhealthy_until.lock().unwrap();
+        if until.is_none() {
+            tracing::warn!(
+                replica = replica.index,
+                error = %error,
+                retry_in_seconds = self.inner.retry_after.as_secs(),
+                "Read replica failed, using the primary"
+            );
+        }
+        *until = Some(Instant::now() + self.inner.retry_after);
+    }
+
+    /// Records that `user_id` was just written on the primary.
+    pub fn wrote(&self, user_id: Uuid) {
+        if self.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let window = self.inner.read_your_writes;
+        let mut recent = self.inner.recent_writes.lock().unwrap();
+        recent.retain(|_, at| now.duration_since(*at) < window);
+        recent.insert(user_id, now);
+    }
+
+    /// Whether `user_id` was written within `database.read_your_writes_seconds`,
+    /// so a replica may not have caught up yet.
+    pub fn recently_wrote(&self, user_id: Uuid) -> bool {
+        self.inner
+            .recent_writes
+            .lock()
+            .unwrap()
+            .get(&user_id)
+            .is_some_and(|at| at.elapsed() < self.inner.read_your_writes)
+    }
+}
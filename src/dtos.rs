@@ -1,9 +1,15 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
 use validator::{Validate, ValidationError};
 
-use crate::models::User;
+use crate::{
+    models::{ApiKey, AuditEvent, DataExport, DataExportStatus, User, UserRole},
+    storage::Storage,
+};
 
 #[derive(Debug, Clone, Validate, Serialize, Deserialize, Default, ToSchema)]
 pub struct RegisterUserDto {
@@ -51,6 +57,29 @@ pub struct LoginUserDto {
     pub password: String,
 }
 
+#[derive(Debug, Clone, Validate, Serialize, Deserialize, Default, ToSchema)]
+pub struct ChangePasswordDto {
+    #[validate(length(min = 1, message = "Current password is required"))]
+    #[serde(rename = "currentPassword")]
+    pub current_password: String,
+
+    #[validate(custom(function = "validate_password"))]
+    #[serde(rename = "newPassword")]
+    pub new_password: String,
+
+    #[validate(
+        length(min = 1, message = "Confirm Password is required"),
+        must_match(other = "new_password", message = "Passwords do not match")
+    )]
+    #[serde(rename = "confirmNewPassword")]
+    pub confirm_new_password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UpdateUserRoleDto {
+    pub role: UserRole,
+}
+
 #[derive(Serialize, Deserialize, Validate, IntoParams)]
 pub struct RequestQueryDto {
     #[validate(range(min = 1))]
@@ -68,6 +97,10 @@ pub struct FilterUserDto {
     pub role: String,
     pub photo: String,
     pub verified: bool,
+    pub disabled: bool,
+
+    #[serde(rename = "deletedAt", skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
 
     #[serde(rename = "createdAt")]
     pub created_at: DateTime<Utc>,
@@ -77,21 +110,26 @@ pub struct FilterUserDto {
 }
 
 impl FilterUserDto {
-    pub fn filter_user(user: &User) -> Self {
+    pub fn filter_user(user: &User, storage: &dyn Storage) -> Self {
         FilterUserDto {
             id: user.id.to_string(),
             name: user.name.to_owned(),
             email: user.email.to_owned(),
             role: user.role.to_str().to_string(),
-            photo: user.photo.to_owned(),
+            photo: storage.url(&user.photo),
             verified: user.verified,
-            created_at: user.created_at.unwrap(),
-            updated_at: user.updated_at.unwrap(),
+            disabled: user.disabled,
+            deleted_at: user.deleted_at,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
         }
     }
 
-    pub fn filter_users(users: &Vec<User>) -> Vec<Self> {
-        users.iter().map(Self::filter_user).collect()
+    pub fn filter_users(users: &[User], storage: &dyn Storage) -> Vec<Self> {
+        users
+            .iter()
+            .map(|user| Self::filter_user(user, storage))
+            .collect()
     }
 }
 
@@ -106,6 +144,13 @@ pub struct UserResponseDto {
     pub data: UserDto,
 }
 
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SessionResponseDto {
+    pub status: String,
+    pub authenticated: bool,
+    pub user: Option<FilterUserDto>,
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct UserListResponseDto {
     pub status: String,
@@ -119,8 +164,346 @@ pub struct UserLoginResponseDto {
     pub token: String,
 }
 
+#[derive(Debug, Clone, Validate, Serialize, Deserialize, Default, ToSchema)]
+pub struct CreateApiKeyDto {
+    #[validate(length(min = 1, max = 100, message = "Name must be 1 to 100 characters"))]
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyDto {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+
+    #[serde(rename = "lastUsedAt")]
+    pub last_used_at: Option<DateTime<Utc>>,
+
+    #[serde(rename = "revokedAt")]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKeyDto {
+    pub fn filter_api_key(api_key: &ApiKey) -> Self {
+        ApiKeyDto {
+            id: api_key.id.to_string(),
+            name: api_key.name.to_owned(),
+            prefix: api_key.prefix.to_owned(),
+            created_at: api_key.created_at,
+            last_used_at: api_key.last_used_at,
+            revoked_at: api_key.revoked_at,
+        }
+    }
+
+    pub fn filter_api_keys(api_keys: &[ApiKey]) -> Vec<Self> {
+        api_keys.iter().map(Self::filter_api_key).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyCreatedResponseDto {
+    pub status: String,
+    /// The full key. It is only returned here and cannot be retrieved again.
+    pub key: String,
+    pub data: ApiKeyDto,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiKeyListResponseDto {
+    pub status: String,
+    pub data: Vec<ApiKeyDto>,
+    pub result: usize,
+}
+
+#[derive(Serialize, Deserialize, Validate, IntoParams)]
+#[validate(schema(function = "validate_audit_range"))]
+pub struct AuditQueryDto {
+    #[validate(range(min = 1))]
+    pub page: Option<usize>,
+
+    #[validate(range(min = 1, max = 100))]
+    pub limit: Option<usize>,
+
+    /// Only events with this action, e.g. `login.failed`.
+    pub action: Option<String>,
+
+    #[serde(rename = "actorId")]
+    pub actor_id: Option<uuid::Uuid>,
+
+    #[serde(rename = "targetId")]
+    pub target_id: Option<uuid::Uuid>,
+
+    /// Only events at or after this time (RFC 3339).
+    pub from: Option<DateTime<Utc>>,
+
+    /// Only events before this time (RFC 3339).
+    pub to: Option<DateTime<Utc>>,
+}
+
+fn validate_audit_range(query: &AuditQueryDto) -> Result<(), ValidationError> {
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from > to {
+            return Err(ValidationError::new("from must not be after to"));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditEventDto {
+    pub id: String,
+    pub action: String,
+
+    #[serde(rename = "actorId")]
+    pub actor_id: Option<String>,
+
+    #[serde(rename = "targetId")]
+    pub target_id: Option<String>,
+
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+
+    pub metadata: serde_json::Value,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl AuditEventDto {
+    pub fn filter_event(event: &AuditEvent) -> Self {
+        AuditEventDto {
+            id: event.id.to_string(),
+            action: event.action.to_owned(),
+            actor_id: event.actor_id.map(|id| id.to_string()),
+            target_id: event.target_id.map(|id| id.to_string()),
+            ip_address: event.ip_address.to_owned(),
+            user_agent: event.user_agent.to_owned(),
+            metadata: event.metadata.to_owned(),
+            created_at: event.created_at,
+        }
+    }
+
+    pub fn filter_events(events: &[AuditEvent]) -> Vec<Self> {
+        events.iter().map(Self::filter_event).collect()
+    }
+}
+
+/// An audit event in a user's data export. Events the user did not perform
+/// themselves, such as an admin suspending them, keep what happened but not
+/// who did it or from where: that is the staff member's data, not theirs.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ExportAuditEventDto {
+    pub id: String,
+    pub action: String,
+
+    #[serde(rename = "actorId")]
+    pub actor_id: Option<String>,
+
+    #[serde(rename = "targetId")]
+    pub target_id: Option<String>,
+
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+
+    pub metadata: serde_json::Value,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl ExportAuditEventDto {
+    pub fn filter_event(event: &AuditEvent, user_id: Uuid) -> Self {
+        let own = event.actor_id == Some(user_id);
+
+        ExportAuditEventDto {
+            id: event.id.to_string(),
+            action: event.action.to_owned(),
+            actor_id: event.actor_id.filter(|_| own).map(|id| id.to_string()),
+            target_id: event.target_id.map(|id| id.to_string()),
+            ip_address: event.ip_address.to_owned().filter(|_| own),
+            user_agent: event.user_agent.to_owned().filter(|_| own),
+            metadata: event.metadata.to_owned(),
+            created_at: event.created_at,
+        }
+    }
+
+    pub fn filter_events(events: &[AuditEvent], user_id: Uuid) -> Vec<Self> {
+        events
+            .iter()
+            .map(|event| Self::filter_event(event, user_id))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditListResponseDto {
+    pub status: String,
+    pub data: Vec<AuditEventDto>,
+    pub result: usize,
+    pub total: i64,
+}
+
+/// A login whose token may still be valid. Tokens are stateless, so these
+/// are derived from the audit log rather than from a session store.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ExportSessionDto {
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime<Utc>,
+
+    #[serde(rename = "expiresAt")]
+    pub expires_at: DateTime<Utc>,
+
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct LoginHistoryDto {
+    pub at: DateTime<Utc>,
+    pub succeeded: bool,
+    pub reason: Option<String>,
+
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+}
+
+/// Everything stored about a user, minus secrets such as the password hash
+/// and API key hashes.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DataExportBundleDto {
+    #[serde(rename = "generatedAt")]
+    pub generated_at: DateTime<Utc>,
+
+    pub profile: FilterUserDto,
+    pub sessions: Vec<ExportSessionDto>,
+
+    #[serde(rename = "apiKeys")]
+    pub api_keys: Vec<ApiKeyDto>,
+
+    #[serde(rename = "auditEvents")]
+    pub audit_events: Vec<ExportAuditEventDto>,
+
+    #[serde(rename = "loginHistory")]
+    pub login_history: Vec<LoginHistoryDto>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DataExportDto {
+    pub id: String,
+    pub status: DataExportStatus,
+
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+
+    #[serde(rename = "completedAt")]
+    pub completed_at: Option<DateTime<Utc>>,
+
+    #[serde(rename = "expiresAt")]
+    pub expires_at: DateTime<Utc>,
+}
+
+impl DataExportDto {
+    pub fn filter_data_export(data_export: &DataExport) -> Self {
+        DataExportDto {
+            id: data_export.id.to_string(),
+            status: data_export.status,
+            created_at: data_export.created_at,
+            completed_at: data_export.completed_at,
+            expires_at: data_export.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DataExportResponseDto {
+    pub status: String,
+    pub data: DataExportDto,
+}
+
+/// Describes the multipart body of the photo upload for the API docs; the
+/// handler reads the stream directly.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct PhotoUploadDto {
+    /// A PNG, JPEG, GIF or WebP image.
+    #[schema(format = Binary, content_media_type = "application/octet-stream")]
+    pub photo: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CsrfTokenResponseDto {
+    pub status: String,
+    pub token: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct Response {
     pub status: &'static str,
     pub message: String,
 }
+
+/// The state of one dependency checked by readiness.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct HealthCheckDto {
+    /// `up`, `degraded` or `down`. A degraded component works with reduced
+    /// capacity and does not make the instance unready.
+    pub status: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl HealthCheckDto {
+    pub fn up(details: Option<serde_json::Value>) -> Self {
+        HealthCheckDto {
+            status: "up".to_string(),
+            error: None,
+            details,
+        }
+    }
+
+    pub fn down(error: impl Into<String>, details: Option<serde_json::Value>) -> Self {
+        HealthCheckDto {
+            status: "down".to_string(),
+            error: Some(error.into()),
+            details,
+        }
+    }
+
+    pub fn degraded(error: impl Into<String>, details: Option<serde_json::Value>) -> Self {
+        HealthCheckDto {
+            status: "degraded".to_string(),
+            error: Some(error.into()),
+            details,
+        }
+    }
+
+    pub fn is_down(&self) -> bool {
+        self.status == "down"
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ReadinessResponseDto {
+    /// `not_ready` when a check is down, `ready` otherwise.
+    pub status: String,
+    pub checks: BTreeMap<String, HealthCheckDto>,
+}
@@ -30,17 +30,35 @@ pub enum ErrorMessage {
     UserNoLongerExist,
     TokenNotProvided,
     PermissionDenied,
+    CsrfTokenInvalid,
+    MalformedAuthorizationHeader,
+    UnsupportedAuthScheme,
+    ApiKeyNotFound,
+    UserNotFound,
+    AccountDisabled,
+    CannotModifySelf,
+    ExportNotFound,
+    ExportFailed,
+    ExportRateLimited,
+    TooManyFailedAttempts,
+    ClientAddressUnknown,
+    InvalidImage,
+    UnsupportedImageType,
+    ImageTooLarge(usize),
+    PhotoMissing,
+    UnexpectedFormField,
+    PreconditionFailed,
 }
 
-impl ToString for ErrorMessage {
-    fn to_string(&self) -> String {
-        self.to_str().to_owned()
+impl fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_str())
     }
 }
 
-impl Into<String> for ErrorMessage {
-    fn into(self) -> String {
-        self.to_string()
+impl From<ErrorMessage> for String {
+    fn from(message: ErrorMessage) -> Self {
+        message.to_string()
     }
 }
 
@@ -60,6 +78,40 @@ impl ErrorMessage {
             ErrorMessage::UserNoLongerExist => "User no longer exist".to_string(),
             ErrorMessage::TokenNotProvided => "Token not provided".to_string(),
             ErrorMessage::PermissionDenied => "Permission denied".to_string(),
+            ErrorMessage::CsrfTokenInvalid => "CSRF token missing or invalid".to_string(),
+            ErrorMessage::MalformedAuthorizationHeader => {
+                "Malformed Authorization header".to_string()
+            }
+            ErrorMessage::UnsupportedAuthScheme => "Unsupported authorization scheme".to_string(),
+            ErrorMessage::ApiKeyNotFound => "API key not found".to_string(),
+            ErrorMessage::UserNotFound => "User not found".to_string(),
+            ErrorMessage::AccountDisabled => "This account has been suspended".to_string(),
+            ErrorMessage::CannotModifySelf => "You cannot do this to your own account".to_string(),
+            ErrorMessage::ExportNotFound => "Export not found or expired".to_string(),
+            ErrorMessage::ExportFailed => "Export failed, please request a new one".to_string(),
+            ErrorMessage::InvalidImage => "The uploaded file is not a valid image".to_string(),
+            ErrorMessage::UnsupportedImageType => {
+                "Only PNG, JPEG, GIF and WebP images are supported".to_string()
+            }
+            ErrorMessage::ImageTooLarge(max_bytes) => {
+                format!("Image cannot be larger than {} bytes", max_bytes)
+            }
+            ErrorMessage::PhotoMissing => "Expected an image in the \"photo\" field".to_string(),
+            ErrorMessage::UnexpectedFormField => {
+                "Only a single \"photo\" field is accepted".to_string()
+            }
+            ErrorMessage::PreconditionFailed => {
+                "The resource has changed since it was fetched, reload it and try again".to_string()
+            }
+            ErrorMessage::ExportRateLimited => {
+                "An export was requested recently, please try again later".to_string()
+            }
+            ErrorMessage::TooManyFailedAttempts => {
+                "Too many failed attempts, please try again later".to_string()
+            }
+            ErrorMessage::ClientAddressUnknown => {
+                "Basic authentication is unavailable for this connection".to_string()
+            }
         }
     }
 }
@@ -105,6 +157,34 @@ impl HttpError {
         }
     }
 
+    pub fn not_found(message: impl Into<String>) -> Self {
+        HttpError {
+            status: 404,
+            message: message.into(),
+        }
+    }
+
+    pub fn precondition_failed(message: impl Into<String>) -> Self {
+        HttpError {
+            status: 412,
+            message: message.into(),
+        }
+    }
+
+    pub fn payload_too_large(message: impl Into<String>) -> Self {
+        HttpError {
+            status: 413,
+            message: message.into(),
+        }
+    }
+
+    pub fn unsupported_media_type(message: impl Into<String>) -> Self {
+        HttpError {
+            status: 415,
+            message: message.into(),
+        }
+    }
+
     pub fn uqique_constraint_voilation(message: impl Into<String>) -> Self {
         HttpError {
             status: 409,
@@ -116,28 +196,44 @@ impl HttpError {
         match self.status {
             500 => HttpResponse::InternalServerError().json(Response {
                 status: "fail",
-                message: self.message.into(),
+                message: self.message,
             }),
             403 => HttpResponse::Forbidden().json(Response {
                 status: "fail",
-                message: self.message.into(),
+                message: self.message,
             }),
             400 => HttpResponse::BadRequest().json(Response {
                 status: "fail",
-                message: self.message.into(),
+                message: self.message,
             }),
             401 => HttpResponse::Unauthorized().json(Response {
                 status: "fail",
-                message: self.message.into(),
+                message: self.message,
+            }),
+            404 => HttpResponse::NotFound().json(Response {
+                status: "fail",
+                message: self.message,
             }),
             409 => HttpResponse::Conflict().json(Response {
                 status: "fail",
-                message: self.message.into(),
+                message: self.message,
+            }),
+            412 => HttpResponse::PreconditionFailed().json(Response {
+                status: "fail",
+                message: self.message,
+            }),
+            413 => HttpResponse::PayloadTooLarge().json(Response {
+                status: "fail",
+                message: self.message,
+            }),
+            415 => HttpResponse::UnsupportedMediaType().json(Response {
+                status: "fail",
+                message: self.message,
             }),
             _ => {
-                eprintln!(
-                    "Warning: Missing pattern match. Converted status code {} to 500",
-                    self.status
+                tracing::warn!(
+                    status = self.status,
+                    "Missing pattern match. Converted status code to 500"
                 );
                 HttpResponse::InternalServerError().json(Response {
                     status: "error",
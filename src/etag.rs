@@ -0,0 +1,65 @@
+//! Optimistic concurrency for user resources. The ETag of a user encodes its
+//! `updated_at`, so an `If-Match` precondition can be handed to the database
+//! and checked in the same statement that performs the update.
+
+use actix_web::{
+    http::header::{self, EntityTag, Header},
+    HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+
+use crate::models::User;
+
+pub fn for_user(user: &User) -> EntityTag {
+    EntityTag::new_strong(format!("{:x}", user.updated_at.timestamp_micros()))
+}
+
+fn version(tag: &EntityTag) -> Option<DateTime<Utc>> {
+    if tag.weak {
+        return None;
+    }
+    i64::from_str_radix(tag.tag(), 16)
+        .ok()
+        .and_then(DateTime::from_timestamp_micros)
+}
+
+/// The `updated_at` values the client accepts, from `If-Match`. `None` means
+/// the request is unconditional (no header, or `*`). Tags we could not have
+/// issued are dropped, so a list of only those never matches.
+pub fn if_match(req: &HttpRequest) -> Option<Vec<DateTime<Utc>>> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return None;
+    }
+
+    match header::IfMatch::parse(req) {
+        Ok(header::IfMatch::Any) => None,
+        Ok(header::IfMatch::Items(tags)) => Some(tags.iter().filter_map(version).collect()),
+        Err(_) => Some(Vec::new()),
+    }
+}
+
+/// Whether a GET can be answered with 304 because the client's
+/// `If-None-Match` already covers the current representation.
+pub fn not_modified(req: &HttpRequest, current: &EntityTag) -> bool {
+    match header::IfNoneMatch::parse(req) {
+        Ok(header::IfNoneMatch::Any) => true,
+        Ok(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(current)),
+        Err(_) => false,
+    }
+}
+
+/// Responds with `user` as JSON and its ETag, or with 304 when the client
+/// already has this version.
+pub fn respond(req: &HttpRequest, user: &User, body: impl serde::Serialize) -> HttpResponse {
+    let etag = for_user(user);
+
+    if not_modified(req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .finish();
+    }
+
+    HttpResponse::Ok()
+        .insert_header(header::ETag(etag))
+        .json(body)
+}
@@ -0,0 +1,133 @@
+//! Self-service export of everything stored about a user. Small accounts get
+//! their bundle within the request; larger ones are built in the background
+//! and stored in `data_exports` until they expire.
+
+use actix_web::rt;
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::{
+    db::AuditFilter,
+    dtos::{
+        ApiKeyDto, DataExportBundleDto, ExportAuditEventDto, ExportSessionDto, FilterUserDto,
+        LoginHistoryDto,
+    },
+    models::{AuditAction, AuditEvent, DataExportStatus, User},
+    AppState,
+};
+
+const AUDIT_BATCH_SIZE: usize = 1000;
+
+/// The audit filter selecting every event the user took part in.
+pub fn audit_filter(user_id: Uuid) -> AuditFilter {
+    AuditFilter {
+        involving: Some(user_id),
+        ..Default::default()
+    }
+}
+
+pub async fn build_bundle(
+    app_state: &AppState,
+    user: &User,
+) -> Result<DataExportBundleDto, sqlx::Error> {
+    let api_keys = app_state.db_client.get_api_keys(user.id).await?;
+
+    let filter = audit_filter(user.id);
+    let mut events = Vec::new();
+    let mut page = 1;
+    loop {
+        let batch = app_state
+            .db_client
+            .get_audit_events(&filter, page, AUDIT_BATCH_SIZE)
+            .await?;
+        let done = batch.len() < AUDIT_BATCH_SIZE;
+        events.extend(batch);
+
+        if done {
+            break;
+        }
+        page += 1;
+    }
+
+    Ok(DataExportBundleDto {
+        generated_at: Utc::now(),
+        profile: FilterUserDto::filter_user(user, app_state.storage.as_ref()),
+        sessions: sessions(app_state, user, &events),
+        api_keys: ApiKeyDto::filter_api_keys(&api_keys),
+        audit_events: ExportAuditEventDto::filter_events(&events, user.id),
+        login_history: login_history(user, &events),
+    })
+}
+
+fn is_login_of(event: &AuditEvent, user: &User, action: AuditAction) -> bool {
+    event.action == action.to_str() && event.target_id == Some(user.id)
+}
+
+fn sessions(app_state: &AppState, user: &User, events: &[AuditEvent]) -> Vec<ExportSessionDto> {
+    let max_age = Duration::seconds(app_state.env.jwt.max_age);
+    let now = Utc::now();
+
+    events
+        .iter()
+        .filter(|event| is_login_of(event, user, AuditAction::LoginSucceeded))
+        .filter(|event| event.created_at + max_age > now)
+        .map(|event| ExportSessionDto {
+            started_at: event.created_at,
+            expires_at: event.created_at + max_age,
+            ip_address: event.ip_address.to_owned(),
+            user_agent: event.user_agent.to_owned(),
+        })
+        .collect()
+}
+
+fn login_history(user: &User, events: &[AuditEvent]) -> Vec<LoginHistoryDto> {
+    events
+        .iter()
+        .filter(|event| {
+            is_login_of(event, user, AuditAction::LoginSucceeded)
+                || is_login_of(event, user, AuditAction::LoginFailed)
+        })
+        .map(|event| LoginHistoryDto {
+            at: event.created_at,
+            succeeded: event.action == AuditAction::LoginSucceeded.to_str(),
+            reason: event
+                .metadata
+                .get("reason")
+                .and_then(|reason| reason.as_str())
+                .map(str::to_string),
+            ip_address: event.ip_address.to_owned(),
+            user_agent: event.user_agent.to_owned(),
+        })
+        .collect()
+}
+
+/// Builds the bundle for a pending export in the background and stores the
+/// result, or marks the export failed.
+pub fn spawn(app_state: AppState, user: User, export_id: Uuid) {
+    rt::spawn(async move {
+        let payload = match build_bundle(&app_state, &user).await {
+            Ok(bundle) => serde_json::to_value(bundle).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        let result = match payload {
+            Ok(payload) => {
+                app_state
+                    .db_client
+                    .finish_data_export(export_id, DataExportStatus::Ready, Some(payload))
+                    .await
+            }
+            Err(e) => {
+                tracing::error!(error = %e, export_id = %export_id, "Failed to build data export");
+                app_state
+                    .db_client
+                    .finish_data_export(export_id, DataExportStatus::Failed, None)
+                    .await
+            }
+        };
+
+        if let Err(e) = result {
+            tracing::error!(error = %e, export_id = %export_id, "Failed to store data export");
+        }
+    });
+}
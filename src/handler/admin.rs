@@ -0,0 +1,499 @@
+use actix_web::{
+    error::ErrorInternalServerError, http::header, web, HttpRequest, HttpResponse, Scope,
+};
+use futures_util::{
+    future::ready,
+    stream::{self, StreamExt},
+};
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+use crate::{
+    audit::AuditRecorder,
+    auth::{Authenticated, RequireAuth},
+    db::AuditFilter,
+    dtos::{
+        AuditEventDto, AuditListResponseDto, AuditQueryDto, FilterUserDto, Response, UserDto,
+        UserResponseDto,
+    },
+    error::{ErrorMessage, HttpError},
+    models::{AuditAction, AuditEvent, User, UserRole},
+    AppState,
+};
+
+const EXPORT_BATCH_SIZE: usize = 1000;
+
+const CSV_HEADER: &str = "id,created_at,action,actor_id,target_id,ip_address,user_agent,metadata\n";
+
+pub fn admin_handler() -> Scope {
+    web::scope("/api/admin")
+        .route(
+            "/audit",
+            web::get()
+                .to(get_audit_events)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
+        .route(
+            "/audit/export",
+            web::get()
+                .to(export_audit_events)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
+        .route(
+            "/users/{id}/suspend",
+            web::post()
+                .to(suspend_user)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
+        .route(
+            "/users/{id}/unsuspend",
+            web::post()
+                .to(unsuspend_user)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
+        .route(
+            "/users/{id}/restore",
+            web::post()
+                .to(restore_user)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
+}
+
+fn audit_filter(query: &AuditQueryDto) -> AuditFilter {
+    AuditFilter {
+        action: query.action.clone(),
+        actor_id: query.actor_id,
+        target_id: query.target_id,
+        involving: None,
+        from: query.from,
+        to: query.to,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/audit",
+    tag = "Audit Log Endpoint",
+    params(
+        AuditQueryDto
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Audit events, newest first",
+            body = AuditListResponseDto
+        ),
+        (
+            status = 400,
+            description = "Validation Errors",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn get_audit_events(
+    query: web::Query<AuditQueryDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+    let query_params: AuditQueryDto = query.into_inner();
+
+    query_params
+        .validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let page = query_params.page.unwrap_or(1);
+    let limit = query_params.limit.unwrap_or(10);
+    let filter = audit_filter(&query_params);
+
+    let events = app_state
+        .db_client
+        .get_audit_events(&filter, page as u32, limit)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let total = app_state
+        .db_client
+        .count_audit_events(&filter)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(AuditListResponseDto {
+        status: "success".to_string(),
+        data: AuditEventDto::filter_events(&events),
+        result: events.len(),
+        total,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/audit/export",
+    tag = "Audit Log Endpoint",
+    params(
+        AuditQueryDto
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Every matching audit event as CSV; page and limit are ignored",
+            content_type = "text/csv",
+            body = String
+        ),
+        (
+            status = 400,
+            description = "Validation Errors",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn export_audit_events(
+    req: HttpRequest,
+    user: Authenticated,
+    query: web::Query<AuditQueryDto>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+    let query = query.into_inner();
+    query
+        .validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    // Pin the end of the range to the database clock, which stamps the
+    // events, so ones recorded while the export runs, its own included,
+    // cannot shift the pages being read.
+    let mut filter = audit_filter(&query);
+    let (exported, until) = app_state
+        .db_client
+        .count_audit_events_until_now(&filter)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+    filter.to = Some(until);
+
+    AuditRecorder::new(&app_state, &req)
+        .record(
+            AuditAction::AuditExported,
+            Some(user.id),
+            None,
+            json!({ "events": exported }),
+        )
+        .await;
+
+    // The log can be large, so rows are sent a page at a time instead of
+    // building the whole file in memory. Each page starts after the last row
+    // of the previous one rather than at an offset.
+    let db = app_state.db_client.clone();
+    let rows = stream::try_unfold(Some(None), move |cursor| {
+        let db = db.clone();
+        let filter = filter.clone();
+        async move {
+            let Some(before) = cursor else {
+                return Ok(None);
+            };
+
+            let events = db
+                .get_audit_events_before(&filter, before, EXPORT_BATCH_SIZE)
+                .await
+                .map_err(|e| {
+                    tracing::error!(error = %e, "Failed to read audit events for export");
+                    ErrorInternalServerError(ErrorMessage::ServerError)
+                })?;
+
+            let chunk: String = events.iter().map(csv_row).collect();
+            let next = (events.len() == EXPORT_BATCH_SIZE)
+                .then(|| events.last().map(|event| (event.created_at, event.id)));
+            Ok::<_, actix_web::Error>(Some((web::Bytes::from(chunk), next)))
+        }
+    });
+    let csv = stream::once(ready(Ok(web::Bytes::from_static(CSV_HEADER.as_bytes())))).chain(rows);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit-events.csv\"",
+        ))
+        .streaming(csv))
+}
+
+fn csv_row(event: &AuditEvent) -> String {
+    let fields = [
+        event.id.to_string(),
+        event.created_at.to_rfc3339(),
+        event.action.to_owned(),
+        event.actor_id.map(|id| id.to_string()).unwrap_or_default(),
+        event.target_id.map(|id| id.to_string()).unwrap_or_default(),
+        event.ip_address.to_owned().unwrap_or_default(),
+        event.user_agent.to_owned().unwrap_or_default(),
+        event.metadata.to_string(),
+    ];
+
+    let mut row = fields
+        .iter()
+        .map(|field| csv_field(field))
+        .collect::<Vec<_>>()
+        .join(",");
+    row.push('\n');
+    row
+}
+
+/// Quotes a CSV field when needed, and defuses values a spreadsheet would
+/// otherwise evaluate as a formula (user agents are attacker-controlled).
+/// Spreadsheets skip leading whitespace before looking for a trigger.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['\t', '\r'])
+        || value.trim_start().starts_with(['=', '+', '-', '@'])
+    {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn user_response(app_state: &AppState, user: &User) -> HttpResponse {
+    HttpResponse::Ok().json(UserResponseDto {
+        status: "success".to_string(),
+        data: UserDto {
+            user: FilterUserDto::filter_user(user, app_state.storage.as_ref()),
+        },
+    })
+}
+
+async fn set_suspended(
+    req: &HttpRequest,
+    admin: &User,
+    app_state: &AppState,
+    target_id: Uuid,
+    suspended: bool,
+) -> Result<HttpResponse, HttpError> {
+    if target_id == admin.id {
+        return Err(HttpError::bad_request(ErrorMessage::CannotModifySelf));
+    }
+
+    let updated = app_state
+        .db_client
+        .set_user_disabled(target_id, suspended)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or(HttpError::not_found(ErrorMessage::UserNotFound))?;
+
+    let action = if suspended {
+        AuditAction::UserSuspended
+    } else {
+        AuditAction::UserUnsuspended
+    };
+    AuditRecorder::new(app_state, req)
+        .record(action, Some(admin.id), Some(target_id), json!({}))
+        .await;
+
+    Ok(user_response(app_state, &updated))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{id}/suspend",
+    tag = "User Moderation Endpoint",
+    params(
+        ("id" = String, Path, description = "User id")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "User suspended. They can no longer log in or use existing credentials",
+            body = UserResponseDto
+        ),
+        (
+            status = 400,
+            description = "Admins cannot suspend themselves",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "User not found or deleted",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn suspend_user(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, HttpError> {
+    set_suspended(&req, &user, &app_state, path.into_inner(), true).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{id}/unsuspend",
+    tag = "User Moderation Endpoint",
+    params(
+        ("id" = String, Path, description = "User id")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "User unsuspended",
+            body = UserResponseDto
+        ),
+        (
+            status = 400,
+            description = "Admins cannot unsuspend themselves",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "User not found or deleted",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn unsuspend_user(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, HttpError> {
+    set_suspended(&req, &user, &app_state, path.into_inner(), false).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/users/{id}/restore",
+    tag = "User Moderation Endpoint",
+    params(
+        ("id" = String, Path, description = "User id")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Deleted user restored",
+            body = UserResponseDto
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "No deleted user with this id, or it has already been purged",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn restore_user(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, HttpError> {
+    let target_id = path.into_inner();
+
+    let restored = app_state
+        .db_client
+        .restore_user(target_id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or(HttpError::not_found(ErrorMessage::UserNotFound))?;
+
+    AuditRecorder::new(&app_state, &req)
+        .record(
+            AuditAction::UserRestored,
+            Some(user.id),
+            Some(target_id),
+            json!({}),
+        )
+        .await;
+
+    Ok(user_response(&app_state, &restored))
+}
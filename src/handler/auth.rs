@@ -1,19 +1,20 @@
-use actix_web::{
-    cookie::time::Duration as ActixWebDuration, cookie::Cookie, web, HttpResponse, Responder, Scope,
-};
+use actix_web::{web, HttpRequest, HttpResponse, Responder, Scope};
 use serde_json::json;
 use validator::Validate;
 
 use crate::{
-    auth::RequireAuth,
-    db::UserExt,
+    audit::AuditRecorder,
+    auth::{Authenticated, OptionalAuthenticated, RequireAuth},
+    csrf,
     dtos::{
-        FilterUserDto, LoginUserDto, RegisterUserDto, Response, UserDto, UserLoginResponseDto,
-        UserResponseDto,
+        CsrfTokenResponseDto, FilterUserDto, LoginUserDto, RegisterUserDto, Response,
+        SessionResponseDto, UserDto, UserLoginResponseDto, UserResponseDto,
     },
     error::{ErrorMessage, HttpError},
-    models::UserRole,
-    utils::{password, token},
+    metrics::METRICS,
+    models::{AuditAction, User, UserRole},
+    request_id,
+    utils::{cookie, email, password, token},
     AppState,
 };
 
@@ -21,6 +22,8 @@ pub fn auth_handler() -> Scope {
     web::scope("/api/auth")
         .route("/register", web::post().to(register))
         .route("/login", web::post().to(login))
+        .route("/csrf", web::get().to(csrf_token))
+        .route("/session", web::get().to(session))
         .route(
             "/logout",
             web::post().to(logout).wrap(RequireAuth::allow_roles(vec![
@@ -69,14 +72,19 @@ pub fn auth_handler() -> Scope {
     )
 )]
 pub async fn register(
+    req: HttpRequest,
     app_state: web::Data<AppState>,
     body: web::Json<RegisterUserDto>,
 ) -> Result<HttpResponse, HttpError> {
+    let mut body = body.into_inner();
+    body.email = email::normalize(&body.email, &app_state.env.email);
+
     body.validate()
         .map_err(|e| HttpError::bad_request(e.to_string()))?;
 
-    let hashed_password =
-        password::hash(&body.password).map_err(|e| HttpError::server_error(e.to_string()))?;
+    let hashed_password = password::hash_blocking(&body.password, &app_state.env.password)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
 
     let result = app_state
         .db_client
@@ -84,12 +92,24 @@ pub async fn register(
         .await;
 
     match result {
-        Ok(user) => Ok(HttpResponse::Created().json(UserResponseDto {
-            status: "success".to_string(),
-            data: UserDto {
-                user: FilterUserDto::filter_user(&user),
-            },
-        })),
+        Ok(user) => {
+            AuditRecorder::new(&app_state, &req)
+                .record(
+                    AuditAction::Registered,
+                    Some(user.id),
+                    Some(user.id),
+                    json!({}),
+                )
+                .await;
+            METRICS.registration();
+
+            Ok(HttpResponse::Created().json(UserResponseDto {
+                status: "success".to_string(),
+                data: UserDto {
+                    user: FilterUserDto::filter_user(&user, app_state.storage.as_ref()),
+                },
+            }))
+        }
         Err(sqlx::Error::Database(db_err)) => {
             if db_err.is_unique_violation() {
                 Err(HttpError::uqique_constraint_voilation(
@@ -126,6 +146,11 @@ pub async fn register(
             description= "Validation Errors", 
             body= Response
         ),
+        (
+            status=403, 
+            description= "Account suspended", 
+            body= Response
+        ),
         (
             status=500, 
             description= "Internal Server Error", 
@@ -134,47 +159,137 @@ pub async fn register(
     )
 )]
 pub async fn login(
+    req: HttpRequest,
     app_state: web::Data<AppState>,
     body: web::Json<LoginUserDto>,
 ) -> Result<HttpResponse, HttpError> {
+    let mut body = body.into_inner();
+    body.email = email::normalize(&body.email, &app_state.env.email);
+
     body.validate()
         .map_err(|e| HttpError::bad_request(e.to_string()))?;
 
+    let audit = AuditRecorder::new(&app_state, &req);
+
+    // Suspended accounts are looked up too so that, once the password has
+    // been proven, the user is told why they can't sign in. Deleted accounts
+    // are treated as if they never existed.
     let result = app_state
         .db_client
-        .get_user(None, None, Some(&body.email))
+        .get_user_including_inactive(None, Some(&body.email))
         .await
-        .map_err(|e| HttpError::server_error(e.to_string()))?;
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .filter(|user| user.deleted_at.is_none());
 
-    let user = result.ok_or(HttpError::unauthorized(ErrorMessage::WrongCredentials))?;
+    let Some(user) = result else {
+        // As slow as a wrong password, so the response does not tell which
+        // emails are registered.
+        password::dummy_compare_blocking(&body.password, &app_state.env.password).await;
+        audit
+            .record(
+                AuditAction::LoginFailed,
+                None,
+                None,
+                json!({ "email": body.email, "reason": "unknown_email" }),
+            )
+            .await;
+        METRICS.login("unknown_email");
+        return Err(HttpError::unauthorized(ErrorMessage::WrongCredentials));
+    };
 
-    let password_match = password::compare(&body.password, &user.password)
-        .map_err(|_| HttpError::unauthorized(ErrorMessage::WrongCredentials))?;
+    let password_match =
+        password::compare_blocking(&body.password, &user.password, &app_state.env.password)
+            .await
+            .unwrap_or(false);
+
+    if !password_match {
+        audit
+            .record(
+                AuditAction::LoginFailed,
+                None,
+                Some(user.id),
+                json!({ "email": body.email, "reason": "wrong_password" }),
+            )
+            .await;
+        METRICS.login("wrong_password");
+    }
+
+    if password_match && user.disabled {
+        audit
+            .record(
+                AuditAction::LoginFailed,
+                None,
+                Some(user.id),
+                json!({ "email": body.email, "reason": "account_disabled" }),
+            )
+            .await;
+        METRICS.login("account_disabled");
+        return Err(HttpError::permission_denied(ErrorMessage::AccountDisabled));
+    }
 
     if password_match {
+        if password::needs_rehash(&user.password, &app_state.env.password) {
+            rehash_password(&app_state, &user, &body.password).await;
+        }
+
         let token = token::create_token(
             &user.id.to_string(),
-            &app_state.env.jwt_secret.as_bytes(),
-            app_state.env.jwt_maxage,
+            app_state.env.jwt.secret.as_bytes(),
+            app_state.env.jwt.max_age,
         )
         .map_err(|e| HttpError::server_error(e.to_string()))?;
 
-        let cookie = Cookie::build("token", token.to_owned())
-            .http_only(true)
-            .path("/")
-            .max_age(ActixWebDuration::new(60 * &app_state.env.jwt_maxage, 0))
-            .finish();
-        Ok(HttpResponse::Ok()
-            .cookie(cookie)
-            .json(UserLoginResponseDto {
-                status: "success".to_string(),
-                token,
-            }))
+        audit
+            .record(
+                AuditAction::LoginSucceeded,
+                Some(user.id),
+                Some(user.id),
+                json!({}),
+            )
+            .await;
+        METRICS.login("succeeded");
+        request_id::record_user(user.id);
+
+        let mut response = HttpResponse::Ok();
+        if app_state.env.cookie.enabled {
+            response.cookie(cookie::auth_cookie(
+                &app_state.env.cookie,
+                token.to_owned(),
+                app_state.env.jwt.max_age,
+            ));
+        }
+
+        Ok(response.json(UserLoginResponseDto {
+            status: "success".to_string(),
+            token,
+        }))
     } else {
         Err(HttpError::unauthorized(ErrorMessage::WrongCredentials))
     }
 }
 
+/// Upgrades a hash stored with outdated Argon2 parameters or pepper. Failures
+/// are only logged: the user already proved their password, so login must not
+/// fail here.
+async fn rehash_password(app_state: &AppState, user: &User, plain_password: &str) {
+    let hashed_password =
+        match password::hash_blocking(plain_password, &app_state.env.password).await {
+            Ok(hashed_password) => hashed_password,
+            Err(e) => {
+                tracing::error!(error = %e, user_id = %user.id, "Failed to rehash password");
+                return;
+            }
+        };
+
+    if let Err(e) = app_state
+        .db_client
+        .update_user_password(user.id, &hashed_password, None)
+        .await
+    {
+        tracing::error!(error = %e, user_id = %user.id, "Failed to store rehashed password");
+    }
+}
+
 #[utoipa::path(
     post,
     path = "/api/auth/logout",
@@ -205,14 +320,83 @@ pub async fn login(
    )
 )]
 
-pub async fn logout() -> impl Responder {
-    let cookie = Cookie::build("token", "")
-        .path("/")
-        .max_age(ActixWebDuration::new(-1, 0))
-        .http_only(true)
-        .finish();
+pub async fn logout(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    AuditRecorder::new(&app_state, &req)
+        .record(AuditAction::Logout, Some(user.id), Some(user.id), json!({}))
+        .await;
+
+    let mut response = HttpResponse::Ok();
+    if app_state.env.cookie.enabled {
+        response.cookie(cookie::removal_cookie(&app_state.env.cookie));
+    }
+
+    response.json(json!({"status": "success"}))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/auth/csrf",
+    tag = "CSRF Token Endpoint",
+    responses(
+        (
+            status = 200,
+            description = "CSRF token issued; also set as a cookie. Send it back in the CSRF header on unsafe requests authenticated by cookie",
+            body = CsrfTokenResponseDto
+        ),
+    )
+)]
+pub async fn csrf_token(app_state: web::Data<AppState>) -> impl Responder {
+    let token = csrf::generate_token();
+    let cookie = cookie::csrf_cookie(
+        &app_state.env.cookie,
+        csrf::cookie_name(&app_state.env),
+        token.clone(),
+        app_state.env.jwt.max_age,
+    );
 
     HttpResponse::Ok()
         .cookie(cookie)
-        .json(json!({"status": "success"}))
+        .json(CsrfTokenResponseDto {
+            status: "success".to_string(),
+            token,
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/auth/session",
+    tag = "Session Endpoint",
+    responses(
+        (
+            status = 200,
+            description = "Whether the caller is logged in, and as whom",
+            body = SessionResponseDto
+        ),
+        (
+            status = 401,
+            description = "Credentials were sent but are invalid",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        ),
+    )
+)]
+pub async fn session(
+    user: OptionalAuthenticated,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    HttpResponse::Ok().json(SessionResponseDto {
+        status: "success".to_string(),
+        authenticated: user.is_some(),
+        user: user
+            .as_ref()
+            .map(|user| FilterUserDto::filter_user(user, app_state.storage.as_ref())),
+    })
 }
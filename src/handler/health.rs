@@ -0,0 +1,226 @@
+use std::{collections::BTreeMap, future::Future, time::Duration};
+
+use actix_web::{rt::time::timeout, web, HttpResponse};
+use serde_json::json;
+
+use crate::{
+    dtos::{HealthCheckDto, ReadinessResponseDto, Response},
+    storage::DEFAULT_PHOTO,
+    AppState,
+};
+
+/// Mounted at the root rather than under `/api`, where orchestrators expect
+/// probes.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/livez", web::get().to(livez))
+        .route("/readyz", web::get().to(readyz));
+}
+
+/// Runs a check, counting it as down when it takes longer than
+/// `health.timeout_ms`.
+async fn within_timeout(
+    app_state: &AppState,
+    check: impl Future<Output = HealthCheckDto>,
+) -> HealthCheckDto {
+    let limit = Duration::from_millis(app_state.env.health.timeout_ms);
+    timeout(limit, check).await.unwrap_or_else(|_| {
+        HealthCheckDto::down(format!("timed out after {}ms", limit.as_millis()), None)
+    })
+}
+
+async fn database_check(app_state: &AppState) -> HealthCheckDto {
+    let started = std::time::Instant::now();
+    match app_state.db_client.ping().await {
+        Ok(()) => HealthCheckDto::up(Some(json!({
+            "latencyMs": started.elapsed().as_millis(),
+        }))),
+        Err(e) => {
+            tracing::warn!(error = %e, "Readiness: database ping failed");
+            HealthCheckDto::down("database is unreachable", None)
+        }
+    }
+}
+
+/// A busy pool still serves requests, only slower, and failing readiness
+/// under load would shed every instance at once, so saturation only
+/// degrades it. A pool that can't hand out a connection at all fails the
+/// database check. `None` when there is no pool to check.
+fn pool_check(app_state: &AppState) -> Option<HealthCheckDto> {
+    let stats = app_state.db_client.pool_stats()?;
+    let threshold = app_state.env.health.pool_saturation_threshold;
+    let details = Some(json!({
+        "size": stats.size,
+        "idle": stats.idle,
+        "inUse": stats.in_use(),
+        "max": stats.max,
+    }));
+
+    Some(if stats.in_use() as f64 >= stats.max as f64 * threshold {
+        HealthCheckDto::degraded("connection pool is saturated", details)
+    } else {
+        HealthCheckDto::up(details)
+    })
+}
+
+/// Read replicas are optional: reads fall back to the primary when one is
+/// unreachable, so that only degrades readiness. Failing it would take every
+/// instance sharing the replica out of rotation at once. `None` when there
+/// are no replicas.
+async fn replicas_check(app_state: &AppState) -> Option<HealthCheckDto> {
+    if app_state.db_client.replica_stats().is_empty() {
+        return None;
+    }
+
+    let limit = Duration::from_millis(app_state.env.health.timeout_ms);
+    let pings = timeout(limit, app_state.db_client.ping_replicas()).await;
+    let stats = app_state.db_client.replica_stats();
+    let reachable: Vec<bool> = match &pings {
+        Ok(pings) => pings.iter().map(Result::is_ok).collect(),
+        Err(_) => vec![false; stats.len()],
+    };
+    for (index, ping) in pings.iter().flatten().enumerate() {
+        if let Err(e) = ping {
+            tracing::warn!(replica = index, error = %e, "Readiness: read replica ping failed");
+        }
+    }
+
+    let details = Some(json!(stats
+        .iter()
+        .zip(&reachable)
+        .map(|(replica, reachable)| json!({
+            "index": replica.index,
+            "reachable": reachable,
+            "inRotation": replica.in_rotation,
+            "size": replica.pool.size,
+            "idle": replica.pool.idle,
+            "inUse": replica.pool.in_use(),
+            "max": replica.pool.max,
+        }))
+        .collect::<Vec<_>>()));
+
+    let unreachable = reachable.iter().filter(|reachable| !**reachable).count();
+    Some(if pings.is_err() {
+        HealthCheckDto::degraded(format!("timed out after {}ms", limit.as_millis()), details)
+    } else if unreachable > 0 {
+        HealthCheckDto::degraded(
+            format!(
+                "{} of {} read replicas are unreachable",
+                unreachable,
+                stats.len()
+            ),
+            details,
+        )
+    } else {
+        HealthCheckDto::up(details)
+    })
+}
+
+async fn migrations_check(app_state: &AppState) -> HealthCheckDto {
+    match app_state.db_client.migration_status().await {
+        Ok(status) => {
+            let details = Some(json!({
+                "pending": status.pending,
+                "failed": status.failed,
+            }));
+            if let Some(version) = status.failed {
+                HealthCheckDto::down(format!("migration {} did not complete", version), details)
+            } else if !status.pending.is_empty() {
+                HealthCheckDto::down("migrations are pending", details)
+            } else {
+                HealthCheckDto::up(details)
+            }
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "Readiness: reading migration status failed");
+            HealthCheckDto::down("migration status is unavailable", None)
+        }
+    }
+}
+
+/// The storage is usable if it can serve the default photo every user
+/// starts with.
+async fn storage_check(app_state: &AppState) -> HealthCheckDto {
+    let details = Some(json!({ "backend": app_state.env.storage.backend.to_str() }));
+
+    match app_state.storage.exists(DEFAULT_PHOTO).await {
+        Ok(true) => HealthCheckDto::up(details),
+        Ok(false) => HealthCheckDto::down("default photo is missing", details),
+        Err(e) => {
+            tracing::warn!(error = %e, "Readiness: storage check failed");
+            HealthCheckDto::down("storage is unreachable", details)
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "Health Checker Endpoint",
+    responses(
+        (status = 200, description = "The process is up and handling requests", body = Response),
+    )
+)]
+pub async fn livez() -> HttpResponse {
+    HttpResponse::Ok().json(Response {
+        status: "success",
+        message: "alive".to_string(),
+    })
+}
+
+/// `/readyz` is unauthenticated, so a failing dependency is reported with a
+/// fixed message and the error itself only goes to the log.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "Health Checker Endpoint",
+    responses(
+        (status = 200, description = "Ready to serve traffic", body = ReadinessResponseDto),
+        (status = 503, description = "A dependency is not ready, or the server is shutting down", body = ReadinessResponseDto),
+    )
+)]
+pub async fn readyz(app_state: web::Data<AppState>) -> HttpResponse {
+    // Fail straight away once shutdown has started, without touching the
+    // dependencies, so the instance is taken out of rotation before it stops
+    // accepting connections.
+    if app_state.shutdown.is_shutting_down() {
+        let mut checks = BTreeMap::new();
+        checks.insert(
+            "shutdown".to_string(),
+            HealthCheckDto::down("shutting down", None),
+        );
+        return HttpResponse::ServiceUnavailable().json(ReadinessResponseDto {
+            status: "not_ready".to_string(),
+            checks,
+        });
+    }
+
+    let (database, migrations, storage, replicas) = futures_util::join!(
+        within_timeout(&app_state, database_check(&app_state)),
+        within_timeout(&app_state, migrations_check(&app_state)),
+        within_timeout(&app_state, storage_check(&app_state)),
+        replicas_check(&app_state),
+    );
+
+    let mut checks = BTreeMap::new();
+    checks.insert("database".to_string(), database);
+    checks.insert("migrations".to_string(), migrations);
+    checks.insert("storage".to_string(), storage);
+    if let Some(pool) = pool_check(&app_state) {
+        checks.insert("pool".to_string(), pool);
+    }
+    if let Some(replicas) = replicas {
+        checks.insert("replicas".to_string(), replicas);
+    }
+
+    let ready = !checks.values().any(HealthCheckDto::is_down);
+    let body = ReadinessResponseDto {
+        status: if ready { "ready" } else { "not_ready" }.to_string(),
+        checks,
+    };
+
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
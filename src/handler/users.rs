@@ -1,7 +1,29 @@
-use actix_web::{web, HttpResponse, Scope};
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::{error::PayloadError, http::header, web, HttpRequest, HttpResponse, Scope};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::{Stream, StreamExt};
+use image::ImageFormat;
+use serde_json::json;
+use uuid::Uuid;
 use validator::Validate;
 
-use crate::{auth::{Authenticated, RequireAuth}, db::UserExt, dtos::{FilterUserDto, RequestQueryDto, Response, UserDto, UserListResponseDto, UserResponseDto}, error::HttpError, models::UserRole, AppState};
+use crate::{
+    audit::AuditRecorder,
+    auth::{Authenticated, RequireAuth},
+    db::{self, Isolation},
+    dtos::{
+        ApiKeyCreatedResponseDto, ApiKeyDto, ApiKeyListResponseDto, ChangePasswordDto,
+        CreateApiKeyDto, DataExportBundleDto, DataExportDto, DataExportResponseDto, FilterUserDto,
+        PhotoUploadDto, RequestQueryDto, Response, UpdateUserRoleDto, UserDto, UserListResponseDto,
+        UserResponseDto,
+    },
+    error::{ErrorMessage, HttpError},
+    etag, export,
+    models::{AuditAction, DataExport, DataExportStatus, UserRole},
+    storage::DEFAULT_PHOTO,
+    utils::{api_key, avatar, password},
+    AppState,
+};
 
 pub fn users_handler() -> Scope {
     web::scope("/api/users")
@@ -19,6 +41,95 @@ pub fn users_handler() -> Scope {
                 UserRole::Admin,
             ])),
         )
+        .route(
+            "/me/photo",
+            web::post()
+                .to(upload_photo)
+                .wrap(RequireAuth::allow_roles(vec![
+                    UserRole::User,
+                    UserRole::Moderator,
+                    UserRole::Admin,
+                ])),
+        )
+        .route(
+            "/me/export",
+            web::get()
+                .to(export_data)
+                .wrap(RequireAuth::allow_roles(vec![
+                    UserRole::User,
+                    UserRole::Moderator,
+                    UserRole::Admin,
+                ])),
+        )
+        .route(
+            "/me/export/{id}",
+            web::get()
+                .to(get_data_export)
+                .wrap(RequireAuth::allow_roles(vec![
+                    UserRole::User,
+                    UserRole::Moderator,
+                    UserRole::Admin,
+                ])),
+        )
+        .route(
+            "/me/password",
+            web::patch()
+                .to(change_password)
+                .wrap(RequireAuth::allow_roles(vec![
+                    UserRole::User,
+                    UserRole::Moderator,
+                    UserRole::Admin,
+                ])),
+        )
+        .route(
+            "/{id}",
+            web::delete()
+                .to(delete_user)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
+        .route(
+            "/{id}/role",
+            web::patch()
+                .to(update_user_role)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
+        .route(
+            "/me/api-keys",
+            web::get()
+                .to(get_api_keys)
+                .wrap(RequireAuth::allow_roles(vec![
+                    UserRole::User,
+                    UserRole::Moderator,
+                    UserRole::Admin,
+                ])),
+        )
+        .route(
+            "/me/api-keys",
+            web::post()
+                .to(create_api_key)
+                .wrap(RequireAuth::allow_roles(vec![
+                    UserRole::User,
+                    UserRole::Moderator,
+                    UserRole::Admin,
+                ])),
+        )
+        .route(
+            "/me/api-keys/{id}",
+            web::delete()
+                .to(revoke_api_key)
+                .wrap(RequireAuth::allow_roles(vec![
+                    UserRole::User,
+                    UserRole::Moderator,
+                    UserRole::Admin,
+                ])),
+        )
+        // Registered last so it doesn't shadow `GET /me`.
+        .route(
+            "/{id}",
+            web::get()
+                .to(get_user)
+                .wrap(RequireAuth::allow_roles(vec![UserRole::Admin])),
+        )
 }
 
 #[utoipa::path(
@@ -31,6 +142,10 @@ pub fn users_handler() -> Scope {
             description= "Authenticated User", 
             body = UserResponseDto
         ),
+        (
+            status = 304,
+            description = "The user has not changed since the ETag in If-None-Match"
+        ),
         (
             status= 500, 
             description= "Internal Server Error", 
@@ -41,17 +156,521 @@ pub fn users_handler() -> Scope {
        ("token" = [])
    )
 )]
-pub async fn get_me(user: Authenticated) -> Result<HttpResponse, HttpError> {
-    let filtered_user = FilterUserDto::filter_user(&user);
+pub async fn get_me(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+    let filtered_user = FilterUserDto::filter_user(&user, app_state.storage.as_ref());
+
+    let response_data = UserResponseDto {
+        status: "success".to_string(),
+        data: UserDto {
+            user: filtered_user,
+        },
+    };
+
+    Ok(etag::respond(&req, &user, response_data))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/users/{id}",
+    tag = "Get User Endpoint",
+    params(
+        ("id" = String, Path, description = "User id")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The user, including suspended and deleted ones. Its ETag can be sent as If-Match when modifying it",
+            body = UserResponseDto
+        ),
+        (
+            status = 304,
+            description = "The user has not changed since the ETag in If-None-Match"
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "User not found",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn get_user(
+    req: HttpRequest,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, HttpError> {
+    let user = app_state
+        .db_client
+        .get_user_including_inactive(Some(path.into_inner()), None)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or(HttpError::not_found(ErrorMessage::UserNotFound))?;
 
     let response_data = UserResponseDto {
         status: "success".to_string(),
-        data: UserDto { 
-            user: filtered_user, 
+        data: UserDto {
+            user: FilterUserDto::filter_user(&user, app_state.storage.as_ref()),
+        },
+    };
+
+    Ok(etag::respond(&req, &user, response_data))
+}
+
+/// Works out why a conditional update of `user_id` matched no row: the user
+/// is gone, or it was modified since the client fetched it.
+async fn missing_or_modified(
+    app_state: &AppState,
+    user_id: Uuid,
+    expected_versions: &Option<Vec<DateTime<Utc>>>,
+) -> HttpError {
+    if expected_versions.is_some() {
+        let exists = app_state
+            .db_client
+            .get_user_including_inactive(Some(user_id), None)
+            .await
+            .ok()
+            .flatten()
+            .is_some_and(|user| user.deleted_at.is_none());
+
+        if exists {
+            return HttpError::precondition_failed(ErrorMessage::PreconditionFailed);
+        }
+    }
+
+    HttpError::not_found(ErrorMessage::UserNotFound)
+}
+
+/// Room for the boundaries and part headers around the photo in an upload.
+const MULTIPART_OVERHEAD: usize = 16 * 1024;
+
+/// Fails the request body with [`PayloadError::Overflow`] as soon as it is
+/// longer than `limit`.
+fn limit_body(
+    payload: web::Payload,
+    limit: usize,
+) -> impl Stream<Item = Result<web::Bytes, PayloadError>> {
+    let mut read = 0;
+    payload.map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len();
+        if read > limit {
+            return Err(PayloadError::Overflow);
+        }
+        Ok(chunk)
+    })
+}
+
+fn multipart_error(error: MultipartError, max_bytes: usize) -> HttpError {
+    match error {
+        MultipartError::Payload(PayloadError::Overflow) => {
+            HttpError::payload_too_large(ErrorMessage::ImageTooLarge(max_bytes))
+        }
+        e => HttpError::bad_request(e.to_string()),
+    }
+}
+
+/// Reads the `photo` field of a multipart upload, enforcing the size limit
+/// while streaming so an oversized upload is never fully buffered. Any other
+/// field is rejected rather than read and thrown away.
+async fn read_photo_field(
+    payload: &mut Multipart,
+    max_bytes: usize,
+) -> Result<(Vec<u8>, ImageFormat), HttpError> {
+    let mut photo = None;
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| multipart_error(e, max_bytes))?;
+        if field.name() != Some("photo") || photo.is_some() {
+            return Err(HttpError::bad_request(ErrorMessage::UnexpectedFormField));
+        }
+
+        let declared = field
+            .content_type()
+            .map(|mime| mime.essence_str())
+            .filter(|mime| avatar::ALLOWED_CONTENT_TYPES.contains(mime))
+            .and_then(ImageFormat::from_mime_type)
+            .ok_or(HttpError::unsupported_media_type(
+                ErrorMessage::UnsupportedImageType,
+            ))?;
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| multipart_error(e, max_bytes))?;
+            if bytes.len() + chunk.len() > max_bytes {
+                return Err(HttpError::payload_too_large(ErrorMessage::ImageTooLarge(
+                    max_bytes,
+                )));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        // The content type is only what the client claims; the file itself
+        // has to agree.
+        if avatar::detect_format(&bytes) != Some(declared) {
+            return Err(HttpError::bad_request(ErrorMessage::InvalidImage));
+        }
+
+        photo = Some((bytes, declared));
+    }
+
+    photo.ok_or(HttpError::bad_request(ErrorMessage::PhotoMissing))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/users/me/photo",
+    tag = "Profile Photo Endpoint",
+    request_body(
+        content = PhotoUploadDto,
+        content_type = "multipart/form-data",
+        description = "The new profile photo. It is cropped to a square and re-encoded as PNG"
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Photo updated",
+            body = UserResponseDto
+        ),
+        (
+            status = 400,
+            description = "Missing or invalid image, or another field",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 413,
+            description = "Image too large",
+            body = Response
+        ),
+        (
+            status = 415,
+            description = "Unsupported image type",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn upload_photo(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    payload: web::Payload,
+) -> Result<HttpResponse, HttpError> {
+    let max_bytes = app_state.env.avatar.max_bytes;
+    let mut payload = Multipart::new(
+        req.headers(),
+        limit_body(payload, max_bytes + MULTIPART_OVERHEAD),
+    );
+    let (bytes, format) = read_photo_field(&mut payload, max_bytes).await?;
+
+    let png = avatar::normalize_blocking(bytes, format, app_state.env.avatar.size)
+        .await
+        .map_err(HttpError::bad_request)?;
+
+    // A fresh key per upload keeps URLs stable for as long as a photo is in
+    // use, so they can be cached indefinitely.
+    let key = format!("avatars/{}/{}.png", user.id, Uuid::new_v4());
+    app_state
+        .storage
+        .put(&key, png)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    // The photo being replaced is read in the same transaction as the update,
+    // so a concurrent upload can't make us delete the wrong one.
+    let user_id = user.id;
+    let key_ref = key.as_str();
+    let updated = db::transaction(
+        app_state.db_client.as_ref(),
+        Isolation::Serializable,
+        |tx| async move {
+            let Some(previous) = tx.get_user(Some(user_id), None, None).await? else {
+                return Ok(None);
+            };
+            let updated = tx.update_user_photo(user_id, key_ref).await?;
+            Ok(updated.map(|updated| (updated, previous.photo)))
+        },
+    )
+    .await;
+
+    let (updated, previous) = match updated {
+        Ok(Some(updated)) => updated,
+        result => {
+            // Nothing refers to the new photo.
+            if let Err(e) = app_state.storage.delete(&key).await {
+                tracing::warn!(error = %e, photo = %key, "Failed to delete unused photo");
+            }
+            return Err(match result {
+                Err(e) => HttpError::server_error(e.to_string()),
+                _ => HttpError::not_found(ErrorMessage::UserNotFound),
+            });
         }
     };
 
-    Ok(HttpResponse::Ok().json(response_data))
+    if previous != DEFAULT_PHOTO {
+        if let Err(e) = app_state.storage.delete(&previous).await {
+            tracing::warn!(error = %e, photo = %previous, "Failed to delete old photo");
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(UserResponseDto {
+        status: "success".to_string(),
+        data: UserDto {
+            user: FilterUserDto::filter_user(&updated, app_state.storage.as_ref()),
+        },
+    }))
+}
+
+fn export_download(payload: serde_json::Value) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"data-export.json\"",
+        ))
+        .json(payload)
+}
+
+fn export_pending(data_export: &DataExport) -> HttpResponse {
+    HttpResponse::Accepted()
+        .insert_header((
+            header::LOCATION,
+            format!("/api/users/me/export/{}", data_export.id),
+        ))
+        .json(DataExportResponseDto {
+            status: "success".to_string(),
+            data: DataExportDto::filter_data_export(data_export),
+        })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/users/me/export",
+    tag = "Data Export Endpoint",
+    responses(
+        (
+            status = 200,
+            description = "Everything stored about the authenticated user, as a JSON download",
+            body = DataExportBundleDto
+        ),
+        (
+            status = 202,
+            description = "The export is being built. Poll the URL in the Location header",
+            body = DataExportResponseDto
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 429,
+            description = "An export was requested recently. See the Retry-After header",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn export_data(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+    let config = &app_state.env.export;
+    let now = Utc::now();
+
+    let latest = app_state
+        .db_client
+        .get_latest_data_export(user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    // Failed exports don't count, so the user can retry right away. A pending
+    // one is handed back instead of starting a second build.
+    if let Some(latest) = latest.filter(|latest| latest.status != DataExportStatus::Failed) {
+        let next_allowed = latest.created_at + Duration::seconds(config.min_interval_seconds);
+        if next_allowed > now {
+            if latest.status == DataExportStatus::Pending {
+                return Ok(export_pending(&latest));
+            }
+
+            return Ok(HttpResponse::TooManyRequests()
+                .insert_header((
+                    header::RETRY_AFTER,
+                    (next_allowed - now).num_seconds().max(1).to_string(),
+                ))
+                .json(Response {
+                    status: "fail",
+                    message: ErrorMessage::ExportRateLimited.to_string(),
+                }));
+        }
+    }
+
+    let event_count = app_state
+        .db_client
+        .count_audit_events(&export::audit_filter(user.id))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+    let expires_at = now + Duration::hours(config.retention_hours);
+    let asynchronous = event_count > config.async_threshold;
+
+    let audit = AuditRecorder::new(&app_state, &req);
+
+    if asynchronous {
+        let started = app_state
+            .db_client
+            .start_data_export(user.id, expires_at)
+            .await
+            .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+        // One is already pending, e.g. from a concurrent request: hand that
+        // one back.
+        let Some(data_export) = started else {
+            let latest = app_state
+                .db_client
+                .get_latest_data_export(user.id)
+                .await
+                .map_err(|e| HttpError::server_error(e.to_string()))?
+                .ok_or_else(|| HttpError::server_error(ErrorMessage::ExportFailed))?;
+            return Ok(export_pending(&latest));
+        };
+
+        export::spawn(app_state.get_ref().clone(), (*user).clone(), data_export.id);
+
+        audit
+            .record(
+                AuditAction::DataExported,
+                Some(user.id),
+                Some(user.id),
+                json!({ "exportId": data_export.id, "async": true }),
+            )
+            .await;
+
+        return Ok(export_pending(&data_export));
+    }
+
+    let bundle = export::build_bundle(&app_state, &user)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+    let payload =
+        serde_json::to_value(bundle).map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let data_export = app_state
+        .db_client
+        .save_data_export(
+            user.id,
+            DataExportStatus::Ready,
+            Some(payload.clone()),
+            expires_at,
+        )
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    audit
+        .record(
+            AuditAction::DataExported,
+            Some(user.id),
+            Some(user.id),
+            json!({ "exportId": data_export.id, "async": false }),
+        )
+        .await;
+
+    Ok(export_download(payload))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/users/me/export/{id}",
+    tag = "Data Export Endpoint",
+    params(
+        ("id" = String, Path, description = "Export id")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "The finished export, as a JSON download",
+            body = DataExportBundleDto
+        ),
+        (
+            status = 202,
+            description = "The export is still being built",
+            body = DataExportResponseDto
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "Export not found or expired",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "The export failed or Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn get_data_export(
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, HttpError> {
+    let data_export = app_state
+        .db_client
+        .get_data_export(user.id, path.into_inner())
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?
+        .ok_or(HttpError::not_found(ErrorMessage::ExportNotFound))?;
+
+    match (data_export.status, data_export.payload.clone()) {
+        (DataExportStatus::Pending, _) => Ok(export_pending(&data_export)),
+        (DataExportStatus::Ready, Some(payload)) => Ok(export_download(payload)),
+        _ => Err(HttpError::server_error(ErrorMessage::ExportFailed)),
+    }
 }
 
 #[utoipa::path(
@@ -89,6 +708,8 @@ pub async fn get_me(user: Authenticated) -> Result<HttpResponse, HttpError> {
    )
 )]
 pub async fn get_users(
+    req: HttpRequest,
+    user: Authenticated,
     query: web::Query<RequestQueryDto>,
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, HttpError> {
@@ -102,14 +723,469 @@ pub async fn get_users(
     let limit = query_params.limit.unwrap_or(10);
 
     let users = app_state
-            .db_client
-            .get_users(page as u32, limit)
-            .await
-            .map_err(|e| HttpError::server_error(e.to_string()))?;
+        .db_client
+        .get_users(page as u32, limit)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    AuditRecorder::new(&app_state, &req)
+        .record(
+            AuditAction::UsersListed,
+            Some(user.id),
+            None,
+            json!({ "page": page, "limit": limit }),
+        )
+        .await;
 
     Ok(HttpResponse::Ok().json(UserListResponseDto {
         status: "success".to_string(),
-        data: FilterUserDto::filter_users(&users),
+        data: FilterUserDto::filter_users(&users, app_state.storage.as_ref()),
         result: users.len(),
     }))
-}
\ No newline at end of file
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/users/me/password",
+    tag = "Change Password Endpoint",
+    request_body(
+        content = ChangePasswordDto,
+        description = "Current password and the new one",
+        example = json!({
+            "currentPassword": "password123",
+            "newPassword": "password456",
+            "confirmNewPassword": "password456"
+        })
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Password changed",
+            body = Response
+        ),
+        (
+            status = 400,
+            description = "Validation Errors",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Current password is wrong",
+            body = Response
+        ),
+        (
+            status = 412,
+            description = "If-Match does not match the current ETag of the user",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn change_password(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    body: web::Json<ChangePasswordDto>,
+) -> Result<HttpResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let password_match = password::compare_blocking(
+        &body.current_password,
+        &user.password,
+        &app_state.env.password,
+    )
+    .await
+    .unwrap_or(false);
+
+    if !password_match {
+        return Err(HttpError::unauthorized(ErrorMessage::WrongCredentials));
+    }
+
+    let hashed_password = password::hash_blocking(&body.new_password, &app_state.env.password)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    let updated = app_state
+        .db_client
+        .update_user_password(user.id, &hashed_password, etag::if_match(&req).as_deref())
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !updated {
+        return Err(HttpError::precondition_failed(
+            ErrorMessage::PreconditionFailed,
+        ));
+    }
+
+    AuditRecorder::new(&app_state, &req)
+        .record(
+            AuditAction::PasswordChanged,
+            Some(user.id),
+            Some(user.id),
+            json!({}),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(Response {
+        status: "success",
+        message: "Password changed".to_string(),
+    }))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/users/{id}/role",
+    tag = "Update User Role Endpoint",
+    params(
+        ("id" = String, Path, description = "User id")
+    ),
+    request_body(
+        content = UpdateUserRoleDto,
+        description = "The new role",
+        example = json!({ "role": "moderator" })
+    ),
+    responses(
+        (
+            status = 200,
+            description = "Role updated",
+            body = UserResponseDto
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "User not found",
+            body = Response
+        ),
+        (
+            status = 412,
+            description = "If-Match does not match the current ETag of the user",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn update_user_role(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    body: web::Json<UpdateUserRoleDto>,
+) -> Result<HttpResponse, HttpError> {
+    let target_id = path.into_inner();
+    let expected_versions = etag::if_match(&req);
+    let versions = expected_versions.as_deref();
+    let audit = &AuditRecorder::new(&app_state, &req);
+    let (actor_id, role) = (user.id, &body.role);
+
+    // The role change is only kept together with its audit event. The target
+    // is read in the transaction too, on the primary, so the audited `from`
+    // role is the one being replaced.
+    let updated = db::transaction(
+        app_state.db_client.as_ref(),
+        Isolation::Serializable,
+        |tx| async move {
+            // Suspended users keep their account, so their role can change.
+            let target = tx
+                .get_user_including_inactive(Some(target_id), None)
+                .await?
+                .filter(|target| target.deleted_at.is_none());
+            let Some(target) = target else {
+                return Ok(Err(HttpError::not_found(ErrorMessage::UserNotFound)));
+            };
+            // The target was just read, so only If-Match can have failed.
+            let Some(updated) = tx
+                .update_user_role(target_id, role.clone(), versions)
+                .await?
+            else {
+                return Ok(Err(HttpError::precondition_failed(
+                    ErrorMessage::PreconditionFailed,
+                )));
+            };
+            audit
+                .record_in(
+                    &*tx,
+                    AuditAction::RoleChanged,
+                    Some(actor_id),
+                    Some(target_id),
+                    json!({ "from": target.role.to_str(), "to": updated.role.to_str() }),
+                )
+                .await?;
+            Ok(Ok(updated))
+        },
+    )
+    .await
+    .map_err(|e| HttpError::server_error(e.to_string()))??;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ETag(etag::for_user(&updated)))
+        .json(UserResponseDto {
+            status: "success".to_string(),
+            data: UserDto {
+                user: FilterUserDto::filter_user(&updated, app_state.storage.as_ref()),
+            },
+        }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/users/{id}",
+    tag = "Delete User Endpoint",
+    params(
+        ("id" = String, Path, description = "User id")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "User deleted. It can be restored until the retention period ends",
+            body = Response
+        ),
+        (
+            status = 400,
+            description = "Admins cannot delete themselves",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 403,
+            description = "Permission Denied Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "User not found",
+            body = Response
+        ),
+        (
+            status = 412,
+            description = "If-Match does not match the current ETag of the user",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn delete_user(
+    req: HttpRequest,
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, HttpError> {
+    let target_id = path.into_inner();
+
+    if target_id == user.id {
+        return Err(HttpError::bad_request(ErrorMessage::CannotModifySelf));
+    }
+
+    let expected_versions = etag::if_match(&req);
+    let deleted = app_state
+        .db_client
+        .soft_delete_user(target_id, expected_versions.as_deref())
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if deleted.is_none() {
+        return Err(missing_or_modified(&app_state, target_id, &expected_versions).await);
+    }
+
+    AuditRecorder::new(&app_state, &req)
+        .record(
+            AuditAction::UserDeleted,
+            Some(user.id),
+            Some(target_id),
+            json!({ "retentionDays": app_state.env.accounts.deleted_retention_days }),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(Response {
+        status: "success",
+        message: "User deleted".to_string(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/users/me/api-keys",
+    tag = "API Keys Endpoint",
+    responses(
+        (
+            status = 200,
+            description = "API keys of the authenticated user",
+            body = ApiKeyListResponseDto
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn get_api_keys(
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, HttpError> {
+    let api_keys = app_state
+        .db_client
+        .get_api_keys(user.id)
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(HttpResponse::Ok().json(ApiKeyListResponseDto {
+        status: "success".to_string(),
+        data: ApiKeyDto::filter_api_keys(&api_keys),
+        result: api_keys.len(),
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/users/me/api-keys",
+    tag = "API Keys Endpoint",
+    request_body(
+        content = CreateApiKeyDto,
+        description = "Name to recognise the key by",
+        example = json!({ "name": "CI deploy" })
+    ),
+    responses(
+        (
+            status = 201,
+            description = "API key created. The key is only shown in this response",
+            body = ApiKeyCreatedResponseDto
+        ),
+        (
+            status = 400,
+            description = "Validation Errors",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn create_api_key(
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    body: web::Json<CreateApiKeyDto>,
+) -> Result<HttpResponse, HttpError> {
+    body.validate()
+        .map_err(|e| HttpError::bad_request(e.to_string()))?;
+
+    let (key, prefix) = api_key::generate();
+
+    let saved = app_state
+        .db_client
+        .save_api_key(user.id, &body.name, &prefix, &api_key::hash(&key))
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    Ok(HttpResponse::Created().json(ApiKeyCreatedResponseDto {
+        status: "success".to_string(),
+        key,
+        data: ApiKeyDto::filter_api_key(&saved),
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/users/me/api-keys/{id}",
+    tag = "API Keys Endpoint",
+    params(
+        ("id" = String, Path, description = "API key id")
+    ),
+    responses(
+        (
+            status = 200,
+            description = "API key revoked",
+            body = Response
+        ),
+        (
+            status = 401,
+            description = "Authentication Error",
+            body = Response
+        ),
+        (
+            status = 404,
+            description = "API key not found",
+            body = Response
+        ),
+        (
+            status = 500,
+            description = "Internal Server Error",
+            body = Response
+        )
+    ),
+    security(
+       ("token" = [])
+   )
+)]
+pub async fn revoke_api_key(
+    user: Authenticated,
+    app_state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, HttpError> {
+    let revoked = app_state
+        .db_client
+        .revoke_api_key(user.id, path.into_inner())
+        .await
+        .map_err(|e| HttpError::server_error(e.to_string()))?;
+
+    if !revoked {
+        return Err(HttpError::not_found(ErrorMessage::ApiKeyNotFound));
+    }
+
+    Ok(HttpResponse::Ok().json(Response {
+        status: "success",
+        message: "API key revoked".to_string(),
+    }))
+}
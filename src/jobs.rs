@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use actix_web::rt;
+use serde_json::json;
+
+use crate::{audit::AuditRecorder, models::AuditAction, storage::DEFAULT_PHOTO, AppState};
+
+/// Starts the cleanup job: it permanently removes users whose soft-deletion is
+/// older than `accounts.deleted_retention_days`, fails data exports pending
+/// for longer than `export.pending_timeout_seconds` and drops expired ones.
+/// Runs once at startup and then every `accounts.purge_interval_seconds`.
+pub fn spawn_cleanup(app_state: AppState) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(Duration::from_secs(
+            app_state.env.accounts.purge_interval_seconds,
+        ));
+
+        loop {
+            interval.tick().await;
+            purge_deleted_users(&app_state).await;
+            fail_stale_data_exports(&app_state).await;
+            delete_expired_data_exports(&app_state).await;
+        }
+    });
+}
+
+async fn purge_deleted_users(app_state: &AppState) {
+    let retention_days = app_state.env.accounts.deleted_retention_days;
+
+    let purged = match app_state
+        .db_client
+        .purge_deleted_users(retention_days)
+        .await
+    {
+        Ok(purged) => purged,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to purge deleted users");
+            return;
+        }
+    };
+
+    if purged.is_empty() {
+        return;
+    }
+
+    for user in purged.iter().filter(|user| user.photo != DEFAULT_PHOTO) {
+        if let Err(e) = app_state.storage.delete(&user.photo).await {
+            tracing::warn!(error = %e, photo = %user.photo, "Failed to delete photo");
+        }
+    }
+    let purged: Vec<_> = purged.iter().map(|user| user.id).collect();
+
+    tracing::info!(count = purged.len(), "Purged deleted users");
+
+    AuditRecorder::system(app_state)
+        .record(
+            AuditAction::UsersPurged,
+            None,
+            None,
+            json!({ "userIds": purged, "retentionDays": retention_days }),
+        )
+        .await;
+}
+
+async fn fail_stale_data_exports(app_state: &AppState) {
+    let timeout_seconds = app_state.env.export.pending_timeout_seconds;
+
+    match app_state
+        .db_client
+        .fail_stale_data_exports(timeout_seconds)
+        .await
+    {
+        Ok(0) => {}
+        Ok(failed) => tracing::warn!(count = failed, "Marked data exports left pending as failed"),
+        Err(e) => tracing::error!(error = %e, "Failed to mark stale data exports failed"),
+    }
+}
+
+async fn delete_expired_data_exports(app_state: &AppState) {
+    if let Err(e) = app_state.db_client.delete_expired_data_exports().await {
+        tracing::error!(error = %e, "Failed to delete expired data exports");
+    }
+}
@@ -0,0 +1,260 @@
+//! Structured logging. Every line written to stdout is one JSON object from
+//! `tracing-subscriber`'s JSON formatter, with the event's fields plus those
+//! of the spans it happened in, so the `request_id` and `user_id` of the
+//! request span end up on everything logged while handling that request,
+//! including sqlx's query timings.
+//!
+//! Records from crates that use `log` instead of `tracing` are forwarded by
+//! `tracing-log`. Before a line is written, fields whose name suggests a
+//! secret are redacted, and so are credentials found inside any value, such
+//! as a bearer token in a message or a password in `Debug` output.
+//!
+//! When traces are exported, lines also carry the `trace_id` and `span_id`
+//! of the current span; see [`crate::telemetry`]. Fields named `otel.*`
+//! describe the exported span and are left out of log lines.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{LazyLock, OnceLock},
+    time::Duration,
+};
+
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use regex::Regex;
+use serde_json::{Map, Value};
+use sqlx::{postgres::PgConnectOptions, ConnectOptions};
+use tracing::{level_filters::LevelFilter, Event, Level, Subscriber};
+use tracing_log::LogTracer;
+use tracing_subscriber::{
+    filter::{filter_fn, FilterExt},
+    fmt::{
+        format::{FormatEvent, FormatFields, Writer},
+        FmtContext, MakeWriter,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    config::{Config, LogConfig},
+    telemetry,
+};
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Where sqlx reports each statement it ran.
+pub const QUERY_TARGET: &str = "sqlx::query";
+
+/// Substrings of field names whose values are never written out.
+const SECRET_FIELDS: &[&str] = &[
+    "password",
+    "token",
+    "secret",
+    "authorization",
+    "cookie",
+    "api_key",
+    "apikey",
+];
+
+/// Credentials that show up inside values: `key: value` or `key=value` pairs
+/// as printed by `Debug` or in query strings, `Authorization` schemes, and
+/// JWTs on their own.
+static SECRET_VALUES: LazyLock<[(Regex, &str); 3]> = LazyLock::new(|| {
+    [
+        (
+            Regex::new(
+                r#"(?i)((?:password|passwd|token|secret|authorization|cookie|api_?key)[\w-]*["']?\s*[:=]\s*)((?:Bearer|Basic|ApiKey)\s+)?("[^"]*"|'[^']*'|[^\s,;&})\]]+)"#,
+            )
+            .unwrap(),
+            "${1}[REDACTED]",
+        ),
+        (
+            Regex::new(r"(?i)\b(Bearer|Basic|ApiKey)\s+[A-Za-z0-9._~+/=-]+").unwrap(),
+            "${1} [REDACTED]",
+        ),
+        (
+            Regex::new(r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+").unwrap(),
+            REDACTED,
+        ),
+    ]
+});
+
+/// Spans waiting to be exported are flushed at shutdown.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+pub fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRET_FIELDS.iter().any(|secret| name.contains(secret))
+}
+
+/// Masks credentials inside `value`.
+pub fn scrub(value: &str) -> String {
+    let mut value = value.to_string();
+    for (pattern, replacement) in SECRET_VALUES.iter() {
+        if pattern.is_match(&value) {
+            value = pattern.replace_all(&value, *replacement).into_owned();
+        }
+    }
+    value
+}
+
+/// Redacts secret fields and scrubs every string, at any depth.
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = scrub(s),
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                if is_secret(name) {
+                    *value = REDACTED.into();
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn validate(config: &LogConfig, errors: &mut Vec<String>) {
+    if let Err(e) = EnvFilter::try_new(&config.filter) {
+        errors.push(format!("log.filter (RUST_LOG): {}", e));
+    }
+    if config.query_level.parse::<LevelFilter>().is_err() {
+        errors.push(format!(
+            "log.query_level: {:?} is not a log level",
+            config.query_level
+        ));
+    }
+}
+
+/// Makes sqlx log every statement with its timing at `log.query_level`, and
+/// those slower than `log.slow_query_ms` at `warn`.
+pub fn log_queries(options: PgConnectOptions, config: &LogConfig) -> PgConnectOptions {
+    let level = config
+        .query_level
+        .parse::<LevelFilter>()
+        .unwrap_or(LevelFilter::INFO);
+    options
+        .log_statements(to_log_filter(level))
+        .log_slow_statements(
+            log::LevelFilter::Warn,
+            Duration::from_millis(config.slow_query_ms),
+        )
+}
+
+fn to_log_filter(level: LevelFilter) -> log::LevelFilter {
+    match level.into_level() {
+        None => log::LevelFilter::Off,
+        Some(Level::ERROR) => log::LevelFilter::Error,
+        Some(Level::WARN) => log::LevelFilter::Warn,
+        Some(Level::INFO) => log::LevelFilter::Info,
+        Some(Level::DEBUG) => log::LevelFilter::Debug,
+        Some(Level::TRACE) => log::LevelFilter::Trace,
+    }
+}
+
+/// The subscriber [`init`] installs: JSON lines matching `filter` (`RUST_LOG`
+/// syntax) written to `writer`, and trace export when there is a `provider`.
+pub fn subscriber<W>(
+    filter: &str,
+    writer: W,
+    provider: Option<&SdkTracerProvider>,
+) -> Result<impl Subscriber + Send + Sync, Box<dyn std::error::Error>>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    // Spans down to `info` are kept whatever the filter, so that `warn` and
+    // `error` lines still carry the request's context.
+    let filter = EnvFilter::try_new(filter)?.or(filter_fn(|metadata| {
+        metadata.is_span() && *metadata.level() <= Level::INFO
+    }));
+
+    let json = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(true)
+        .map_event_format(Redacted)
+        .with_writer(writer)
+        .with_filter(filter);
+
+    Ok(Registry::default()
+        .with(json)
+        .with(provider.map(telemetry::layer)))
+}
+
+/// Installs the JSON logger for both `tracing` and `log`, writing to stdout,
+/// and starts exporting traces when `tracing.enabled` is set.
+pub fn init(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let provider = if config.tracing.enabled {
+        let exporter = telemetry::otlp_exporter(&config.tracing)?;
+        Some(PROVIDER.get_or_init(|| telemetry::provider(exporter, &config.tracing)))
+    } else {
+        None
+    };
+
+    LogTracer::init()?;
+    tracing::subscriber::set_global_default(subscriber(&config.log.filter, io::stdout, provider)?)?;
+    Ok(())
+}
+
+/// Writes out anything buffered by the logger installed by [`init`],
+/// including spans waiting to be exported. Called at shutdown.
+pub fn flush() {
+    if let Some(provider) = PROVIDER.get() {
+        let _ = provider.force_flush();
+    }
+    let _ = io::stdout().flush();
+}
+
+/// Wraps the JSON formatter to put the fields of the enclosing spans on the
+/// line itself, add the trace ids and redact secrets.
+struct Redacted<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for Redacted<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut json = String::new();
+        self.0.format_event(ctx, Writer::new(&mut json), event)?;
+        let Ok(Value::Object(mut line)) = serde_json::from_str::<Value>(&json) else {
+            return Err(fmt::Error);
+        };
+
+        // Outer spans first, so inner ones win; the event's own fields win
+        // over all of them.
+        let mut context = Map::new();
+        if let Some(Value::Array(spans)) = line.remove("spans") {
+            for span in spans {
+                if let Value::Object(mut fields) = span {
+                    fields.remove("name");
+                    context.extend(fields);
+                }
+            }
+        }
+        for (name, value) in context {
+            line.entry(name).or_insert(value);
+        }
+        line.retain(|name, _| !name.starts_with("otel."));
+
+        if let Some((trace_id, span_id)) = telemetry::current_ids() {
+            line.insert("trace_id".to_string(), trace_id.into());
+            line.insert("span_id".to_string(), span_id.into());
+        }
+
+        let mut line = Value::Object(line);
+        redact(&mut line);
+        writeln!(writer, "{}", line)
+    }
+}
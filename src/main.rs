@@ -1,50 +1,97 @@
+mod audit;
 mod auth;
 mod config;
+mod cors;
+mod csrf;
 mod db;
 mod dtos;
 mod error;
+mod etag;
+mod export;
 mod handler;
+mod jobs;
+mod logging;
+mod metrics;
+mod migrations;
 mod models;
+mod pool;
+mod request_id;
+mod shutdown;
+mod storage;
+mod telemetry;
+#[cfg(test)]
+mod tests;
+mod throttle;
 mod utils;
-use actix_cors::Cors;
-use actix_web::{
-    get, http::header, middleware::Logger, web, App, HttpResponse, HttpServer, Responder,
-};
+use actix_web::{get, middleware::from_fn, rt, web, App, HttpResponse, HttpServer, Responder};
 use config::Config;
-use db::DbClient;
+use db::{replica::Replicas, DbClient, Repository};
 use dotenv::dotenv;
 use dtos::{
-    FilterUserDto, LoginUserDto, RegisterUserDto, Response, UserDto, UserListResponseDto,
-    UserLoginResponseDto, UserResponseDto,
+    ApiKeyCreatedResponseDto, ApiKeyDto, ApiKeyListResponseDto, AuditEventDto,
+    AuditListResponseDto, ChangePasswordDto, CreateApiKeyDto, CsrfTokenResponseDto,
+    DataExportBundleDto, DataExportDto, DataExportResponseDto, ExportAuditEventDto,
+    ExportSessionDto, FilterUserDto, HealthCheckDto, LoginHistoryDto, LoginUserDto, PhotoUploadDto,
+    ReadinessResponseDto, RegisterUserDto, Response, SessionResponseDto, UpdateUserRoleDto,
+    UserDto, UserListResponseDto, UserLoginResponseDto, UserResponseDto,
 };
-use sqlx::postgres::PgPoolOptions;
+use shutdown::ShutdownState;
+use sqlx::postgres::PgConnectOptions;
+use std::{sync::Arc, time::Duration};
+use storage::Storage;
+use throttle::FailureThrottle;
 use utoipa::{
-    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi,
 };
 use utoipa_rapidoc::RapiDoc;
 use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
 
-use handler::{auth as authHandler, users};
+use handler::{admin, auth as authHandler, health, users};
+use models::{DataExportStatus, UserRole};
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub env: Config,
-    pub db_client: DbClient,
+    pub db_client: Arc<dyn Repository>,
+    pub storage: Arc<dyn Storage>,
+    pub shutdown: ShutdownState,
+    pub basic_auth_failures: FailureThrottle,
 }
 
 #[derive(OpenApi)]
 #[openapi(
-    paths(authHandler::login, authHandler::logout, authHandler::register, users::get_me, users::get_users, health_checker_handler),
+    paths(authHandler::login, authHandler::logout, authHandler::register, authHandler::csrf_token, authHandler::session, users::get_me, users::get_users, users::get_user, users::upload_photo, users::export_data, users::get_data_export, users::get_api_keys, users::create_api_key, users::revoke_api_key, users::change_password, users::update_user_role, users::delete_user, admin::get_audit_events, admin::export_audit_events, admin::suspend_user, admin::unsuspend_user, admin::restore_user, health_checker_handler, health::livez, health::readyz),
     components(schemas(
         UserDto,
         FilterUserDto,
+        PhotoUploadDto,
+        DataExportBundleDto,
+        DataExportDto,
+        DataExportResponseDto,
+        DataExportStatus,
+        ExportAuditEventDto,
+        ExportSessionDto,
+        LoginHistoryDto,
+        CsrfTokenResponseDto,
+        SessionResponseDto,
+        CreateApiKeyDto,
+        ApiKeyDto,
+        ApiKeyCreatedResponseDto,
+        ApiKeyListResponseDto,
+        ChangePasswordDto,
+        UpdateUserRoleDto,
+        UserRole,
+        AuditEventDto,
+        AuditListResponseDto,
         LoginUserDto,
         RegisterUserDto,
         UserResponseDto,
         UserLoginResponseDto,
         Response,
-        UserListResponseDto
+        UserListResponseDto,
+        HealthCheckDto,
+        ReadinessResponseDto
     )),
     tags(
         (name = "Rust Authentication Api", description = "Authentication in Rust API")
@@ -68,6 +115,17 @@ impl Modify for SecurityAddon {
                             .build(),
                     ),
                 )
+                .security_scheme(
+                    "basic",
+                    SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Basic).build()),
+                )
+                .security_scheme(
+                    "api_key",
+                    SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                        "Authorization",
+                        "`ApiKey <key>`",
+                    ))),
+                )
                 .build(),
         )
     }
@@ -77,73 +135,123 @@ impl Modify for SecurityAddon {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     openssl_probe::init_ssl_cert_env_vars();
 
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "actix_web=info");
-    }
-
     dotenv().ok();
-    env_logger::init();
-
-    let config = Config::init();
 
-    let pool = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(&config.database_url)
-        .await
-        .expect("Failed to connect to Postgres");
+    let config = Config::init()?;
+    logging::init(&config)?;
 
-    match sqlx::migrate!().run(&pool).await {
-        Ok(_) => {
-            println!("Database migration successful");
-        }
+    let connect_options = logging::log_queries(
+        config.database.url.parse::<PgConnectOptions>()?,
+        &config.log,
+    );
+    let pool = match pool::connect(&config.database, connect_options).await {
+        Ok(pool) => pool,
         Err(e) => {
-            eprintln!("Failed to migrate database: {:?}", e);
+            tracing::error!(error = %e, "Failed to connect to Postgres");
+            return Err(format!("cannot connect to Postgres: {}", e).into());
         }
+    };
+
+    if config.database.auto_migrate {
+        match migrations::run(&pool).await {
+            Ok(_) => {
+                tracing::info!("Database migration successful");
+            }
+            Err(e) if config.database.ignore_migration_errors => {
+                tracing::error!(error = ?e, "Failed to migrate database, continuing anyway");
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, "Failed to migrate database");
+                return Err(e.into());
+            }
+        }
+    } else {
+        tracing::info!("Skipping database migration (database.auto_migrate is off)");
+    }
+
+    let storage = storage::build(&config.storage);
+    storage::ensure_default_photo(storage.as_ref()).await?;
+
+    let mut replica_pools = Vec::new();
+    for url in &config.database.replica_urls {
+        let options = logging::log_queries(url.parse::<PgConnectOptions>()?, &config.log);
+        replica_pools.push(pool::connect_replica(&config.database, options));
     }
+    let replicas = Replicas::new(
+        replica_pools.clone(),
+        Duration::from_secs(config.database.read_your_writes_seconds),
+        Duration::from_secs(config.database.replica_retry_seconds),
+    );
 
-    let db_client = DbClient::new(pool);
+    let db_client = Arc::new(DbClient::new(pool.clone()).with_replicas(replicas));
     let app_state = AppState {
         env: config.clone(),
         db_client,
+        storage,
+        shutdown: ShutdownState::default(),
+        basic_auth_failures: FailureThrottle::default(),
     };
+    let shutdown_state = app_state.shutdown.clone();
+
+    jobs::spawn_cleanup(app_state.clone());
 
-    println!(
-        "{}",
-        format!("Server running at http://localhost:{}", config.port)
+    tracing::info!(
+        "Server running at http://{}:{}",
+        config.bind_address,
+        config.port
     );
 
     let open_api = ApiDoc::openapi();
+    let cors_config = config.cors.clone();
+    let storage_config = config.storage.clone();
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
-            .allowed_origin("http://localhost:8000")
-            .allowed_methods(vec!["GET", "POST"])
-            .allowed_headers(vec![
-                header::CONTENT_TYPE,
-                header::AUTHORIZATION,
-                header::ACCEPT,
-            ])
-            .supports_credentials();
+    let server = HttpServer::new(move || {
+        let cors = cors::build(&cors_config);
 
         App::new()
             .app_data(web::Data::new(app_state.clone()))
             .wrap(cors)
-            .wrap(Logger::default())
-            .service(authHandler::auth_handler())
-            .service(users::users_handler())
-            .service(health_checker_handler)
+            .wrap(from_fn(metrics::track))
+            .wrap(from_fn(request_id::propagate))
+            .configure(configure_api)
+            .configure(|cfg| storage::configure_routes(&storage_config, cfg))
             .service(Redoc::with_url("/redoc", open_api.clone()))
             .service(RapiDoc::new("/api-docs/openapi.json").path("/redoc"))
             .service(SwaggerUi::new("/{_:.*}").url("/api-docs/openapi.json", open_api.clone()))
     })
-    .bind(("0.0.0.0", config.port))?
-    .run()
-    .await?;
+    .bind((config.bind_address.as_str(), config.port))?
+    .disable_signals()
+    .shutdown_timeout(config.shutdown.drain_timeout_seconds)
+    .run();
+
+    rt::spawn(shutdown::handle_signals(
+        shutdown::Signals::new()?,
+        server.handle(),
+        shutdown_state,
+        config.shutdown.clone(),
+    ));
+    server.await?;
+
+    pool.close().await;
+    for replica in replica_pools {
+        replica.close().await;
+    }
+    tracing::info!("Shutdown complete");
+    logging::flush();
 
     Ok(())
 }
 
+/// The JSON API, without middleware or docs. Shared by `main` and the tests.
+fn configure_api(cfg: &mut web::ServiceConfig) {
+    cfg.service(authHandler::auth_handler())
+        .service(users::users_handler())
+        .service(admin::admin_handler())
+        .service(health_checker_handler)
+        .configure(health::configure)
+        .configure(metrics::configure);
+}
+
 #[utoipa::path(
     get,
     path = "/api/healthchecker",
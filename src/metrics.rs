@@ -0,0 +1,289 @@
+//! Prometheus metrics, served on `GET /metrics` in the text exposition format.
+//! The registry is process-wide so that code without access to `AppState`,
+//! such as password hashing, can record into it too.
+//!
+//! HTTP metrics are labelled with the route template (`/api/users/{id}`),
+//! never the raw path, and with one of a fixed set of methods, so the number
+//! of series stays bounded.
+
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header::ContentType, Method},
+    middleware::Next,
+    web, HttpResponse,
+};
+use prometheus::{
+    core::Collector, proto::MetricFamily, Encoder, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{db::ReplicaStats, AppState};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Argon2 is tuned to take tens to hundreds of milliseconds.
+const HASH_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Methods that get a `method` label of their own. Clients can send any
+/// token as the method, so everything else is counted as `OTHER`.
+const METHODS: [Method; 7] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+    Method::OPTIONS,
+];
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    logins: IntCounterVec,
+    registrations: IntCounter,
+    token_validation_failures: IntCounterVec,
+    password_hash_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new(
+                    "http_requests_total",
+                    "HTTP requests handled, by route template and status.",
+                ),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time to handle an HTTP request, by route template and status.",
+                )
+                .buckets(LATENCY_BUCKETS.to_vec()),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            logins: IntCounterVec::new(
+                Opts::new("logins_total", "Login attempts, by outcome."),
+                &["outcome"],
+            )
+            .unwrap(),
+            registrations: IntCounter::new("registrations_total", "Accounts registered.").unwrap(),
+            token_validation_failures: IntCounterVec::new(
+                Opts::new(
+                    "token_validation_failures_total",
+                    "Requests whose credentials were rejected, by reason.",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            password_hash_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "password_hash_duration_seconds",
+                    "Time spent in Argon2, by operation.",
+                )
+                .buckets(HASH_BUCKETS.to_vec()),
+                &["operation"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn Collector>; 6] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+            Box::new(metrics.logins.clone()),
+            Box::new(metrics.registrations.clone()),
+            Box::new(metrics.token_validation_failures.clone()),
+            Box::new(metrics.password_hash_duration.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+
+    /// `outcome` is `succeeded` or the reason the login failed.
+    pub fn login(&self, outcome: &str) {
+        self.logins.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn registration(&self) {
+        self.registrations.inc();
+    }
+
+    pub fn token_validation_failure(&self, reason: &str) {
+        self.token_validation_failures
+            .with_label_values(&[reason])
+            .inc();
+    }
+
+    /// `operation` is `hash` or `verify`.
+    pub fn password_hash(&self, operation: &str, duration: Duration) {
+        self.password_hash_duration
+            .with_label_values(&[operation])
+            .observe(duration.as_secs_f64());
+    }
+
+    fn request(&self, method: &Method, route: &str, status: u16, duration: Duration) {
+        let method = if METHODS.contains(method) {
+            method.as_str()
+        } else {
+            "OTHER"
+        };
+        let status = status.to_string();
+        let labels = [method, route, status.as_str()];
+        self.http_requests.with_label_values(&labels).inc();
+        self.http_request_duration
+            .with_label_values(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    fn render(&self, app_state: &AppState) -> String {
+        let mut families = self.registry.gather();
+        if let Some(stats) = app_state.db_client.pool_stats() {
+            families.extend(
+                [
+                    ("db_pool_connections", "Open connections.", stats.size),
+                    ("db_pool_idle_connections", "Idle connections.", stats.idle),
+                    (
+                        "db_pool_in_use_connections",
+                        "Connections checked out of the pool.",
+                        stats.in_use(),
+                    ),
+                    (
+                        "db_pool_max_connections",
+                        "Configured maximum connections.",
+                        stats.max,
+                    ),
+                ]
+                .into_iter()
+                .flat_map(|(name, help, value)| gauge(name, help, value)),
+            );
+        }
+
+        let replicas = app_state.db_client.replica_stats();
+        if !replicas.is_empty() {
+            let series = |value: fn(&ReplicaStats) -> u32| {
+                replicas
+                    .iter()
+                    .map(move |replica| (replica.index, value(replica)))
+            };
+            families.extend(
+                [
+                    replica_gauge(
+                        "db_replica_pool_connections",
+                        "Open connections to a read replica.",
+                        series(|replica| replica.pool.size),
+                    ),
+                    replica_gauge(
+                        "db_replica_pool_idle_connections",
+                        "Idle connections to a read replica.",
+                        series(|replica| replica.pool.idle),
+                    ),
+                    replica_gauge(
+                        "db_replica_pool_in_use_connections",
+                        "Connections checked out of a read replica's pool.",
+                        series(|replica| replica.pool.in_use()),
+                    ),
+                    replica_gauge(
+                        "db_replica_pool_max_connections",
+                        "Configured maximum connections to a read replica.",
+                        series(|replica| replica.pool.max),
+                    ),
+                    replica_gauge(
+                        "db_replica_in_rotation",
+                        "1 while reads are routed to a read replica, 0 while it is skipped after a failure.",
+                        series(|replica| replica.in_rotation.into()),
+                    ),
+                ]
+                .into_iter()
+                .flatten(),
+            );
+        }
+
+        let mut out = Vec::new();
+        TextEncoder::new()
+            .encode(&families, &mut out)
+            .expect("encode metrics");
+        String::from_utf8(out).expect("metrics are UTF-8")
+    }
+}
+
+/// A gauge read at scrape time, such as pool usage, which is not worth
+/// keeping in the registry between scrapes.
+fn gauge(name: &str, help: &str, value: u32) -> Vec<MetricFamily> {
+    let gauge = IntGauge::new(name, help).unwrap();
+    gauge.set(value.into());
+    gauge.collect()
+}
+
+/// Like [`gauge`], with a series per read replica labelled with its position
+/// in `database.replica_urls`.
+fn replica_gauge(
+    name: &str,
+    help: &str,
+    series: impl Iterator<Item = (usize, u32)>,
+) -> Vec<MetricFamily> {
+    let gauge = IntGaugeVec::new(Opts::new(name, help), &["replica"]).unwrap();
+    for (index, value) in series {
+        gauge
+            .with_label_values(&[index.to_string().as_str()])
+            .set(value.into());
+    }
+    gauge.collect()
+}
+
+/// Records count and latency of every request. Requests that match no route
+/// are grouped under `unmatched`.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, actix_web::Error> {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let result = next
+        .call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body);
+
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.error_response().status(),
+    };
+    METRICS.request(&method, &route, status.as_u16(), started.elapsed());
+
+    result
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(metrics));
+}
+
+async fn metrics(app_state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType(
+            "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+        ))
+        .body(METRICS.render(&app_state))
+}
@@ -0,0 +1,83 @@
+//! Schema migrations. They run at startup unless `database.auto_migrate` is
+//! off, and readiness reports migrations this build expects but the database
+//! lacks, so an instance is never sent traffic against an outdated schema.
+
+use sqlx::{
+    migrate::{Migrate, MigrateError, Migrator},
+    Connection, PgConnection, PgPool,
+};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Where the database stands relative to the migrations compiled into this
+/// build.
+#[derive(Debug, Clone, Default)]
+pub struct MigrationStatus {
+    /// Versions not applied yet, oldest first.
+    pub pending: Vec<i64>,
+    /// A migration that started but did not finish, leaving the schema in an
+    /// unknown state.
+    pub failed: Option<i64>,
+}
+
+impl MigrationStatus {
+    pub fn is_current(&self) -> bool {
+        self.pending.is_empty() && self.failed.is_none()
+    }
+}
+
+/// Applies pending migrations. sqlx holds an advisory lock while it does, so
+/// an instance starting while another migrates waits for it; by the time it
+/// gets the lock, there is usually nothing left to do.
+pub async fn run(pool: &PgPool) -> Result<(), MigrateError> {
+    // A connection of its own rather than a pooled one: the lock and the
+    // lifted timeout belong to its session, which ends when it is closed.
+    let mut conn = PgConnection::connect_with(&pool.connect_options()).await?;
+
+    // Neither waiting for the lock nor a long migration should be cut short
+    // by `database.statement_timeout_ms`.
+    let result = match sqlx::query("SET statement_timeout = 0")
+        .execute(&mut conn)
+        .await
+    {
+        Ok(_) => MIGRATOR.run(&mut conn).await,
+        Err(e) => Err(e.into()),
+    };
+
+    if let Err(e) = conn.close().await {
+        tracing::warn!(error = %e, "Failed to close migration connection");
+    }
+
+    result
+}
+
+pub async fn status(pool: &PgPool) -> Result<MigrationStatus, MigrateError> {
+    let mut conn = pool.acquire().await?;
+
+    let has_table: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(&mut *conn)
+        .await?;
+    if !has_table {
+        return Ok(MigrationStatus {
+            pending: up_migrations().collect(),
+            failed: None,
+        });
+    }
+
+    let applied = conn.list_applied_migrations().await?;
+    let failed = conn.dirty_version().await?;
+
+    Ok(MigrationStatus {
+        pending: up_migrations()
+            .filter(|version| !applied.iter().any(|m| m.version == *version))
+            .collect(),
+        failed,
+    })
+}
+
+fn up_migrations() -> impl Iterator<Item = i64> {
+    MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .map(|m| m.version)
+}
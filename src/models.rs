@@ -1,8 +1,9 @@
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, sqlx::Type)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, sqlx::Type, utoipa::ToSchema)]
 #[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum UserRole {
     Admin,
     Moderator,
@@ -19,7 +20,7 @@ impl UserRole {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow, sqlx::Type)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, sqlx::FromRow, sqlx::Type)]
 pub struct User {
     pub id: uuid::Uuid,
     pub name: String,
@@ -30,8 +31,95 @@ pub struct User {
     pub password: String,
 
     #[serde(rename = "createdAt")]
-    pub created_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
 
     #[serde(rename = "updatedAt")]
-    pub updated_at: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+
+    pub disabled: bool,
+
+    #[serde(rename = "deletedAt")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
+pub struct ApiKey {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    LoginSucceeded,
+    LoginFailed,
+    Logout,
+    Registered,
+    PasswordChanged,
+    RoleChanged,
+    UsersListed,
+    AuditExported,
+    UserDeleted,
+    UserSuspended,
+    UserUnsuspended,
+    UserRestored,
+    UsersPurged,
+    DataExported,
+}
+
+impl AuditAction {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            AuditAction::LoginSucceeded => "login.succeeded",
+            AuditAction::LoginFailed => "login.failed",
+            AuditAction::Logout => "logout",
+            AuditAction::Registered => "user.registered",
+            AuditAction::PasswordChanged => "user.password_changed",
+            AuditAction::RoleChanged => "user.role_changed",
+            AuditAction::UsersListed => "admin.users_listed",
+            AuditAction::AuditExported => "admin.audit_exported",
+            AuditAction::UserDeleted => "user.deleted",
+            AuditAction::UserSuspended => "user.suspended",
+            AuditAction::UserUnsuspended => "user.unsuspended",
+            AuditAction::UserRestored => "user.restored",
+            AuditAction::UsersPurged => "users.purged",
+            AuditAction::DataExported => "user.data_exported",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub id: uuid::Uuid,
+    pub action: String,
+    pub actor_id: Option<uuid::Uuid>,
+    pub target_id: Option<uuid::Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, sqlx::Type, utoipa::ToSchema)]
+#[sqlx(type_name = "data_export_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DataExportStatus {
+    Pending,
+    Ready,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
+pub struct DataExport {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub status: DataExportStatus,
+    pub payload: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub expires_at: DateTime<Utc>,
 }
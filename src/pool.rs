@@ -0,0 +1,93 @@
+//! The Postgres connection pool. Its size, timeouts and per-connection
+//! `statement_timeout` come from `[database]`, and connecting at startup is
+//! retried with backoff so the service can start before Postgres is up.
+//! Read replicas share the same settings.
+
+use std::time::Duration;
+
+use actix_web::rt;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    PgPool,
+};
+
+use crate::config::DatabaseConfig;
+
+/// Longest wait between two connection attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Upper bound on waiting for a replica connection. Reads fall back to the
+/// primary when a replica is down, and that should not take the full
+/// `database.acquire_timeout_seconds`.
+const REPLICA_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// SQLSTATEs Postgres answers with while it cannot take connections yet:
+/// `cannot_connect_now` (starting up or shutting down) and
+/// `too_many_connections`.
+const TRANSIENT_SQLSTATES: [&str; 2] = ["57P03", "53300"];
+
+pub fn pool_options(config: &DatabaseConfig) -> PgPoolOptions {
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_seconds))
+        .idle_timeout(config.idle_timeout_seconds.map(Duration::from_secs))
+        .max_lifetime(config.max_lifetime_seconds.map(Duration::from_secs))
+}
+
+/// Sets `statement_timeout` on every connection opened with `options`.
+pub fn connect_options(options: PgConnectOptions, config: &DatabaseConfig) -> PgConnectOptions {
+    options.options([("statement_timeout", config.statement_timeout_ms.to_string())])
+}
+
+/// Opens the pool, trying up to `database.connect_attempts` times while the
+/// failure looks temporary. Bad credentials or an unknown database fail
+/// straight away.
+pub async fn connect(
+    config: &DatabaseConfig,
+    options: PgConnectOptions,
+) -> Result<PgPool, sqlx::Error> {
+    let options = connect_options(options, config);
+    let mut backoff = Duration::from_millis(config.connect_backoff_ms);
+    let mut attempt = 1;
+
+    loop {
+        match pool_options(config).connect_with(options.clone()).await {
+            Ok(pool) => return Ok(pool),
+            Err(e) if attempt < config.connect_attempts && is_transient(&e) => {
+                tracing::warn!(
+                    error = %e,
+                    attempt,
+                    max_attempts = config.connect_attempts,
+                    retry_in_ms = backoff.as_millis() as u64,
+                    "Failed to connect to Postgres, retrying"
+                );
+                rt::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A pool for a read replica. It connects lazily, so a replica that is down
+/// at startup does not hold the service back; reads use the primary until it
+/// answers.
+pub fn connect_replica(config: &DatabaseConfig, options: PgConnectOptions) -> PgPool {
+    let acquire_timeout =
+        Duration::from_secs(config.acquire_timeout_seconds).min(REPLICA_ACQUIRE_TIMEOUT);
+    pool_options(config)
+        .acquire_timeout(acquire_timeout)
+        .connect_lazy_with(connect_options(options, config))
+}
+
+fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(e) => e
+            .code()
+            .is_some_and(|code| TRANSIENT_SQLSTATES.contains(&code.as_ref())),
+        _ => false,
+    }
+}
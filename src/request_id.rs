@@ -0,0 +1,140 @@
+//! Request correlation. Each request gets an id, taken from `X-Request-Id`
+//! when the caller sent a usable one and generated otherwise. It is echoed in
+//! the response header, added to JSON error bodies as `requestId`, and set on
+//! the request span so every log line for the request carries it. The span
+//! ends with one access log line per request, and is the server span of the
+//! request's trace, joining the caller's trace when it sent `traceparent`.
+
+use std::time::Instant;
+
+use actix_web::{
+    body::{self, BodySize, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    HttpResponse,
+};
+use serde_json::Value;
+use tracing::{field, Instrument};
+
+use crate::telemetry::{self, TRACEPARENT};
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longer ids from callers are replaced rather than logged.
+const MAX_LEN: usize = 128;
+
+/// Error bodies up to this size get the id added.
+const MAX_ERROR_BODY: u64 = 64 * 1024;
+
+fn from_header(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(X_REQUEST_ID)?.to_str().ok()?;
+    let valid = !value.is_empty()
+        && value.len() <= MAX_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+    valid.then(|| value.to_string())
+}
+
+/// Adds the user to the request span once they are authenticated.
+pub fn record_user(user_id: uuid::Uuid) {
+    tracing::Span::current().record("user_id", field::display(user_id));
+}
+
+pub async fn propagate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, actix_web::Error> {
+    let started = Instant::now();
+    let request_id = from_header(&req).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = req.path(),
+        route = route.as_str(),
+        user_id = field::Empty,
+        status = field::Empty,
+        otel.name = format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        otel.status_code = field::Empty,
+    );
+    if let Some(parent) = req
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+    {
+        telemetry::set_remote_parent(&span, parent);
+    }
+
+    let result = next.call(req).instrument(span.clone()).await;
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.error_response().status(),
+    };
+
+    // As i64: tracing-opentelemetry exports unsigned fields as strings.
+    span.record("status", i64::from(status.as_u16()));
+    if status.is_server_error() {
+        span.record("otel.status_code", "error");
+    }
+
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    span.in_scope(|| {
+        if status.is_server_error() {
+            tracing::error!(status = status.as_u16(), latency_ms, "request failed");
+        } else {
+            tracing::info!(status = status.as_u16(), latency_ms, "request completed");
+        }
+    });
+
+    match result {
+        Ok(res) => {
+            let (req, res) = res.map_into_boxed_body().into_parts();
+            let res = with_request_id(res, &request_id).await;
+            Ok(ServiceResponse::new(req, res))
+        }
+        // Errors from middleware are rendered here rather than by the server,
+        // so that their response gets the id as well.
+        Err(e) => {
+            let res = with_request_id(e.error_response(), &request_id).await;
+            Err(InternalError::from_response(e, res).into())
+        }
+    }
+}
+
+/// Echoes the id in `X-Request-Id` and, for errors whose body is a JSON
+/// object, adds it as `requestId`.
+async fn with_request_id(mut res: HttpResponse, request_id: &str) -> HttpResponse {
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        res.headers_mut().insert(X_REQUEST_ID, value);
+    }
+
+    // Middleware errors render JSON bodies as text/plain, so go by the
+    // content rather than the content type.
+    let is_error = res.status().is_client_error() || res.status().is_server_error();
+    let is_small = matches!(res.body().size(), BodySize::Sized(n) if n <= MAX_ERROR_BODY);
+    if !(is_error && is_small) {
+        return res;
+    }
+
+    let (res, body) = res.into_parts();
+    let Ok(bytes) = body::to_bytes(body).await else {
+        return res.set_body(BoxBody::new(()));
+    };
+
+    let body = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut object)) => {
+            object.insert("requestId".to_string(), request_id.into());
+            serde_json::to_vec(&object).map_or(bytes, Into::into)
+        }
+        _ => bytes,
+    };
+    res.set_body(BoxBody::new(body))
+}
@@ -0,0 +1,111 @@
+//! Graceful shutdown. On SIGTERM or SIGINT readiness starts failing at once,
+//! so load balancers stop sending new requests, while the server keeps
+//! serving for `shutdown.readiness_delay_seconds`. It then stops accepting
+//! connections and gives in-flight requests up to
+//! `shutdown.drain_timeout_seconds` to finish. A second signal skips whatever
+//! is left and stops immediately.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix_web::{dev::ServerHandle, rt};
+use futures_util::future::{select, Either};
+
+use crate::config::ShutdownConfig;
+
+/// Whether shutdown has started. Shared with the readiness probe.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownState(Arc<AtomicBool>);
+
+impl ShutdownState {
+    pub fn begin(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The signals that start a shutdown.
+pub struct Signals {
+    #[cfg(unix)]
+    terminate: rt::signal::unix::Signal,
+    #[cfg(unix)]
+    interrupt: rt::signal::unix::Signal,
+}
+
+impl Signals {
+    /// Must be called from within the runtime.
+    pub fn new() -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use rt::signal::unix::{signal, SignalKind};
+            Ok(Signals {
+                terminate: signal(SignalKind::terminate())?,
+                interrupt: signal(SignalKind::interrupt())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(Signals {})
+    }
+
+    /// Waits for the next signal and returns its name.
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            let terminate = Box::pin(self.terminate.recv());
+            let interrupt = Box::pin(self.interrupt.recv());
+            match select(terminate, interrupt).await {
+                Either::Left(_) => "SIGTERM",
+                Either::Right(_) => "SIGINT",
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = rt::signal::ctrl_c().await;
+            "Ctrl-C"
+        }
+    }
+}
+
+/// Runs the shutdown sequence for `server` once a signal arrives. The server
+/// must have been built with `disable_signals` and its shutdown timeout set to
+/// the drain timeout.
+pub async fn handle_signals(
+    mut signals: Signals,
+    server: ServerHandle,
+    state: ShutdownState,
+    config: ShutdownConfig,
+) {
+    let signal = signals.recv().await;
+    state.begin();
+    tracing::info!(
+        signal,
+        readiness_delay_seconds = config.readiness_delay_seconds,
+        drain_timeout_seconds = config.drain_timeout_seconds,
+        "Shutting down, readiness now fails"
+    );
+
+    let delay = Box::pin(rt::time::sleep(Duration::from_secs(
+        config.readiness_delay_seconds,
+    )));
+    if let Either::Right((signal, _)) = select(delay, Box::pin(signals.recv())).await {
+        tracing::warn!(signal, "Received a second signal, stopping immediately");
+        server.stop(false).await;
+        return;
+    }
+
+    tracing::info!("Stopped accepting connections, draining in-flight requests");
+    let drain = Box::pin(server.stop(true));
+    if let Either::Right((signal, _)) = select(drain, Box::pin(signals.recv())).await {
+        tracing::warn!(signal, "Received a second signal, stopping immediately");
+        server.stop(false).await;
+    }
+}
@@ -0,0 +1,149 @@
+//! Where uploaded files live. Handlers only see the [`Storage`] trait, so a
+//! remote backend can be added next to [`LocalStorage`] without touching them.
+
+use std::{fmt, io, path::PathBuf, sync::Arc};
+
+use actix_files::Files;
+use actix_web::web;
+use async_trait::async_trait;
+
+use crate::config::{StorageBackend, StorageConfig};
+
+/// The avatar every user starts with. Stored under this key at startup.
+pub const DEFAULT_PHOTO: &str = "default.png";
+const DEFAULT_PHOTO_BYTES: &[u8] = include_bytes!("../assets/default.png");
+
+#[async_trait]
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Stores `bytes` under `key`, replacing any existing file.
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> io::Result<()>;
+
+    /// Removes the file under `key`. Removing a missing file is not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// The URL clients can fetch the file under `key` from.
+    fn url(&self, key: &str) -> String;
+}
+
+pub fn build(config: &StorageConfig) -> Arc<dyn Storage> {
+    match config.backend {
+        StorageBackend::Local => Arc::new(LocalStorage::new(
+            config.local_path.clone(),
+            config.public_url.clone(),
+        )),
+    }
+}
+
+/// Serves files of the local backend when `public_url` is a path on this app.
+pub fn configure_routes(config: &StorageConfig, cfg: &mut web::ServiceConfig) {
+    if config.backend == StorageBackend::Local && config.public_url.starts_with('/') {
+        cfg.service(Files::new(
+            config.public_url.trim_end_matches('/'),
+            &config.local_path,
+        ));
+    }
+}
+
+/// Uploads the bundled default avatar unless the storage already has one, so
+/// a custom default can be dropped in place.
+pub async fn ensure_default_photo(storage: &dyn Storage) -> io::Result<()> {
+    if !storage.exists(DEFAULT_PHOTO).await? {
+        storage
+            .put(DEFAULT_PHOTO, DEFAULT_PHOTO_BYTES.to_vec())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Keys are generated by us, but are still checked so that a bad one can
+/// never escape the storage root.
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+}
+
+fn invalid_key(key: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid storage key {:?}", key),
+    )
+}
+
+/// Stores files in a directory on the local filesystem.
+#[derive(Debug)]
+pub struct LocalStorage {
+    root: PathBuf,
+    public_url: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, public_url: impl Into<String>) -> Self {
+        LocalStorage {
+            root: root.into(),
+            public_url: public_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if valid_key(key) {
+            Ok(self.root.join(key))
+        } else {
+            Err(invalid_key(key))
+        }
+    }
+}
+
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(io::Error::other)?
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> io::Result<()> {
+        let path = self.path(key)?;
+
+        blocking(move || {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            // Write next to the target and rename, so readers never see a
+            // half-written file.
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, bytes)?;
+            std::fs::rename(&tmp, &path)
+        })
+        .await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key)?;
+
+        blocking(move || match std::fs::remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        })
+        .await
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        let path = self.path(key)?;
+        blocking(move || path.try_exists()).await
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.public_url, key)
+    }
+}
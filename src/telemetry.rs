@@ -0,0 +1,252 @@
+//! Trace export. When `tracing.enabled` is set, the request span and a span
+//! per SQL statement are sent to an OpenTelemetry collector over OTLP/HTTP
+//! (protobuf), with `http://` or `https://` endpoints and any headers the
+//! collector needs for authentication. A request joins the caller's trace when
+//! it carries a W3C `traceparent` header, and otherwise starts a new one that
+//! is kept with probability `tracing.sample_ratio`.
+//!
+//! Spans are built by `tracing-opentelemetry` from the `tracing` spans down to
+//! `info`, with `otel.name`, `otel.kind` and `otel.status_code` fields setting
+//! the span's name, kind and status. Attributes are redacted like log lines
+//! before they leave the process.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use opentelemetry::{
+    propagation::TextMapPropagator,
+    trace::{Span as _, SpanKind, TraceContextExt, Tracer as _, TracerProvider as _},
+    Context, KeyValue, StringValue, Value,
+};
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracer, SdkTracerProvider, SpanData, SpanExporter},
+    Resource,
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::filter_fn, layer::Context as LayerContext, registry::LookupSpan, Layer,
+};
+
+use crate::{
+    config::TracingConfig,
+    logging::{self, QUERY_TARGET, REDACTED},
+};
+
+pub const TRACEPARENT: &str = "traceparent";
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the current span sits in a distributed trace, as W3C trace and span
+/// ids, when traces are exported.
+pub fn current_ids() -> Option<(String, String)> {
+    let context = Context::current();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context.is_valid().then(|| {
+        (
+            span_context.trace_id().to_string(),
+            span_context.span_id().to_string(),
+        )
+    })
+}
+
+/// Makes `span`, which must have just been created, continue the trace
+/// described by a `traceparent` header instead of starting its own. Sampling
+/// follows the caller's decision.
+pub fn set_remote_parent(span: &tracing::Span, traceparent: &str) {
+    let headers = HashMap::from([(TRACEPARENT.to_string(), traceparent.to_string())]);
+    let parent = TraceContextPropagator::new().extract(&headers);
+    if parent.span().span_context().is_valid() {
+        let _ = span.set_parent(parent);
+    }
+}
+
+/// The OTLP/HTTP exporter for `config`.
+pub fn otlp_exporter(
+    config: &TracingConfig,
+) -> Result<opentelemetry_otlp::SpanExporter, Box<dyn std::error::Error>> {
+    // The blocking client cannot be built from within an async runtime, which
+    // is where `main` runs. It is used from the exporter's own thread.
+    let client = std::thread::spawn(|| {
+        reqwest::blocking::Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+    })
+    .join()
+    .map_err(|_| "failed to build the OTLP HTTP client")??;
+    let headers = config
+        .otlp_headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
+    Ok(opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_http_client(client)
+        .with_endpoint(&config.otlp_endpoint)
+        .with_headers(headers)
+        .with_timeout(HTTP_TIMEOUT)
+        .build()?)
+}
+
+/// Sends spans to `exporter` in batches from a background thread, sampling
+/// new traces at `tracing.sample_ratio`.
+pub fn provider(
+    exporter: impl SpanExporter + 'static,
+    config: &TracingConfig,
+) -> SdkTracerProvider {
+    SdkTracerProvider::builder()
+        .with_batch_exporter(Redacted(exporter))
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build()
+}
+
+/// Builds spans from `tracing` spans down to `info`, and client spans for
+/// sqlx's statements, with `provider`.
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let spans = tracing_opentelemetry::layer()
+        .with_tracer(tracer.clone())
+        .with_filter(filter_fn(|metadata| {
+            metadata.is_span() && *metadata.level() <= Level::INFO
+        }));
+    let queries =
+        QueryLayer { tracer }.with_filter(filter_fn(|metadata| metadata.target() == QUERY_TARGET));
+    spans.and_then(queries)
+}
+
+/// Turns sqlx's per-statement events into client spans under the current
+/// span, dated back by the statement's duration.
+struct QueryLayer {
+    tracer: SdkTracer,
+}
+
+impl<S: Subscriber> Layer<S> for QueryLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let parent = Context::current();
+        if !parent.span().span_context().is_sampled() {
+            return;
+        }
+
+        let mut query = Query::default();
+        event.record(&mut query);
+        let end = SystemTime::now();
+        let summary = query.summary.unwrap_or_else(|| "query".to_string());
+        let statement = query
+            .statement
+            .map(|statement| statement.trim().to_string())
+            .filter(|statement| !statement.is_empty())
+            .unwrap_or_else(|| summary.clone());
+
+        let mut attributes = vec![
+            KeyValue::new("db.system", "postgresql"),
+            KeyValue::new("db.statement", statement),
+        ];
+        attributes.extend(query.rows);
+
+        let mut span = self
+            .tracer
+            .span_builder(summary)
+            .with_kind(SpanKind::Client)
+            .with_start_time(end - query.elapsed)
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, &parent);
+        span.end_with_timestamp(end);
+    }
+}
+
+/// The fields of sqlx's per-statement event.
+#[derive(Default)]
+struct Query {
+    summary: Option<String>,
+    statement: Option<String>,
+    elapsed: Duration,
+    rows: Vec<KeyValue>,
+}
+
+impl Visit for Query {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed = Duration::try_from_secs_f64(value).unwrap_or_default();
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if let name @ ("rows_affected" | "rows_returned") = field.name() {
+            self.rows.push(KeyValue::new(name, value as i64));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "summary" => self.summary = Some(value.to_string()),
+            "db.statement" => self.statement = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "summary" | "db.statement" => self.record_str(field, &format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Redacts span attributes before handing spans to the exporter, the same way
+/// [`crate::logging`] does for log lines.
+#[derive(Debug)]
+struct Redacted<E>(E);
+
+impl<E: SpanExporter> SpanExporter for Redacted<E> {
+    async fn export(&self, mut batch: Vec<SpanData>) -> OTelSdkResult {
+        for span in &mut batch {
+            for attribute in &mut span.attributes {
+                attribute.value = if logging::is_secret(attribute.key.as_str()) {
+                    REDACTED.into()
+                } else {
+                    redact(&attribute.value)
+                };
+            }
+        }
+        self.0.export(batch).await
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.0.set_resource(resource);
+    }
+}
+
+fn redact(value: &Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(StringValue::from(logging::scrub(s.as_str()))),
+        other => other.clone(),
+    }
+}
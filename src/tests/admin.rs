@@ -0,0 +1,226 @@
+use actix_web::test;
+use serde_json::{json, Value};
+
+use crate::db::NewAuditEvent;
+
+use super::{app_state, bearer, init, login, sign_up, sign_up_admin, status, PASSWORD};
+
+#[actix_web::test]
+async fn audit_log_records_logins() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (user, _) = sign_up(&app, "bob", "bob@example.com").await;
+    login(&app, "bob@example.com", "wrong-password").await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/audit?action=login.failed")
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["total"], 1);
+    assert_eq!(body["data"][0]["targetId"], user["id"]);
+    assert_eq!(body["data"][0]["metadata"]["reason"], "wrong_password");
+}
+
+#[actix_web::test]
+async fn audit_log_is_admin_only() {
+    let app = init(&app_state()).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/audit")
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 403);
+}
+
+#[actix_web::test]
+async fn audit_export_streams_every_page_as_csv() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (admin, admin_token) = sign_up_admin(&app_state, &app).await;
+
+    for i in 0..1500 {
+        app_state
+            .db_client
+            .save_audit_event(NewAuditEvent {
+                action: "login.failed",
+                actor_id: None,
+                target_id: None,
+                ip_address: None,
+                user_agent: Some(format!("=agent {}", i)),
+                metadata: json!({ "reason": "unknown_email" }),
+            })
+            .await
+            .unwrap();
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/audit/export?action=login.failed")
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+
+    let mut lines = body.lines();
+    assert_eq!(
+        lines.next(),
+        Some("id,created_at,action,actor_id,target_id,ip_address,user_agent,metadata")
+    );
+    let rows: Vec<_> = lines.collect();
+    assert_eq!(rows.len(), 1500);
+    let ids: std::collections::HashSet<_> = rows.iter().map(|row| &row[..36]).collect();
+    assert_eq!(ids.len(), 1500);
+    assert!(rows.iter().all(|row| row.contains(",'=agent ")));
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/audit?action=admin.audit_exported")
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"][0]["actorId"], admin.id.to_string());
+    assert_eq!(body["data"][0]["metadata"]["events"], 1500);
+}
+
+#[actix_web::test]
+async fn audit_export_defuses_every_formula_trigger() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+
+    let agents = [
+        "=1+1", "+1", "-1", "@SUM(A1)", "\t=1", "\r=1", "  =1", "agent",
+    ];
+    for agent in agents {
+        app_state
+            .db_client
+            .save_audit_event(NewAuditEvent {
+                action: "login.failed",
+                actor_id: None,
+                target_id: None,
+                ip_address: None,
+                user_agent: Some(agent.to_string()),
+                metadata: json!({}),
+            })
+            .await
+            .unwrap();
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/audit/export?action=login.failed")
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+
+    for agent in [
+        "'=1+1",
+        "'+1",
+        "'-1",
+        "'@SUM(A1)",
+        "'\t=1",
+        "\"'\r=1\"",
+        "'  =1",
+    ] {
+        assert!(body.contains(&format!(",{},", agent)), "{:?}", agent);
+    }
+    assert!(body.contains(",agent,"));
+}
+
+#[actix_web::test]
+async fn audit_queries_reject_an_inverted_range() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+
+    for path in ["/api/admin/audit", "/api/admin/audit/export"] {
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "{}?from=2025-02-01T00:00:00Z&to=2025-01-01T00:00:00Z",
+                path
+            ))
+            .insert_header(bearer(&admin_token))
+            .to_request();
+        assert_eq!(status(&app, req).await, 400, "{}", path);
+    }
+}
+
+#[actix_web::test]
+async fn suspended_users_cannot_log_in_until_unsuspended() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (user, _) = sign_up(&app, "bob", "bob@example.com").await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+    let id = user["id"].as_str().unwrap();
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/admin/users/{}/suspend", id))
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["user"]["disabled"], true);
+
+    assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 403);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/admin/users/{}/unsuspend", id))
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 200);
+}
+
+#[actix_web::test]
+async fn suspended_users_role_can_be_changed() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (user, _) = sign_up(&app, "bob", "bob@example.com").await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+    let id = user["id"].as_str().unwrap();
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/admin/users/{}/suspend", id))
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    let req = test::TestRequest::patch()
+        .uri(&format!("/api/users/{}/role", id))
+        .insert_header(bearer(&admin_token))
+        .set_json(json!({ "role": "moderator" }))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["data"]["user"]["role"], "moderator");
+    assert_eq!(body["data"]["user"]["disabled"], true);
+}
+
+#[actix_web::test]
+async fn deleted_users_can_be_restored() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (user, _) = sign_up(&app, "bob", "bob@example.com").await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+    let id = user["id"].as_str().unwrap();
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/admin/users/{}/restore", id))
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 404);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/users/{}", id))
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/admin/users/{}/restore", id))
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 200);
+}
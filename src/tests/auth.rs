@@ -0,0 +1,321 @@
+use actix_web::{http::header, test};
+use serde_json::{json, Value};
+
+use super::{app_state, bearer, init, login, register, sign_up, status, PASSWORD};
+use crate::{config::BasicAuthConfig, throttle::FailureThrottle};
+
+#[actix_web::test]
+async fn register_normalizes_email_and_hides_password() {
+    let app = init(&app_state()).await;
+
+    let res = register(&app, "bob", "  Bob@EXAMPLE.com ").await;
+    assert_eq!(res.status(), 201);
+
+    let body: Value = test::read_body_json(res).await;
+    let user = &body["data"]["user"];
+    assert_eq!(user["email"], "Bob@example.com");
+    assert_eq!(user["role"], "user");
+    assert!(user.get("password").is_none());
+}
+
+#[actix_web::test]
+async fn register_rejects_duplicate_email_regardless_of_case() {
+    let app = init(&app_state()).await;
+
+    assert_eq!(register(&app, "bob", "bob@example.com").await.status(), 201);
+    assert_eq!(register(&app, "bob", "BOB@example.com").await.status(), 409);
+}
+
+#[actix_web::test]
+async fn register_validates_body() {
+    let app = init(&app_state()).await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/register")
+        .set_json(json!({
+            "name": "bob",
+            "email": "bob@example.com",
+            "password": PASSWORD,
+            "confirmPassword": "something else",
+        }))
+        .to_request();
+    assert_eq!(status(&app, req).await, 400);
+
+    assert_eq!(register(&app, "bob", "not-an-email").await.status(), 400);
+}
+
+#[actix_web::test]
+async fn login_sets_cookie_and_returns_token() {
+    let app = init(&app_state()).await;
+    register(&app, "bob", "bob@example.com").await;
+
+    let res = login(&app, " BOB@example.com", PASSWORD).await;
+    assert_eq!(res.status(), 200);
+    assert!(res.response().cookies().any(|c| c.name() == "token"));
+
+    let body: Value = test::read_body_json(res).await;
+    assert!(body["token"].as_str().is_some_and(|t| !t.is_empty()));
+}
+
+#[actix_web::test]
+async fn login_rejects_wrong_credentials() {
+    let app = init(&app_state()).await;
+    register(&app, "bob", "bob@example.com").await;
+
+    assert_eq!(
+        login(&app, "bob@example.com", "wrong-password")
+            .await
+            .status(),
+        401
+    );
+    assert_eq!(
+        login(&app, "nobody@example.com", PASSWORD).await.status(),
+        401
+    );
+}
+
+#[actix_web::test]
+async fn enabling_the_pepper_keeps_existing_passwords_working() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    register(&app, "bob", "bob@example.com").await;
+
+    let mut peppered = app_state.clone();
+    peppered.env.password.pepper = Some("pepper".to_string());
+    let app = init(&peppered).await;
+    assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 200);
+
+    let stored = peppered
+        .db_client
+        .get_user(None, None, Some("bob@example.com"))
+        .await
+        .unwrap()
+        .unwrap()
+        .password;
+    assert!(stored.contains("keyid="), "{stored}");
+    assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 200);
+    assert_eq!(
+        login(&app, "bob@example.com", "wrong-password")
+            .await
+            .status(),
+        401
+    );
+}
+
+#[actix_web::test]
+async fn session_reports_who_is_logged_in() {
+    let app = init(&app_state()).await;
+    let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/auth/session")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["authenticated"], false);
+
+    let req = test::TestRequest::get()
+        .uri("/api/auth/session")
+        .insert_header(bearer(&token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["authenticated"], true);
+    assert_eq!(body["user"]["id"], user["id"]);
+}
+
+#[actix_web::test]
+async fn logout_requires_auth_and_clears_cookie() {
+    let app = init(&app_state()).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/logout")
+        .to_request();
+    assert_eq!(status(&app, req).await, 401);
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/logout")
+        .insert_header(bearer(&token))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    let cookie = res
+        .response()
+        .cookies()
+        .find(|c| c.name() == "token")
+        .unwrap();
+    assert_eq!(cookie.value(), "");
+}
+
+#[actix_web::test]
+async fn cookie_auth_requires_csrf_token_on_unsafe_requests() {
+    let app = init(&app_state()).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+    let auth_cookie = actix_web::cookie::Cookie::new("token", token);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .cookie(auth_cookie.clone())
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/logout")
+        .cookie(auth_cookie.clone())
+        .to_request();
+    assert_eq!(status(&app, req).await, 403);
+
+    let req = test::TestRequest::get().uri("/api/auth/csrf").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let csrf = body["token"].as_str().unwrap().to_string();
+
+    let req = test::TestRequest::post()
+        .uri("/api/auth/logout")
+        .cookie(auth_cookie)
+        .cookie(actix_web::cookie::Cookie::new("csrf_token", csrf.clone()))
+        .insert_header(("x-csrf-token", csrf))
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+}
+
+#[actix_web::test]
+async fn basic_auth_is_accepted() {
+    use base64::Engine;
+
+    let app = init(&app_state()).await;
+    register(&app, "bob", "bob@example.com").await;
+
+    let credentials =
+        base64::engine::general_purpose::STANDARD.encode(format!("Bob@Example.com:{}", PASSWORD));
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .peer_addr("10.0.0.1:4000".parse().unwrap())
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    // Failures could not be throttled without the client's address.
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+        .to_request();
+    assert_eq!(status(&app, req).await, 401);
+}
+
+fn basic_auth(email: &str, password: &str, client: &str) -> test::TestRequest {
+    use base64::Engine;
+
+    let credentials =
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", email, password));
+    test::TestRequest::get()
+        .uri("/api/users/me")
+        .peer_addr(client.parse().unwrap())
+        .insert_header((header::AUTHORIZATION, format!("Basic {}", credentials)))
+}
+
+fn basic_auth_request(email: &str, password: &str, client: &str) -> actix_http::Request {
+    basic_auth(email, password, client).to_request()
+}
+
+#[actix_web::test]
+async fn basic_auth_rejects_unknown_email_like_wrong_password() {
+    let app = init(&app_state()).await;
+    register(&app, "bob", "bob@example.com").await;
+
+    let req = basic_auth_request("nobody@example.com", PASSWORD, "10.0.0.1:4000");
+    let unknown = test::try_call_service(&app, req).await.err().unwrap();
+    let req = basic_auth_request("bob@example.com", "wrong-password", "10.0.0.1:4000");
+    let wrong = test::try_call_service(&app, req).await.err().unwrap();
+
+    assert_eq!(unknown.error_response().status(), 401);
+    assert_eq!(unknown.to_string(), wrong.to_string());
+}
+
+#[actix_web::test]
+async fn basic_auth_refuses_clients_after_repeated_failures() {
+    let mut state = app_state();
+    state.env.basic_auth.max_failures = 3;
+    let app = init(&state).await;
+    register(&app, "bob", "bob@example.com").await;
+
+    for _ in 0..3 {
+        let req = basic_auth_request("bob@example.com", "wrong-password", "10.0.0.1:4000");
+        assert_eq!(status(&app, req).await, 401);
+    }
+
+    // Even the right password is refused until the window ends.
+    let req = basic_auth_request("bob@example.com", PASSWORD, "10.0.0.1:4000");
+    let res = test::try_call_service(&app, req)
+        .await
+        .err()
+        .unwrap()
+        .error_response();
+    assert_eq!(res.status(), 429);
+    assert!(res.headers().contains_key(header::RETRY_AFTER));
+
+    // Other clients are not affected.
+    let req = basic_auth_request("bob@example.com", PASSWORD, "10.0.0.2:4000");
+    assert_eq!(status(&app, req).await, 200);
+}
+
+#[actix_web::test]
+async fn basic_auth_success_does_not_reset_failures() {
+    let mut state = app_state();
+    state.env.basic_auth.max_failures = 3;
+    let app = init(&state).await;
+    register(&app, "bob", "bob@example.com").await;
+    register(&app, "mallory", "mallory@example.com").await;
+
+    // Logging in to their own account between guesses gains an attacker
+    // nothing.
+    for attempt in 0..3 {
+        let req = basic_auth_request("bob@example.com", "guess", "10.0.0.1:4000");
+        assert_eq!(status(&app, req).await, 401);
+        if attempt < 2 {
+            let req = basic_auth_request("mallory@example.com", PASSWORD, "10.0.0.1:4000");
+            assert_eq!(status(&app, req).await, 200);
+        }
+    }
+
+    let req = basic_auth_request("mallory@example.com", PASSWORD, "10.0.0.1:4000");
+    assert_eq!(status(&app, req).await, 429);
+}
+
+#[actix_web::test]
+async fn concurrent_basic_attempts_cannot_exceed_the_limit() {
+    let throttle = FailureThrottle::default();
+    let config = BasicAuthConfig {
+        max_failures: 2,
+        ..BasicAuthConfig::default()
+    };
+    let client = "10.0.0.1".parse().unwrap();
+
+    // Attempts still being checked count against the limit.
+    assert!(throttle.reserve(client, &config).is_ok());
+    assert!(throttle.reserve(client, &config).is_ok());
+    assert!(throttle.reserve(client, &config).is_err());
+
+    throttle.release(client);
+    assert!(throttle.reserve(client, &config).is_ok());
+}
+
+#[actix_web::test]
+async fn basic_auth_throttles_the_client_behind_a_trusted_proxy() {
+    let mut state = app_state();
+    state.env.basic_auth.max_failures = 2;
+    state.env.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+    let app = init(&state).await;
+    register(&app, "bob", "bob@example.com").await;
+
+    let via_proxy = |password: &str, client: &str| {
+        basic_auth("bob@example.com", password, "10.0.0.1:4000")
+            .insert_header(("x-forwarded-for", client.to_string()))
+            .to_request()
+    };
+    for _ in 0..2 {
+        assert_eq!(status(&app, via_proxy("guess", "198.51.100.1")).await, 401);
+    }
+    assert_eq!(status(&app, via_proxy(PASSWORD, "198.51.100.1")).await, 429);
+
+    // Another client behind the same proxy is not locked out.
+    assert_eq!(status(&app, via_proxy(PASSWORD, "198.51.100.2")).await, 200);
+}
@@ -0,0 +1,62 @@
+use actix_web::test;
+use serde_json::Value;
+
+use super::{app_state, init};
+use crate::storage::DEFAULT_PHOTO;
+
+#[actix_web::test]
+async fn livez_always_succeeds() {
+    let app = init(&app_state()).await;
+
+    let req = test::TestRequest::get().uri("/livez").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+}
+
+#[actix_web::test]
+async fn readyz_reports_each_check() {
+    let app = init(&app_state()).await;
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["status"], "ready");
+    for check in ["database", "migrations", "storage"] {
+        assert_eq!(body["checks"][check]["status"], "up", "{}", check);
+    }
+}
+
+#[actix_web::test]
+async fn readyz_fails_when_storage_is_broken() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    app_state.storage.delete(DEFAULT_PHOTO).await.unwrap();
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 503);
+
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["status"], "not_ready");
+    assert_eq!(body["checks"]["storage"]["status"], "down");
+    assert_eq!(body["checks"]["database"]["status"], "up");
+}
+
+#[actix_web::test]
+async fn readyz_fails_once_shutdown_starts() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    app_state.shutdown.begin();
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 503);
+
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["status"], "not_ready");
+    assert_eq!(body["checks"]["shutdown"]["status"], "down");
+
+    let req = test::TestRequest::get().uri("/livez").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+}
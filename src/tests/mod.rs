@@ -0,0 +1,177 @@
+//! HTTP tests against the in-memory repository. They run the real handlers
+//! and middleware through `actix_web::test`, so no database is needed.
+
+mod admin;
+mod auth;
+mod health;
+mod logging;
+mod metrics;
+mod postgres;
+mod telemetry;
+mod users;
+
+use std::{io::Cursor, sync::Arc};
+
+use actix_http::Request;
+use actix_web::{
+    body::BoxBody,
+    dev::{Service, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::from_fn,
+    test, web, App, Error,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    config::Config,
+    db::memory::InMemoryDb,
+    models::User,
+    storage::{self, LocalStorage},
+    utils::password,
+    AppState,
+};
+
+pub const PASSWORD: &str = "password123";
+
+/// The service returned by [`init`].
+pub trait TestApp: Service<Request, Response = ServiceResponse<BoxBody>, Error = Error> {}
+
+impl<T> TestApp for T where T: Service<Request, Response = ServiceResponse<BoxBody>, Error = Error> {}
+
+/// Defaults plus what `Config::init` would insist on, with Argon2 turned down
+/// so the tests don't spend their time hashing.
+pub fn config() -> Config {
+    let mut config = Config::default();
+    config.jwt.secret = "test-secret".to_string();
+    config.cookie.secure = false;
+    config.password.memory_kib = 64;
+    config.password.iterations = 1;
+    config.password.parallelism = 1;
+    config
+}
+
+pub fn app_state() -> AppState {
+    let config = config();
+    let storage_root = std::env::temp_dir().join(format!("crud-rust-{}", uuid::Uuid::new_v4()));
+
+    AppState {
+        storage: Arc::new(LocalStorage::new(
+            storage_root,
+            config.storage.public_url.clone(),
+        )),
+        db_client: Arc::new(InMemoryDb::new()),
+        env: config,
+        shutdown: Default::default(),
+        basic_auth_failures: Default::default(),
+    }
+}
+
+pub async fn init(app_state: &AppState) -> impl TestApp {
+    storage::ensure_default_photo(app_state.storage.as_ref())
+        .await
+        .unwrap();
+
+    test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state.clone()))
+            .wrap(from_fn(crate::metrics::track))
+            .wrap(from_fn(crate::request_id::propagate))
+            .configure(crate::configure_api),
+    )
+    .await
+}
+
+/// The status the client would see. Middleware such as [`RequireAuth`]
+/// rejects with an error rather than a response, which `call_service` panics
+/// on; the server turns it into a response, so do the same here.
+///
+/// [`RequireAuth`]: crate::auth::RequireAuth
+pub async fn status(app: &impl TestApp, req: Request) -> StatusCode {
+    match test::try_call_service(app, req).await {
+        Ok(res) => res.status(),
+        Err(e) => e.error_response().status(),
+    }
+}
+
+pub fn bearer(token: &str) -> (header::HeaderName, String) {
+    (header::AUTHORIZATION, format!("Bearer {}", token))
+}
+
+pub async fn register(app: &impl TestApp, name: &str, email: &str) -> ServiceResponse<BoxBody> {
+    let req = test::TestRequest::post()
+        .uri("/api/auth/register")
+        .set_json(json!({
+            "name": name,
+            "email": email,
+            "password": PASSWORD,
+            "confirmPassword": PASSWORD,
+        }))
+        .to_request();
+    test::call_service(app, req).await
+}
+
+pub async fn login(app: &impl TestApp, email: &str, password: &str) -> ServiceResponse<BoxBody> {
+    let req = test::TestRequest::post()
+        .uri("/api/auth/login")
+        .set_json(json!({ "email": email, "password": password }))
+        .to_request();
+    test::call_service(app, req).await
+}
+
+/// Registers a user and logs them in, returning the user's JSON and token.
+pub async fn sign_up(app: &impl TestApp, name: &str, email: &str) -> (Value, String) {
+    let res = register(app, name, email).await;
+    assert_eq!(res.status(), 201);
+    let body: Value = test::read_body_json(res).await;
+
+    (body["data"]["user"].clone(), token(app, email).await)
+}
+
+/// Creates an admin directly in the repository, since the API has no way to
+/// make the first one, and logs them in.
+pub async fn sign_up_admin(app_state: &AppState, app: &impl TestApp) -> (User, String) {
+    let hashed = password::hash(PASSWORD, &app_state.env.password).unwrap();
+    let admin = app_state
+        .db_client
+        .save_admin_user("admin", "admin@example.com", &hashed)
+        .await
+        .unwrap();
+
+    (admin, token(app, "admin@example.com").await)
+}
+
+async fn token(app: &impl TestApp, email: &str) -> String {
+    let res = login(app, email, PASSWORD).await;
+    assert_eq!(res.status(), 200);
+    let body: Value = test::read_body_json(res).await;
+    body["token"].as_str().unwrap().to_string()
+}
+
+/// A small PNG, for photo uploads.
+pub fn png() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image::RgbImage::new(40, 20)
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+        .unwrap();
+    bytes
+}
+
+/// A `multipart/form-data` body with a file part per `(name, content type,
+/// bytes)`, and the matching `Content-Type`.
+pub fn multipart(parts: &[(&str, &str, &[u8])]) -> (String, Vec<u8>) {
+    let boundary = "crud-rust-test-boundary";
+    let mut body = Vec::new();
+    for (name, content_type, bytes) in parts {
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"{name}\"\r\nContent-Type: {content_type}\r\n\r\n"
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
@@ -0,0 +1,52 @@
+use std::{collections::HashSet, sync::Arc};
+
+use actix_web::test;
+
+use super::with_pool;
+use crate::{
+    db::DbClient,
+    tests::{app_state, bearer, init, sign_up_admin},
+};
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn audit_export_pages_through_events_sharing_a_timestamp() {
+    with_pool(|pool| async move {
+        let mut app_state = app_state();
+        app_state.db_client = Arc::new(DbClient::new(pool.clone()));
+        let app = init(&app_state).await;
+        let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+
+        // One statement, so every row gets the same `now()`.
+        sqlx::query(
+            "INSERT INTO audit_events (action, user_agent)
+            SELECT 'login.failed', 'agent ' || n FROM generate_series(1, 2500) AS n",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO audit_events (action, created_at)
+            VALUES ('login.failed', now() + interval '1 hour')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/audit/export?action=login.failed")
+            .insert_header(bearer(&admin_token))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let ids: HashSet<_> = body
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').next().unwrap())
+            .collect();
+        assert_eq!(ids.len(), 2500);
+        assert_eq!(body.lines().count(), 2501);
+    })
+    .await
+}
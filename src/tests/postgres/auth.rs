@@ -0,0 +1,231 @@
+use actix_web::{cookie::Cookie, test};
+use serde_json::{json, Value};
+
+use super::{with_database, with_database_config};
+use crate::tests::{bearer, init, login, register, sign_up, status, PASSWORD};
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn register_stores_normalized_email() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+
+        let res = register(&app, "bob", " Bob@EXAMPLE.com").await;
+        assert_eq!(res.status(), 201);
+
+        let user = app_state
+            .db_client
+            .get_user(None, None, Some("bob@example.com"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.email, "Bob@example.com");
+        assert_ne!(user.password, PASSWORD);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn register_returns_409_for_duplicate_email() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+
+        assert_eq!(register(&app, "bob", "bob@example.com").await.status(), 201);
+        assert_eq!(register(&app, "bob", "bob@example.com").await.status(), 409);
+
+        let res = register(&app, "other bob", "BOB@Example.COM").await;
+        assert_eq!(res.status(), 409);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["status"], "fail");
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn register_rejects_invalid_input() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/register")
+            .set_json(json!({
+                "name": "",
+                "email": "bob@example.com",
+                "password": PASSWORD,
+                "confirmPassword": PASSWORD,
+            }))
+            .to_request();
+        assert_eq!(status(&app, req).await, 400);
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/register")
+            .set_json(json!({
+                "name": "bob",
+                "email": "bob@example.com",
+                "password": "short",
+                "confirmPassword": "short",
+            }))
+            .to_request();
+        assert_eq!(status(&app, req).await, 400);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn login_issues_token_in_body_and_cookie() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        register(&app, "bob", "bob@example.com").await;
+
+        let res = login(&app, "bob@example.com", PASSWORD).await;
+        assert_eq!(res.status(), 200);
+        let cookie = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == "token")
+            .map(|c| c.value().to_string())
+            .unwrap();
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["token"], cookie);
+
+        assert_eq!(login(&app, "bob@example.com", "wrong").await.status(), 401);
+        assert_eq!(
+            login(&app, "alice@example.com", PASSWORD).await.status(),
+            401
+        );
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn cookie_and_header_auth_are_both_accepted() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+        for req in [
+            test::TestRequest::get()
+                .uri("/api/auth/session")
+                .insert_header(bearer(&token)),
+            test::TestRequest::get()
+                .uri("/api/auth/session")
+                .cookie(Cookie::new("token", token.clone())),
+        ] {
+            let body: Value = test::call_and_read_body_json(&app, req.to_request()).await;
+            assert_eq!(body["authenticated"], true);
+            assert_eq!(body["user"]["id"], user["id"]);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/users/me")
+            .cookie(Cookie::new("token", "garbage"))
+            .to_request();
+        assert_eq!(status(&app, req).await, 401);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn header_wins_over_cookie() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (bob, bob_token) = sign_up(&app, "bob", "bob@example.com").await;
+        let (_, carol_token) = sign_up(&app, "carol", "carol@example.com").await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/users/me")
+            .insert_header(bearer(&bob_token))
+            .cookie(Cookie::new("token", carol_token))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["user"]["id"], bob["id"]);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn cookie_auth_needs_csrf_header_for_unsafe_methods() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/logout")
+            .cookie(Cookie::new("token", token.clone()))
+            .to_request();
+        assert_eq!(status(&app, req).await, 403);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/api/auth/csrf").to_request(),
+        )
+        .await;
+        let csrf_cookie = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == "csrf_token")
+            .map(|c| c.value().to_string())
+            .unwrap();
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["token"], csrf_cookie);
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/logout")
+            .cookie(Cookie::new("token", token.clone()))
+            .cookie(Cookie::new("csrf_token", csrf_cookie.clone()))
+            .insert_header(("x-csrf-token", "not-the-cookie"))
+            .to_request();
+        assert_eq!(status(&app, req).await, 403);
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/logout")
+            .cookie(Cookie::new("token", token.clone()))
+            .cookie(Cookie::new("csrf_token", csrf_cookie.clone()))
+            .insert_header(("x-csrf-token", csrf_cookie))
+            .to_request();
+        assert_eq!(status(&app, req).await, 200);
+
+        // Bearer tokens are not sent by browsers on their own, so they need
+        // no CSRF token.
+        let req = test::TestRequest::post()
+            .uri("/api/auth/logout")
+            .insert_header(bearer(&token))
+            .to_request();
+        assert_eq!(status(&app, req).await, 200);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn cookie_is_ignored_when_cookie_auth_is_disabled() {
+    with_database_config(
+        |config| config.cookie.enabled = false,
+        |app_state| async move {
+            let app = init(&app_state).await;
+            let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+            let res = login(&app, "bob@example.com", PASSWORD).await;
+            assert_eq!(res.response().cookies().count(), 0);
+
+            let req = test::TestRequest::get()
+                .uri("/api/users/me")
+                .cookie(Cookie::new("token", token.clone()))
+                .to_request();
+            assert_eq!(status(&app, req).await, 401);
+
+            let req = test::TestRequest::get()
+                .uri("/api/users/me")
+                .insert_header(bearer(&token))
+                .to_request();
+            assert_eq!(status(&app, req).await, 200);
+        },
+    )
+    .await
+}
@@ -0,0 +1,266 @@
+use std::sync::Arc;
+
+use actix_web::test;
+use serde_json::Value;
+
+use super::with_pool;
+use crate::{
+    db::DbClient,
+    migrations,
+    tests::{app_state, init},
+};
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn readyz_fails_while_migrations_are_pending() {
+    with_pool(|pool| async move {
+        let mut app_state = app_state();
+        app_state.db_client = Arc::new(DbClient::new(pool.clone()));
+        let app = init(&app_state).await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["pool"]["details"]["max"], 5);
+
+        let latest = migrations::MIGRATOR.iter().last().unwrap().version;
+        sqlx::query("DELETE FROM _sqlx_migrations WHERE version = $1")
+            .bind(latest)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(
+            body["checks"]["migrations"]["details"]["pending"][0],
+            latest
+        );
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn migrating_again_concurrently_is_harmless() {
+    with_pool(|pool| async move {
+        let (a, b) = futures_util::join!(migrations::run(&pool), migrations::run(&pool));
+        a.unwrap();
+        b.unwrap();
+
+        let status = migrations::status(&pool).await.unwrap();
+        assert!(status.is_current());
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn migrating_leaves_no_lock_or_lifted_timeout_in_the_pool() {
+    with_pool(|pool| async move {
+        migrations::run(&pool).await.unwrap();
+
+        let locks: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM pg_locks l JOIN pg_database d ON d.oid = l.database
+            WHERE l.locktype = 'advisory' AND d.datname = current_database()",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(locks, 0);
+
+        let mut conns = Vec::new();
+        for _ in 0..pool.options().get_max_connections() {
+            conns.push(pool.acquire().await.unwrap());
+        }
+        for conn in &mut conns {
+            let timeout: String = sqlx::query_scalar("SHOW statement_timeout")
+                .fetch_one(&mut **conn)
+                .await
+                .unwrap();
+            assert_ne!(timeout, "0");
+        }
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn readyz_fails_when_the_pool_is_exhausted() {
+    with_pool(|pool| async move {
+        let mut app_state = app_state();
+        app_state.env.health.timeout_ms = 200;
+        app_state.db_client = Arc::new(DbClient::new(pool.clone()));
+        let app = init(&app_state).await;
+
+        let mut held = Vec::new();
+        for _ in 0..pool.options().get_max_connections() {
+            held.push(pool.acquire().await.unwrap());
+        }
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["checks"]["pool"]["status"], "degraded");
+        assert_eq!(body["checks"]["database"]["status"], "down");
+
+        drop(held);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn readyz_stays_ready_when_the_pool_is_saturated() {
+    with_pool(|pool| async move {
+        let mut app_state = app_state();
+        app_state.env.health.pool_saturation_threshold = 0.5;
+        app_state.db_client = Arc::new(DbClient::new(pool.clone()));
+        let app = init(&app_state).await;
+
+        let mut held = Vec::new();
+        for _ in 0..pool.options().get_max_connections() - 1 {
+            held.push(pool.acquire().await.unwrap());
+        }
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["pool"]["status"], "degraded");
+        assert_eq!(
+            body["checks"]["pool"]["error"],
+            "connection pool is saturated"
+        );
+        assert_eq!(body["checks"]["database"]["status"], "up");
+
+        drop(held);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn case_only_duplicate_emails_are_reported_instead_of_failing_migration() {
+    with_pool(|pool| async move {
+        // Go back to before emails were made case-insensitive.
+        migrations::MIGRATOR
+            .undo(&pool, 20250116090000)
+            .await
+            .unwrap();
+
+        for (email, created_at) in [
+            ("bob@example.com", "2024-01-01T00:00:00Z"),
+            ("Bob@Example.com", "2024-02-01T00:00:00Z"),
+            ("alice@example.com", "2024-01-01T00:00:00Z"),
+        ] {
+            sqlx::query(
+                "INSERT INTO users (name, email, password, created_at) VALUES ('x', $1, 'x', $2::timestamptz)",
+            )
+            .bind(email)
+            .bind(created_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        migrations::run(&pool).await.unwrap();
+        assert!(migrations::status(&pool).await.unwrap().is_current());
+
+        let conflicts: Vec<(String, String)> = sqlx::query_as(
+            "SELECT email, reason FROM user_email_conflicts ORDER BY email",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            conflicts,
+            [
+                ("Bob@Example.com".to_string(), "case_duplicate".to_string()),
+                ("bob@example.com".to_string(), "case_duplicate".to_string()),
+            ]
+        );
+
+        // The oldest account keeps the address; the other is renamed, so a
+        // login by email finds one account.
+        let emails: Vec<String> = sqlx::query_scalar(
+            "SELECT email FROM users WHERE lower(email) = 'bob@example.com' OR email LIKE 'conflict-%'",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(emails.len(), 2);
+        assert!(emails.contains(&"bob@example.com".to_string()));
+        assert!(emails
+            .iter()
+            .any(|email| email.starts_with("conflict-") && email.ends_with("@invalid")));
+
+        // Every address is unique whatever its case, through one index.
+        let constraints: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM pg_constraint WHERE conname = 'users_email_key'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(constraints, 0);
+        let duplicate = sqlx::query(
+            "INSERT INTO users (name, email, password) VALUES ('x', 'BOB@example.com', 'x')",
+        )
+        .execute(&pool)
+        .await;
+        assert!(duplicate.is_err());
+        let duplicate = sqlx::query(
+            "INSERT INTO users (name, email, password) VALUES ('x', 'ALICE@example.com', 'x')",
+        )
+        .execute(&pool)
+        .await;
+        assert!(duplicate.is_err());
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn readyz_does_not_expose_database_errors() {
+    with_pool(|pool| async move {
+        let mut app_state = app_state();
+        app_state.db_client = Arc::new(DbClient::new(pool.clone()));
+        let app = init(&app_state).await;
+        pool.close().await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(
+            body["checks"]["database"]["error"],
+            "database is unreachable"
+        );
+        assert_eq!(
+            body["checks"]["migrations"]["error"],
+            "migration status is unavailable"
+        );
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn metrics_include_pool_gauges() {
+    with_pool(|pool| async move {
+        let mut app_state = app_state();
+        app_state.db_client = Arc::new(DbClient::new(pool));
+        let app = init(&app_state).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        assert!(body.contains("# TYPE db_pool_connections gauge"));
+        assert!(body.contains("\ndb_pool_max_connections 5\n"));
+    })
+    .await
+}
@@ -0,0 +1,104 @@
+//! Integration tests against a real Postgres. Each test gets a database of its
+//! own, created next to the one `DATABASE_URL` points at and migrated with
+//! `sqlx::migrate!()`, then dropped again. They are `#[ignore]`d, so plain
+//! `cargo test` works without a server; run them with
+//! `cargo test -- --ignored` and `DATABASE_URL` set.
+
+mod audit;
+mod auth;
+mod health;
+mod pool;
+mod replica;
+mod telemetry;
+mod transaction;
+mod users;
+
+use std::{future::Future, panic::AssertUnwindSafe, str::FromStr, sync::Arc};
+
+use futures_util::FutureExt;
+use sqlx::{postgres::PgConnectOptions, PgPool};
+use uuid::Uuid;
+
+use crate::{
+    db::DbClient,
+    pool::{connect_options, pool_options},
+    AppState,
+};
+
+/// Runs `test` with an app state backed by a freshly migrated database.
+pub async fn with_database<F, Fut>(test: F)
+where
+    F: FnOnce(AppState) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    with_database_config(|_| {}, test).await
+}
+
+/// Like [`with_database`], with a chance to adjust the configuration first.
+pub async fn with_database_config<C, F, Fut>(configure: C, test: F)
+where
+    C: FnOnce(&mut crate::config::Config),
+    F: FnOnce(AppState) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    with_pool(|pool| {
+        let mut app_state = super::app_state();
+        configure(&mut app_state.env);
+        app_state.db_client = Arc::new(DbClient::new(pool));
+        test(app_state)
+    })
+    .await
+}
+
+/// The server the Postgres tests run against.
+pub fn database_url() -> String {
+    std::env::var("DATABASE_URL").expect("DATABASE_URL must be set to run the Postgres tests")
+}
+
+/// Runs `test` with a pool on a freshly migrated database.
+pub async fn with_pool<F, Fut>(test: F)
+where
+    F: FnOnce(PgPool) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let url = database_url();
+
+    let admin = PgPool::connect(&url)
+        .await
+        .expect("connect to DATABASE_URL");
+    let name = format!("crud_rust_test_{}", Uuid::new_v4().simple());
+    sqlx::query(&format!(r#"CREATE DATABASE "{}""#, name))
+        .execute(&admin)
+        .await
+        .expect("create test database");
+
+    let options = PgConnectOptions::from_str(&url)
+        .expect("parse DATABASE_URL")
+        .database(&name);
+    let config = super::config().database;
+    let pool = pool_options(&config)
+        .max_connections(5)
+        .connect_with(connect_options(options, &config))
+        .await
+        .expect("connect to test database");
+    sqlx::migrate!()
+        .run(&pool)
+        .await
+        .expect("migrate test database");
+
+    // Catch a failing assertion so the database is dropped either way.
+    let result = AssertUnwindSafe(test(pool.clone())).catch_unwind().await;
+
+    pool.close().await;
+    if let Err(e) = sqlx::query(&format!(r#"DROP DATABASE "{}" WITH (FORCE)"#, name))
+        .execute(&admin)
+        .await
+    {
+        eprintln!("Failed to drop test database {}: {}", name, e);
+    }
+    admin.close().await;
+
+    if let Err(panic) = result {
+        std::panic::resume_unwind(panic);
+    }
+}
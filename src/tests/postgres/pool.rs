@@ -0,0 +1,49 @@
+use std::{str::FromStr, time::Instant};
+
+use sqlx::postgres::PgConnectOptions;
+
+use super::{database_url, with_pool};
+use crate::{pool, tests::config};
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn statement_timeout_applies_to_pooled_connections() {
+    with_pool(|pool| async move {
+        let timeout: String = sqlx::query_scalar("SHOW statement_timeout")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(timeout, "30s");
+
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query("SET statement_timeout = 50")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let err = sqlx::query("SELECT pg_sleep(1)")
+            .execute(&mut *conn)
+            .await
+            .unwrap_err();
+        let code = err.as_database_error().and_then(|e| e.code());
+        assert_eq!(code.as_deref(), Some("57014"));
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn connect_does_not_retry_permanent_errors() {
+    let url = database_url();
+
+    let mut config = config().database;
+    config.connect_attempts = 5;
+    config.connect_backoff_ms = 10_000;
+    let options = PgConnectOptions::from_str(&url)
+        .unwrap()
+        .database("crud_rust_test_does_not_exist");
+
+    let started = Instant::now();
+    let err = pool::connect(&config, options).await.unwrap_err();
+    assert!(err.as_database_error().is_some(), "{err}");
+    assert!(started.elapsed().as_secs() < 5);
+}
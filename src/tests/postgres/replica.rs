@@ -0,0 +1,227 @@
+use std::{sync::Arc, time::Duration};
+
+use actix_web::test;
+use serde_json::{json, Value};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+use super::with_pool;
+use crate::{
+    db::{replica::Replicas, DbClient, UserExt},
+    tests::{app_state, bearer, init, sign_up, sign_up_admin, status, TestApp},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Copies `user_id` from `primary` to `replica` under another name, as a
+/// replica that has not caught up with a rename would have it.
+async fn insert_stale_copy(primary: &PgPool, replica: &PgPool, user_id: uuid::Uuid) {
+    let (email, password): (String, String) =
+        sqlx::query_as("SELECT email, password FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(primary)
+            .await
+            .unwrap();
+    sqlx::query("INSERT INTO users (id, name, email, password) VALUES ($1, 'stale', $2, $3)")
+        .bind(user_id)
+        .bind(email)
+        .bind(password)
+        .execute(replica)
+        .await
+        .unwrap();
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn reads_go_to_the_replica_unless_the_user_was_just_written() {
+    with_pool(|primary| async move {
+        with_pool(|replica| async move {
+            let replicas = Replicas::new(vec![replica.clone()], WINDOW, WINDOW);
+            let db = DbClient::new(primary.clone()).with_replicas(replicas);
+            let alice = db
+                .save_user("alice", "alice@example.com", "hash")
+                .await
+                .unwrap();
+            insert_stale_copy(&primary, &replica, alice.id).await;
+
+            // Just written, so read from the primary however it is looked up.
+            let user = db.get_user(Some(alice.id), None, None).await.unwrap();
+            assert_eq!(user.unwrap().name, "alice");
+            let user = db
+                .get_user(None, None, Some("alice@example.com"))
+                .await
+                .unwrap();
+            assert_eq!(user.unwrap().name, "alice");
+
+            // Outside the window the replica answers, stale or not.
+            let replicas = Replicas::new(vec![replica.clone()], Duration::ZERO, WINDOW);
+            let db = DbClient::new(primary.clone()).with_replicas(replicas);
+            let user = db.get_user(Some(alice.id), None, None).await.unwrap();
+            assert_eq!(user.unwrap().name, "stale");
+            let users = db.get_users(1, 10).await.unwrap();
+            assert_eq!(users.len(), 1);
+            assert_eq!(users[0].name, "stale");
+
+            // A user the replica has not seen yet is still found.
+            let bob = db
+                .save_user("bob", "bob@example.com", "hash")
+                .await
+                .unwrap();
+            let user = db
+                .get_user(None, None, Some("bob@example.com"))
+                .await
+                .unwrap();
+            assert_eq!(user.unwrap().id, bob.id);
+        })
+        .await
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn reads_fall_back_to_the_primary_when_the_replica_fails() {
+    with_pool(|primary| async move {
+        let replica = PgPoolOptions::new().connect_lazy_with((*primary.connect_options()).clone());
+        replica.close().await;
+
+        let replicas = Replicas::new(vec![replica], WINDOW, WINDOW);
+        let db = DbClient::new(primary).with_replicas(replicas.clone());
+        let alice = db
+            .save_user("alice", "alice@example.com", "hash")
+            .await
+            .unwrap();
+
+        let users = db.get_users(1, 10).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(replicas.pick().is_none(), "the replica is out of rotation");
+
+        let user = db
+            .get_user(None, None, Some("alice@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(user.unwrap().id, alice.id);
+    })
+    .await
+}
+
+/// `/api/users/me` answers with the user that authenticated the request.
+async fn me(app: &impl TestApp, token: &str) -> Value {
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(bearer(token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(app, req).await;
+    body["data"]["user"]["name"].clone()
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn authentication_reads_a_replica_unless_it_is_unhealthy() {
+    with_pool(|primary| async move {
+        with_pool(|replica| async move {
+            let instance = |replicas: Replicas| {
+                let mut app_state = app_state();
+                app_state.db_client =
+                    Arc::new(DbClient::new(primary.clone()).with_replicas(replicas));
+                app_state
+            };
+            let writer = instance(Replicas::new(vec![replica.clone()], WINDOW, WINDOW));
+            let app = init(&writer).await;
+            let (alice, token) = sign_up(&app, "alice", "alice@example.com").await;
+            let alice_id = alice["id"].as_str().unwrap().parse().unwrap();
+            insert_stale_copy(&primary, &replica, alice_id).await;
+
+            // The instance that wrote her reads her from the primary.
+            assert_eq!(me(&app, &token).await, "alice");
+
+            // Another instance does not know about the write and asks the
+            // replica, until the replica is taken out of rotation.
+            let replicas = Replicas::new(vec![replica.clone()], WINDOW, WINDOW);
+            let other = instance(replicas.clone());
+            let app = init(&other).await;
+            assert_eq!(me(&app, &token).await, "stale");
+
+            replicas.mark_unhealthy(replicas.pick().unwrap(), &sqlx::Error::PoolTimedOut);
+            assert_eq!(me(&app, &token).await, "alice");
+        })
+        .await
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn readyz_and_metrics_report_each_replica() {
+    with_pool(|primary| async move {
+        let down = PgPoolOptions::new().connect_lazy_with((*primary.connect_options()).clone());
+        down.close().await;
+        let replicas = Replicas::new(vec![primary.clone(), down], WINDOW, WINDOW);
+        let mut app_state = app_state();
+        app_state.db_client = Arc::new(DbClient::new(primary.clone()).with_replicas(replicas));
+        let app = init(&app_state).await;
+
+        // Reads fall back to the primary, so a replica being down does not
+        // make the instance unready.
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = test::read_body_json(res).await;
+        assert_eq!(body["status"], "ready");
+        let check = &body["checks"]["replicas"];
+        assert_eq!(check["status"], "degraded");
+        assert_eq!(check["error"], "1 of 2 read replicas are unreachable");
+        assert_eq!(check["details"][0]["reachable"], true);
+        assert_eq!(check["details"][0]["inRotation"], true);
+        assert_eq!(check["details"][0]["max"], 5);
+        assert_eq!(check["details"][1]["reachable"], false);
+        assert_eq!(check["details"][1]["inRotation"], false);
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("db_replica_pool_max_connections{replica=\"0\"} 5"));
+        assert!(body.contains("db_replica_in_rotation{replica=\"0\"} 1"));
+        assert!(body.contains("db_replica_in_rotation{replica=\"1\"} 0"));
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn role_change_audits_the_role_on_the_primary() {
+    with_pool(|primary| async move {
+        with_pool(|replica| async move {
+            let replicas = Replicas::new(vec![replica.clone()], Duration::ZERO, WINDOW);
+            let mut app_state = app_state();
+            app_state.db_client = Arc::new(DbClient::new(primary.clone()).with_replicas(replicas));
+            let app = init(&app_state).await;
+            let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+
+            let (bob, _) = sign_up(&app, "bob", "bob@example.com").await;
+            let bob_id = bob["id"].as_str().unwrap();
+            insert_stale_copy(&primary, &replica, bob_id.parse().unwrap()).await;
+            sqlx::query("UPDATE users SET role = 'moderator' WHERE id = $1::uuid")
+                .bind(bob_id)
+                .execute(&primary)
+                .await
+                .unwrap();
+
+            let req = test::TestRequest::patch()
+                .uri(&format!("/api/users/{}/role", bob_id))
+                .insert_header(bearer(&admin_token))
+                .set_json(json!({ "role": "admin" }))
+                .to_request();
+            assert_eq!(status(&app, req).await, 200);
+
+            let req = test::TestRequest::get()
+                .uri("/api/admin/audit?action=user.role_changed")
+                .insert_header(bearer(&admin_token))
+                .to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body["data"][0]["metadata"]["from"], "moderator");
+            assert_eq!(body["data"][0]["metadata"]["to"], "admin");
+        })
+        .await
+    })
+    .await
+}
@@ -0,0 +1,57 @@
+use actix_web::test;
+use opentelemetry::{trace::SpanKind, Value};
+use serde_json::json;
+
+use super::with_database;
+use crate::tests::{
+    init,
+    telemetry::{attribute, traced, traceparent},
+    PASSWORD,
+};
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn queries_are_exported_as_child_spans() {
+    with_database(|app_state| async move {
+        let traced = traced(0.0);
+        let app = init(&app_state).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/register")
+            .insert_header(traceparent(true))
+            .set_json(json!({
+                "name": "Traced",
+                "email": "traced@example.com",
+                "password": PASSWORD,
+                "confirmPassword": PASSWORD,
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 201);
+
+        let spans = traced.spans();
+        let server = spans
+            .iter()
+            .find(|span| span.span_kind == SpanKind::Server)
+            .expect("request span");
+        let queries: Vec<_> = spans
+            .iter()
+            .filter(|span| span.span_kind == SpanKind::Client)
+            .collect();
+        assert!(!queries.is_empty());
+        for query in &queries {
+            assert_eq!(
+                query.span_context.trace_id(),
+                server.span_context.trace_id()
+            );
+            assert_eq!(query.parent_span_id, server.span_context.span_id());
+            assert_eq!(
+                attribute(query, "db.system"),
+                Some(Value::from("postgresql"))
+            );
+            assert!(query.start_time >= server.start_time && query.end_time <= server.end_time);
+        }
+        assert!(queries.iter().any(|query| attribute(query, "db.statement")
+            .is_some_and(|statement| statement.as_str().starts_with("INSERT INTO users"))));
+    })
+    .await;
+}
@@ -0,0 +1,144 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde_json::json;
+use sqlx::PgPool;
+
+use super::with_pool;
+use crate::db::{
+    self, AuditExt, AuditFilter, DbClient, Isolation, NewAuditEvent, TransactionExt, UserExt,
+};
+
+fn registered(user_id: uuid::Uuid) -> NewAuditEvent<'static> {
+    NewAuditEvent {
+        action: "registered",
+        actor_id: Some(user_id),
+        target_id: Some(user_id),
+        ip_address: None,
+        user_agent: None,
+        metadata: json!({}),
+    }
+}
+
+async fn user_count(db: &DbClient) -> usize {
+    db.get_users(1, 100).await.unwrap().len()
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn transaction_commits_or_rolls_back_every_write() {
+    with_pool(|pool| async move {
+        let db = DbClient::new(pool);
+
+        let user = db::transaction(&db, Isolation::default(), |tx| async move {
+            let user = tx.save_user("alice", "alice@example.com", "hash").await?;
+            tx.save_audit_event(registered(user.id)).await?;
+            Ok(user)
+        })
+        .await
+        .unwrap();
+        let filter = AuditFilter {
+            target_id: Some(user.id),
+            ..Default::default()
+        };
+        assert_eq!(db.count_audit_events(&filter).await.unwrap(), 1);
+
+        let err = db::transaction(&db, Isolation::default(), |tx| async move {
+            let user = tx.save_user("bob", "bob@example.com", "hash").await?;
+            tx.save_audit_event(registered(user.id)).await?;
+            tx.save_user("bob again", "BOB@example.com", "hash").await
+        })
+        .await
+        .unwrap_err();
+        assert!(err.as_database_error().unwrap().is_unique_violation());
+        assert_eq!(user_count(&db).await, 1);
+        assert_eq!(
+            db.count_audit_events(&AuditFilter::default())
+                .await
+                .unwrap(),
+            1
+        );
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn serialization_failures_are_retried() {
+    with_pool(|pool| async move {
+        let db = DbClient::new(pool);
+        let attempts = &AtomicU32::new(0);
+        let pool = &db.pool;
+
+        db::transaction(&db, Isolation::Serializable, |tx| async move {
+            tx.save_user("alice", "alice@example.com", "hash").await?;
+            // The first attempt's user must be rolled back, or the second
+            // would hit the unique email.
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(serialization_failure(pool).await);
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(user_count(&db).await, 1);
+    })
+    .await
+}
+
+/// A genuine `serialization_failure` from Postgres.
+async fn serialization_failure(pool: &PgPool) -> sqlx::Error {
+    sqlx::query("DO $$ BEGIN RAISE EXCEPTION 'conflict' USING ERRCODE = '40001'; END $$")
+        .execute(pool)
+        .await
+        .unwrap_err()
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn other_errors_are_not_retried() {
+    with_pool(|pool| async move {
+        let db = DbClient::new(pool);
+        db.save_user("alice", "alice@example.com", "hash")
+            .await
+            .unwrap();
+        let attempts = &AtomicU32::new(0);
+
+        let err = db::transaction(&db, Isolation::Serializable, |tx| async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            tx.save_user("alice", "alice@example.com", "hash").await
+        })
+        .await
+        .unwrap_err();
+
+        assert!(err.as_database_error().unwrap().is_unique_violation());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn nested_transactions_join_the_outer_one() {
+    with_pool(|pool| async move {
+        let db = DbClient::new(pool);
+
+        let outer = db.begin(Isolation::default()).await.unwrap();
+        let user = db::transaction(outer.as_ref(), Isolation::default(), |tx| async move {
+            tx.save_user("alice", "alice@example.com", "hash").await
+        })
+        .await
+        .unwrap();
+
+        // Committing the inner transaction left the outer one open.
+        let seen = outer.get_user(Some(user.id), None, None).await.unwrap();
+        assert!(seen.is_some());
+        assert_eq!(user_count(&db).await, 0);
+
+        outer.rollback().await.unwrap();
+        assert_eq!(user_count(&db).await, 0);
+        assert!(outer.get_users(1, 10).await.is_err());
+    })
+    .await
+}
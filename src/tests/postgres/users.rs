@@ -0,0 +1,476 @@
+use std::sync::Arc;
+
+use actix_web::{http::header, test};
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use super::{with_database, with_database_config, with_pool};
+use crate::{
+    db::DbClient,
+    models::DataExportStatus,
+    storage::LocalStorage,
+    tests::{
+        app_state, bearer, init, login, multipart, png, sign_up, sign_up_admin, status, TestApp,
+        PASSWORD,
+    },
+};
+
+async fn get(app: &impl TestApp, uri: &str, token: &str) -> Value {
+    let req = test::TestRequest::get()
+        .uri(uri)
+        .insert_header(bearer(token))
+        .to_request();
+    test::call_and_read_body_json(app, req).await
+}
+
+async fn set_role(app: &impl TestApp, admin_token: &str, user_id: &str, role: &str) {
+    let req = test::TestRequest::patch()
+        .uri(&format!("/api/users/{}/role", user_id))
+        .insert_header(bearer(admin_token))
+        .set_json(json!({ "role": role }))
+        .to_request();
+    assert_eq!(status(app, req).await, 200);
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn admin_routes_are_gated_by_role() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+        let (user, user_token) = sign_up(&app, "bob", "bob@example.com").await;
+        let (moderator, moderator_token) = sign_up(&app, "carol", "carol@example.com").await;
+        let moderator_id = moderator["id"].as_str().unwrap();
+        set_role(&app, &admin_token, moderator_id, "moderator").await;
+
+        let user_uri = format!("/api/users/{}", user["id"].as_str().unwrap());
+        let role_uri = format!("{}/role", user_uri);
+        let requests = || {
+            [
+                test::TestRequest::get().uri("/api/users"),
+                test::TestRequest::get().uri(&user_uri),
+                test::TestRequest::patch()
+                    .uri(&role_uri)
+                    .set_json(json!({ "role": "user" })),
+                test::TestRequest::delete().uri(&user_uri),
+            ]
+        };
+
+        for req in requests() {
+            assert_eq!(status(&app, req.to_request()).await, 401);
+        }
+        for token in [&user_token, &moderator_token] {
+            for req in requests() {
+                let req = req.insert_header(bearer(token)).to_request();
+                assert_eq!(status(&app, req).await, 403);
+            }
+        }
+
+        // Roles are read on every request, so a promotion applies to tokens
+        // that were issued before it.
+        set_role(&app, &admin_token, moderator_id, "admin").await;
+        let req = test::TestRequest::get()
+            .uri("/api/users")
+            .insert_header(bearer(&moderator_token))
+            .to_request();
+        assert_eq!(status(&app, req).await, 200);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn self_service_routes_are_open_to_every_role() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (admin, admin_token) = sign_up_admin(&app_state, &app).await;
+        let (_, user_token) = sign_up(&app, "bob", "bob@example.com").await;
+        let (moderator, moderator_token) = sign_up(&app, "carol", "carol@example.com").await;
+        set_role(
+            &app,
+            &admin_token,
+            moderator["id"].as_str().unwrap(),
+            "moderator",
+        )
+        .await;
+
+        for token in [&user_token, &moderator_token, &admin_token] {
+            for uri in ["/api/users/me", "/api/users/me/api-keys"] {
+                let req = test::TestRequest::get()
+                    .uri(uri)
+                    .insert_header(bearer(token))
+                    .to_request();
+                assert_eq!(status(&app, req).await, 200, "{}", uri);
+            }
+        }
+
+        let me = get(&app, "/api/users/me", &admin_token).await;
+        assert_eq!(me["data"]["user"]["id"], admin.id.to_string());
+        assert_eq!(me["data"]["user"]["role"], "admin");
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn listing_users_handles_pagination_edges() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+        let (newest, _) = sign_up(&app, "carol", "carol@example.com").await;
+
+        let all = get(&app, "/api/users", &admin_token).await;
+        assert_eq!(all["result"], 2);
+
+        let first = get(&app, "/api/users?page=1&limit=1", &admin_token).await;
+        assert_eq!(first["result"], 1);
+        assert_eq!(first["data"][0]["id"], newest["id"]);
+
+        let second = get(&app, "/api/users?page=2&limit=1", &admin_token).await;
+        assert_eq!(second["data"][0]["email"], "admin@example.com");
+
+        let past_end = get(&app, "/api/users?page=99&limit=100", &admin_token).await;
+        assert_eq!(past_end["result"], 0);
+        assert_eq!(past_end["data"], json!([]));
+
+        for query in ["page=0", "limit=0", "limit=101", "page=-1", "page=abc"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/users?{}", query))
+                .insert_header(bearer(&admin_token))
+                .to_request();
+            assert_eq!(status(&app, req).await, 400, "{}", query);
+        }
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn get_user_by_id() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+        let (user, _) = sign_up(&app, "bob", "bob@example.com").await;
+
+        let uri = format!("/api/users/{}", user["id"].as_str().unwrap());
+        let body = get(&app, &uri, &admin_token).await;
+        assert_eq!(body["data"]["user"]["email"], "bob@example.com");
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/users/{}", Uuid::new_v4()))
+            .insert_header(bearer(&admin_token))
+            .to_request();
+        assert_eq!(status(&app, req).await, 404);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn change_password() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+        let change = |current: &str| {
+            json!({
+                "currentPassword": current,
+                "newPassword": "new-password",
+                "confirmNewPassword": "new-password",
+            })
+        };
+
+        let req = test::TestRequest::patch()
+            .uri("/api/users/me/password")
+            .insert_header(bearer(&token))
+            .set_json(change("wrong-password"))
+            .to_request();
+        assert_eq!(status(&app, req).await, 401);
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/users/me")
+                .insert_header(bearer(&token))
+                .to_request(),
+        )
+        .await;
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+        let req = test::TestRequest::patch()
+            .uri("/api/users/me/password")
+            .insert_header(bearer(&token))
+            .insert_header((header::IF_MATCH, etag))
+            .set_json(change(PASSWORD))
+            .to_request();
+        assert_eq!(status(&app, req).await, 200);
+
+        assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 401);
+        assert_eq!(
+            login(&app, "bob@example.com", "new-password")
+                .await
+                .status(),
+            200
+        );
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn role_change_and_delete_honour_if_match() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+        let (user, user_token) = sign_up(&app, "bob", "bob@example.com").await;
+        let uri = format!("/api/users/{}", user["id"].as_str().unwrap());
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&uri)
+                .insert_header(bearer(&admin_token))
+                .to_request(),
+        )
+        .await;
+        let stale = res.headers().get(header::ETAG).unwrap().clone();
+
+        set_role(
+            &app,
+            &admin_token,
+            user["id"].as_str().unwrap(),
+            "moderator",
+        )
+        .await;
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header(bearer(&admin_token))
+            .insert_header((header::IF_MATCH, stale))
+            .to_request();
+        assert_eq!(status(&app, req).await, 412);
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header(bearer(&admin_token))
+            .to_request();
+        assert_eq!(status(&app, req).await, 200);
+
+        let req = test::TestRequest::get()
+            .uri("/api/users/me")
+            .insert_header(bearer(&user_token))
+            .to_request();
+        assert_eq!(status(&app, req).await, 401);
+
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header(bearer(&admin_token))
+            .to_request();
+        assert_eq!(status(&app, req).await, 404);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn api_key_lifecycle() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/users/me/api-keys")
+            .insert_header(bearer(&token))
+            .set_json(json!({ "name": "deploy" }))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let key = body["key"].as_str().unwrap().to_string();
+        let key_id = body["data"]["id"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get()
+            .uri("/api/users/me")
+            .insert_header((header::AUTHORIZATION, format!("ApiKey {}", key)))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["data"]["user"]["id"], user["id"]);
+
+        let keys = get(&app, "/api/users/me/api-keys", &token).await;
+        assert_eq!(keys["result"], 1);
+        assert!(keys["data"][0]["lastUsedAt"].is_string());
+
+        let revoke = || {
+            test::TestRequest::delete()
+                .uri(&format!("/api/users/me/api-keys/{}", key_id))
+                .insert_header(bearer(&token))
+                .to_request()
+        };
+        assert_eq!(status(&app, revoke()).await, 200);
+        assert_eq!(status(&app, revoke()).await, 404);
+
+        let req = test::TestRequest::get()
+            .uri("/api/users/me")
+            .insert_header((header::AUTHORIZATION, format!("ApiKey {}", key)))
+            .to_request();
+        assert_eq!(status(&app, req).await, 401);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn upload_photo() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+        assert_eq!(user["photo"], "/uploads/default.png");
+
+        let (content_type, body) = multipart(&[("photo", "image/png", &png())]);
+        let req = test::TestRequest::post()
+            .uri("/api/users/me/photo")
+            .insert_header(bearer(&token))
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let photo = body["data"]["user"]["photo"].as_str().unwrap();
+        assert!(photo.starts_with("/uploads/avatars/"));
+
+        let key = photo.trim_start_matches("/uploads/");
+        assert!(app_state.storage.exists(key).await.unwrap());
+
+        let (content_type, body) = multipart(&[("photo", "image/png", b"not an image")]);
+        let req = test::TestRequest::post()
+            .uri("/api/users/me/photo")
+            .insert_header(bearer(&token))
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(status(&app, req).await, 400);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn failed_photo_update_deletes_the_upload() {
+    with_pool(|pool| async move {
+        let root = std::env::temp_dir().join(format!("crud-rust-{}", Uuid::new_v4()));
+        let mut app_state = app_state();
+        app_state.db_client = Arc::new(DbClient::new(pool.clone()));
+        app_state.storage = Arc::new(LocalStorage::new(&root, "/uploads"));
+        let app = init(&app_state).await;
+        let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+        sqlx::query(
+            "CREATE FUNCTION reject_photo() RETURNS trigger AS $$
+            BEGIN RAISE EXCEPTION 'photo updates are disabled'; END
+            $$ LANGUAGE plpgsql",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "CREATE TRIGGER reject_photo BEFORE UPDATE OF photo ON users
+            FOR EACH ROW EXECUTE FUNCTION reject_photo()",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let (content_type, body) = multipart(&[("photo", "image/png", &png())]);
+        let req = test::TestRequest::post()
+            .uri("/api/users/me/photo")
+            .insert_header(bearer(&token))
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(status(&app, req).await, 500);
+
+        let dir = root.join("avatars").join(user["id"].as_str().unwrap());
+        let uploads = std::fs::read_dir(&dir).map_or(0, |entries| entries.count());
+        assert_eq!(uploads, 0);
+    })
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn export_runs_in_background_for_large_accounts() {
+    with_database_config(
+        |config| config.export.async_threshold = 0,
+        |app_state| async move {
+            let app = init(&app_state).await;
+            let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+            let res = test::call_service(
+                &app,
+                test::TestRequest::get()
+                    .uri("/api/users/me/export")
+                    .insert_header(bearer(&token))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(res.status(), 202);
+            let location = res.headers().get(header::LOCATION).unwrap();
+            let location = location.to_str().unwrap().to_string();
+
+            let mut bundle = None;
+            for _ in 0..50 {
+                let res = test::call_service(
+                    &app,
+                    test::TestRequest::get()
+                        .uri(&location)
+                        .insert_header(bearer(&token))
+                        .to_request(),
+                )
+                .await;
+                if res.status() == 200 {
+                    bundle = Some(test::read_body_json::<Value, _>(res).await);
+                    break;
+                }
+                assert_eq!(res.status(), 202);
+                actix_web::rt::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            let bundle = bundle.expect("export did not finish");
+            assert_eq!(bundle["profile"]["id"], user["id"]);
+
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/users/me/export/{}", Uuid::new_v4()))
+                .insert_header(bearer(&token))
+                .to_request();
+            assert_eq!(status(&app, req).await, 404);
+        },
+    )
+    .await
+}
+
+#[actix_web::test]
+#[ignore = "needs DATABASE_URL"]
+async fn only_one_export_is_pending_per_user() {
+    with_database(|app_state| async move {
+        let app = init(&app_state).await;
+        let (user, _) = sign_up(&app, "bob", "bob@example.com").await;
+        let user_id: Uuid = user["id"].as_str().unwrap().parse().unwrap();
+        let db = &app_state.db_client;
+        let expires_at = Utc::now() + Duration::hours(1);
+
+        let started = futures_util::future::join_all(
+            (0..10).map(|_| db.start_data_export(user_id, expires_at)),
+        )
+        .await;
+        let started: Vec<_> = started.into_iter().filter_map(Result::unwrap).collect();
+        assert_eq!(started.len(), 1);
+
+        assert_eq!(db.fail_stale_data_exports(60).await.unwrap(), 0);
+        assert_eq!(db.fail_stale_data_exports(-60).await.unwrap(), 1);
+
+        let latest = db.get_latest_data_export(user_id).await.unwrap().unwrap();
+        assert_eq!(latest.status, DataExportStatus::Failed);
+        assert!(db
+            .start_data_export(user_id, expires_at)
+            .await
+            .unwrap()
+            .is_some());
+    })
+    .await
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+};
+
+use actix_web::test;
+use opentelemetry::{
+    trace::{SpanId, SpanKind, Status, Tracer as _, TracerProvider as _},
+    Value,
+};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+use tracing::subscriber::DefaultGuard;
+
+use super::{app_state, bearer, init, sign_up};
+use crate::{
+    config::TracingConfig,
+    logging,
+    telemetry::{self, TRACEPARENT},
+};
+
+pub const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+pub const PARENT_ID: &str = "00f067aa0ba902b7";
+
+/// Traces this thread into memory, sampling new traces at `sample_ratio`.
+/// Log lines are discarded.
+pub struct Traced {
+    provider: SdkTracerProvider,
+    exporter: InMemorySpanExporter,
+    _guard: DefaultGuard,
+}
+
+impl Traced {
+    /// Spans finished so far.
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.provider.force_flush().unwrap();
+        self.exporter.get_finished_spans().unwrap()
+    }
+}
+
+pub fn traced(sample_ratio: f64) -> Traced {
+    let exporter = InMemorySpanExporter::default();
+    let config = TracingConfig {
+        sample_ratio,
+        ..TracingConfig::default()
+    };
+    let provider = telemetry::provider(exporter.clone(), &config);
+    let subscriber = logging::subscriber("info", io::sink, Some(&provider)).unwrap();
+    Traced {
+        _guard: tracing::subscriber::set_default(subscriber),
+        provider,
+        exporter,
+    }
+}
+
+pub fn traceparent(sampled: bool) -> (&'static str, String) {
+    (
+        TRACEPARENT,
+        format!("00-{}-{}-0{}", TRACE_ID, PARENT_ID, sampled as u8),
+    )
+}
+
+pub fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+    span.attributes
+        .iter()
+        .find(|attribute| attribute.key.as_str() == key)
+        .map(|attribute| attribute.value.clone())
+}
+
+#[actix_web::test]
+async fn invalid_traceparent_starts_a_new_trace() {
+    let traced = traced(1.0);
+    let app = init(&app_state()).await;
+
+    for invalid in [
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        "00-xyz92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+    ] {
+        let req = test::TestRequest::get()
+            .uri("/livez")
+            .insert_header((TRACEPARENT, invalid))
+            .to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let spans = traced.spans();
+    assert_eq!(spans.len(), 5);
+    for span in spans {
+        assert_ne!(span.span_context.trace_id().to_string(), TRACE_ID);
+        assert_eq!(span.parent_span_id, SpanId::INVALID);
+    }
+}
+
+#[actix_web::test]
+async fn request_span_continues_the_callers_trace() {
+    let traced = traced(0.0);
+    let app = init(&app_state()).await;
+    let (user, token) = sign_up(&app, "Traced", "traced@example.com").await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(bearer(&token))
+        .insert_header(traceparent(true))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+    // Registering started a trace of its own, which a ratio of 0 drops.
+    let spans = traced.spans();
+    assert_eq!(spans.len(), 1);
+    let span = &spans[0];
+    assert_eq!(span.span_context.trace_id().to_string(), TRACE_ID);
+    assert_eq!(span.parent_span_id.to_string(), PARENT_ID);
+    assert!(span.parent_span_is_remote);
+    assert_eq!(span.name, "GET /api/users/me");
+    assert_eq!(span.span_kind, SpanKind::Server);
+    assert_eq!(span.status, Status::Unset);
+    assert!(span.start_time <= span.end_time);
+    assert_eq!(attribute(span, "status"), Some(Value::I64(200)));
+    assert_eq!(attribute(span, "route"), Some(Value::from("/api/users/me")));
+    assert_eq!(
+        attribute(span, "user_id"),
+        Some(Value::from(user["id"].as_str().unwrap().to_string()))
+    );
+    assert!(span
+        .attributes
+        .iter()
+        .all(|attribute| !attribute.key.as_str().starts_with("otel.")));
+}
+
+#[actix_web::test]
+async fn unsampled_traces_are_not_exported() {
+    let traced = traced(1.0);
+    let app = init(&app_state()).await;
+
+    let req = test::TestRequest::get()
+        .uri("/livez")
+        .insert_header(traceparent(false))
+        .to_request();
+    test::call_service(&app, req).await;
+    assert!(traced.spans().is_empty());
+
+    let req = test::TestRequest::get().uri("/livez").to_request();
+    test::call_service(&app, req).await;
+    let spans = traced.spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].parent_span_id, SpanId::INVALID);
+    assert_ne!(spans[0].span_context.trace_id().to_string(), TRACE_ID);
+}
+
+#[actix_web::test]
+async fn span_attributes_are_redacted() {
+    let traced = traced(1.0);
+
+    tracing::info_span!(
+        "work",
+        api_token = "t0k3n",
+        note = "retried with password=hunter2"
+    )
+    .in_scope(|| {});
+
+    let spans = traced.spans();
+    assert_eq!(
+        attribute(&spans[0], "api_token"),
+        Some(Value::from("[REDACTED]"))
+    );
+    assert_eq!(
+        attribute(&spans[0], "note"),
+        Some(Value::from("retried with password=[REDACTED]"))
+    );
+}
+
+/// An HTTP request as received by [`collector`].
+struct Received {
+    request_line: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// Accepts one request and answers 200.
+fn collector() -> (u16, std::thread::JoinHandle<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+        let length = headers["content-length"].parse().unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        Received {
+            request_line,
+            headers,
+            body,
+        }
+    });
+    (port, handle)
+}
+
+fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+#[actix_web::test]
+async fn otlp_exporter_posts_spans_to_the_collector() {
+    let (port, collector) = collector();
+    let config = TracingConfig {
+        enabled: true,
+        otlp_endpoint: format!("http://127.0.0.1:{}/v1/traces", port),
+        otlp_headers: BTreeMap::from([(
+            "authorization".to_string(),
+            "Bearer collector-token".to_string(),
+        )]),
+        service_name: "crud-rust-test".to_string(),
+        sample_ratio: 1.0,
+    };
+    // On a thread of its own, as the blocking HTTP client cannot be dropped
+    // inside the test's runtime.
+    std::thread::spawn(move || {
+        let provider = telemetry::provider(telemetry::otlp_exporter(&config).unwrap(), &config);
+        provider
+            .tracer("test")
+            .in_span("POST /api/auth/register", |_| {});
+        provider.shutdown().unwrap();
+    })
+    .join()
+    .unwrap();
+
+    let received = collector.join().unwrap();
+    assert!(received.request_line.starts_with("POST /v1/traces "));
+    assert_eq!(received.headers["content-type"], "application/x-protobuf");
+    assert_eq!(received.headers["authorization"], "Bearer collector-token");
+    assert!(contains(&received.body, "crud-rust-test"));
+    assert!(contains(&received.body, "POST /api/auth/register"));
+}
@@ -0,0 +1,443 @@
+use actix_web::{
+    http::{header, StatusCode},
+    test,
+};
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+
+use super::{
+    app_state, bearer, init, login, multipart, png, sign_up, sign_up_admin, status, TestApp,
+    PASSWORD,
+};
+
+#[actix_web::test]
+async fn me_requires_authentication() {
+    let app = init(&app_state()).await;
+
+    let req = test::TestRequest::get().uri("/api/users/me").to_request();
+    assert_eq!(status(&app, req).await, 401);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(bearer("not-a-token"))
+        .to_request();
+    assert_eq!(status(&app, req).await, 401);
+}
+
+#[actix_web::test]
+async fn me_supports_conditional_get() {
+    let app = init(&app_state()).await;
+    let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(bearer(&token))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    let etag = res.headers().get(header::ETAG).unwrap().clone();
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["data"]["user"]["id"], user["id"]);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(bearer(&token))
+        .insert_header((header::IF_NONE_MATCH, etag))
+        .to_request();
+    assert_eq!(status(&app, req).await, 304);
+}
+
+#[actix_web::test]
+async fn listing_users_is_admin_only_and_paginated() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (_, user_token) = sign_up(&app, "bob", "bob@example.com").await;
+    sign_up(&app, "carol", "carol@example.com").await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/users")
+        .insert_header(bearer(&user_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 403);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users?page=1&limit=2")
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["result"], 2);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users?page=2&limit=2")
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["result"], 1);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users?limit=0")
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 400);
+}
+
+#[actix_web::test]
+async fn change_password_honours_if_match() {
+    let app = init(&app_state()).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+    let change = json!({
+        "currentPassword": PASSWORD,
+        "newPassword": "new-password",
+        "confirmNewPassword": "new-password",
+    });
+
+    let req = test::TestRequest::patch()
+        .uri("/api/users/me/password")
+        .insert_header(bearer(&token))
+        .insert_header((header::IF_MATCH, "\"0\""))
+        .set_json(&change)
+        .to_request();
+    assert_eq!(status(&app, req).await, 412);
+
+    let req = test::TestRequest::patch()
+        .uri("/api/users/me/password")
+        .insert_header(bearer(&token))
+        .set_json(&change)
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 401);
+    assert_eq!(
+        login(&app, "bob@example.com", "new-password")
+            .await
+            .status(),
+        200
+    );
+}
+
+#[actix_web::test]
+async fn matching_if_match_applies_the_change() {
+    let app = init(&app_state()).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(bearer(&token))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    let etag = res.headers().get(header::ETAG).unwrap().clone();
+
+    let req = test::TestRequest::patch()
+        .uri("/api/users/me/password")
+        .insert_header(bearer(&token))
+        .insert_header((header::IF_MATCH, etag.clone()))
+        .set_json(json!({
+            "currentPassword": PASSWORD,
+            "newPassword": "new-password",
+            "confirmNewPassword": "new-password",
+        }))
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    // The change moved the version on, so the same tag no longer matches.
+    let req = test::TestRequest::patch()
+        .uri("/api/users/me/password")
+        .insert_header(bearer(&token))
+        .insert_header((header::IF_MATCH, etag))
+        .set_json(json!({
+            "currentPassword": "new-password",
+            "newPassword": PASSWORD,
+            "confirmNewPassword": PASSWORD,
+        }))
+        .to_request();
+    assert_eq!(status(&app, req).await, 412);
+}
+
+#[actix_web::test]
+async fn admin_can_change_roles() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (user, user_token) = sign_up(&app, "bob", "bob@example.com").await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+    let uri = format!("/api/users/{}/role", user["id"].as_str().unwrap());
+
+    let req = test::TestRequest::patch()
+        .uri(&uri)
+        .insert_header(bearer(&user_token))
+        .set_json(json!({ "role": "admin" }))
+        .to_request();
+    assert_eq!(status(&app, req).await, 403);
+
+    let req = test::TestRequest::patch()
+        .uri(&uri)
+        .insert_header(bearer(&admin_token))
+        .set_json(json!({ "role": "moderator" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    assert!(res.headers().contains_key(header::ETAG));
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["data"]["user"]["role"], "moderator");
+}
+
+#[actix_web::test]
+async fn api_keys_authenticate_until_revoked() {
+    let app = init(&app_state()).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let req = test::TestRequest::post()
+        .uri("/api/users/me/api-keys")
+        .insert_header(bearer(&token))
+        .set_json(json!({ "name": "ci" }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 201);
+    let body: Value = test::read_body_json(res).await;
+    let key = body["key"].as_str().unwrap().to_string();
+    let key_id = body["data"]["id"].as_str().unwrap().to_string();
+    let api_key = (header::AUTHORIZATION, format!("ApiKey {}", key));
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(api_key.clone())
+        .to_request();
+    assert_eq!(status(&app, req).await, 200);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me/api-keys")
+        .insert_header(bearer(&token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["result"], 1);
+    assert!(!body["data"][0]["lastUsedAt"].is_null());
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/users/me/api-keys/{}", key_id))
+        .insert_header(bearer(&token))
+        .to_request();
+    assert!(status(&app, req).await.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(api_key)
+        .to_request();
+    assert_eq!(status(&app, req).await, 401);
+}
+
+#[actix_web::test]
+async fn deleted_users_are_hidden_and_cannot_log_in() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (user, user_token) = sign_up(&app, "bob", "bob@example.com").await;
+    let (admin, admin_token) = sign_up_admin(&app_state, &app).await;
+    let uri = format!("/api/users/{}", user["id"].as_str().unwrap());
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/users/{}", admin.id))
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 400);
+
+    let req = test::TestRequest::delete()
+        .uri(&uri)
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    assert!(status(&app, req).await.is_success());
+
+    let req = test::TestRequest::get()
+        .uri(&uri)
+        .insert_header(bearer(&admin_token))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["data"]["user"]["deletedAt"].is_string());
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me")
+        .insert_header(bearer(&user_token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 401);
+
+    assert_eq!(login(&app, "bob@example.com", PASSWORD).await.status(), 401);
+}
+
+#[actix_web::test]
+async fn export_contains_profile_and_login_history() {
+    let app = init(&app_state()).await;
+    let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me/export")
+        .insert_header(bearer(&token))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["profile"]["id"], user["id"]);
+    assert_eq!(body["loginHistory"][0]["succeeded"], true);
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me/export")
+        .insert_header(bearer(&token))
+        .to_request();
+    assert_eq!(status(&app, req).await, 429);
+}
+
+#[actix_web::test]
+async fn export_hands_back_the_pending_one_until_it_is_failed() {
+    let mut app_state = app_state();
+    app_state.env.export.async_threshold = 0;
+    app_state.env.export.min_interval_seconds = 0;
+    let app = init(&app_state).await;
+    let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+    let user_id = user["id"].as_str().unwrap().parse().unwrap();
+
+    let expires_at = Utc::now() + Duration::hours(1);
+    let db = &app_state.db_client;
+    let pending = db.start_data_export(user_id, expires_at).await.unwrap();
+    let pending = pending.unwrap();
+    assert!(db
+        .start_data_export(user_id, expires_at)
+        .await
+        .unwrap()
+        .is_none());
+
+    let export = || {
+        test::TestRequest::get()
+            .uri("/api/users/me/export")
+            .insert_header(bearer(&token))
+            .to_request()
+    };
+    let res = test::call_service(&app, export()).await;
+    assert_eq!(res.status(), 202);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["data"]["id"], pending.id.to_string());
+
+    assert_eq!(db.fail_stale_data_exports(60).await.unwrap(), 0);
+    assert_eq!(db.fail_stale_data_exports(-60).await.unwrap(), 1);
+
+    let res = test::call_service(&app, export()).await;
+    assert_eq!(res.status(), 202);
+    let body: Value = test::read_body_json(res).await;
+    assert_ne!(body["data"]["id"], pending.id.to_string());
+}
+
+#[actix_web::test]
+async fn export_hides_who_acted_on_the_user() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (user, token) = sign_up(&app, "bob", "bob@example.com").await;
+    let (_, admin_token) = sign_up_admin(&app_state, &app).await;
+    let id = user["id"].as_str().unwrap();
+
+    for action in ["suspend", "unsuspend"] {
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/admin/users/{}/{}", id, action))
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .insert_header(bearer(&admin_token))
+            .insert_header((header::USER_AGENT, "AdminBrowser/1.0"))
+            .to_request();
+        assert_eq!(status(&app, req).await, 200);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/users/me/export")
+        .insert_header(bearer(&token))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 200);
+    let body: Value = test::read_body_json(res).await;
+
+    let events = body["auditEvents"].as_array().unwrap();
+    let suspended = events
+        .iter()
+        .find(|event| event["action"] == "user.suspended")
+        .unwrap();
+    assert_eq!(suspended["targetId"], user["id"]);
+    assert_eq!(suspended["actorId"], Value::Null);
+    assert_eq!(suspended["ipAddress"], Value::Null);
+    assert_eq!(suspended["userAgent"], Value::Null);
+
+    let body = body.to_string();
+    assert!(!body.contains("203.0.113.7"));
+    assert!(!body.contains("AdminBrowser"));
+}
+
+async fn upload(
+    app: &impl TestApp,
+    token: &str,
+    (content_type, body): (String, Vec<u8>),
+) -> StatusCode {
+    let req = test::TestRequest::post()
+        .uri("/api/users/me/photo")
+        .insert_header(bearer(token))
+        .insert_header((header::CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    status(app, req).await
+}
+
+#[actix_web::test]
+async fn photo_upload_rejects_every_other_field() {
+    let app = init(&app_state()).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+    let png = png();
+
+    for parts in [
+        vec![
+            ("note", "text/plain", &b"hi"[..]),
+            ("photo", "image/png", &png),
+        ],
+        vec![
+            ("photo", "image/png", &png),
+            ("note", "text/plain", &b"hi"[..]),
+        ],
+        vec![("photo", "image/png", &png), ("photo", "image/png", &png)],
+    ] {
+        assert_eq!(upload(&app, &token, multipart(&parts)).await, 400);
+    }
+    assert_eq!(
+        upload(&app, &token, multipart(&[("photo", "image/png", &png)])).await,
+        200
+    );
+}
+
+#[actix_web::test]
+async fn photo_upload_bounds_the_whole_body() {
+    let mut app_state = app_state();
+    app_state.env.avatar.max_bytes = 1024;
+    let app = init(&app_state).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    // Text before the first boundary is skipped by the parser, but still has
+    // to be read.
+    let (content_type, part) = multipart(&[("photo", "image/png", &png())]);
+    let mut body = vec![b'x'; 64 * 1024];
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(&part);
+    assert_eq!(upload(&app, &token, (content_type, body)).await, 413);
+}
+
+#[actix_web::test]
+async fn replacing_a_photo_deletes_the_previous_one() {
+    let app_state = app_state();
+    let app = init(&app_state).await;
+    let (_, token) = sign_up(&app, "bob", "bob@example.com").await;
+
+    let mut keys = Vec::new();
+    for _ in 0..2 {
+        let (content_type, body) = multipart(&[("photo", "image/png", &png())]);
+        let req = test::TestRequest::post()
+            .uri("/api/users/me/photo")
+            .insert_header(bearer(&token))
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let photo = body["data"]["user"]["photo"].as_str().unwrap();
+        keys.push(photo.trim_start_matches("/uploads/").to_string());
+    }
+
+    assert!(!app_state.storage.exists(&keys[0]).await.unwrap());
+    assert!(app_state.storage.exists(&keys[1]).await.unwrap());
+}
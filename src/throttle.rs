@@ -0,0 +1,62 @@
+//! Throttling of failed Basic auth attempts. Every request carries the
+//! password, so without a limit a client could guess passwords as fast as
+//! Argon2 lets it, bypassing anything done at the login endpoint.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::BasicAuthConfig;
+
+/// Counts failed attempts per client address. Once a client reaches
+/// `basic_auth.max_failures` within `basic_auth.failure_window_seconds`, its
+/// Basic auth requests are refused until that window ends.
+///
+/// An attempt counts as failed from the moment it starts, and is only taken
+/// back once it succeeds, so concurrent attempts cannot all get past the
+/// limit while the first ones are still being checked. A success never
+/// clears the failures of other attempts: a client with one valid account
+/// cannot use it to reset the count between guesses at others.
+#[derive(Debug, Clone, Default)]
+pub struct FailureThrottle {
+    failures: Arc<Mutex<HashMap<IpAddr, Failures>>>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    window_started: Instant,
+}
+
+impl FailureThrottle {
+    /// Counts an attempt by `client` as failed until [`release`](Self::release)
+    /// says otherwise, or returns how long it has to wait when it is over the
+    /// limit.
+    pub fn reserve(&self, client: IpAddr, config: &BasicAuthConfig) -> Result<(), Duration> {
+        let window = Duration::from_secs(config.failure_window_seconds);
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, entry| now.duration_since(entry.window_started) < window);
+
+        let entry = failures.entry(client).or_insert(Failures {
+            count: 0,
+            window_started: now,
+        });
+        if entry.count >= config.max_failures {
+            return Err(window - now.duration_since(entry.window_started));
+        }
+        entry.count += 1;
+        Ok(())
+    }
+
+    /// Takes back the failure [`reserve`](Self::reserve) counted, for an
+    /// attempt that succeeded or could not be checked.
+    pub fn release(&self, client: IpAddr) {
+        if let Some(entry) = self.failures.lock().unwrap().get_mut(&client) {
+            entry.count = entry.count.saturating_sub(1);
+        }
+    }
+}
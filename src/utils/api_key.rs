@@ -0,0 +1,24 @@
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+const KEY_PREFIX: &str = "crk_";
+const DISPLAY_PREFIX_LENGTH: usize = 12;
+
+/// Generates a new API key, returning the key itself (shown to the user once)
+/// and the short prefix kept for display.
+pub fn generate() -> (String, String) {
+    let key = format!(
+        "{}{}{}",
+        KEY_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let prefix = key[..DISPLAY_PREFIX_LENGTH].to_string();
+    (key, prefix)
+}
+
+/// Keys are long random strings rather than passwords, so a fast hash is
+/// enough and lets us look them up by hash.
+pub fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
@@ -0,0 +1,54 @@
+use actix_web::http::header::HeaderValue;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::error::ErrorMessage;
+
+/// Credentials carried by an `Authorization` header.
+#[derive(Debug, PartialEq)]
+pub enum Credentials {
+    /// `Bearer <jwt>`
+    Bearer(String),
+    /// `ApiKey <key>`
+    ApiKey(String),
+    /// `Basic base64(<email>:<password>)`
+    Basic { email: String, password: String },
+}
+
+/// Parses an `Authorization` header. Scheme names are case-insensitive.
+pub fn parse(value: &HeaderValue) -> Result<Credentials, ErrorMessage> {
+    let value = value
+        .to_str()
+        .map_err(|_| ErrorMessage::MalformedAuthorizationHeader)?;
+
+    let (scheme, credentials) = value
+        .trim()
+        .split_once(' ')
+        .ok_or(ErrorMessage::MalformedAuthorizationHeader)?;
+
+    let credentials = credentials.trim();
+    if credentials.is_empty() {
+        return Err(ErrorMessage::MalformedAuthorizationHeader);
+    }
+
+    match scheme.to_ascii_lowercase().as_str() {
+        "bearer" => Ok(Credentials::Bearer(credentials.to_string())),
+        "apikey" => Ok(Credentials::ApiKey(credentials.to_string())),
+        "basic" => {
+            let decoded = STANDARD
+                .decode(credentials)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or(ErrorMessage::MalformedAuthorizationHeader)?;
+
+            let (email, password) = decoded
+                .split_once(':')
+                .ok_or(ErrorMessage::MalformedAuthorizationHeader)?;
+
+            Ok(Credentials::Basic {
+                email: email.to_string(),
+                password: password.to_string(),
+            })
+        }
+        _ => Err(ErrorMessage::UnsupportedAuthScheme),
+    }
+}
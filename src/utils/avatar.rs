@@ -0,0 +1,61 @@
+use std::io::Cursor;
+
+use actix_web::web;
+use image::{imageops::FilterType, ImageFormat, ImageReader, Limits};
+
+use crate::error::ErrorMessage;
+
+/// Largest width or height accepted before resizing. Keeps a tiny upload from
+/// decompressing into a huge bitmap.
+const MAX_DIMENSION: u32 = 8192;
+
+pub const ALLOWED_CONTENT_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Detects the image format from the leading bytes of the file. The declared
+/// content type is only a claim by the client; this is what we decode with.
+pub fn detect_format(bytes: &[u8]) -> Option<ImageFormat> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageFormat::Png)
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::Jpeg)
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some(ImageFormat::Gif)
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some(ImageFormat::WebP)
+    } else {
+        None
+    }
+}
+
+/// Decodes `bytes`, crops it to a square and re-encodes it as a `size` by
+/// `size` PNG. Re-encoding drops metadata such as EXIF location data and
+/// anything smuggled in after the image data.
+pub fn normalize(bytes: &[u8], format: ImageFormat, size: u32) -> Result<Vec<u8>, ErrorMessage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+
+    let image = reader.decode().map_err(|_| ErrorMessage::InvalidImage)?;
+
+    let mut output = Vec::new();
+    image
+        .resize_to_fill(size, size, FilterType::Lanczos3)
+        .write_to(&mut Cursor::new(&mut output), ImageFormat::Png)
+        .map_err(|_| ErrorMessage::InvalidImage)?;
+
+    Ok(output)
+}
+
+/// Runs [`normalize`] on the blocking thread pool so the actix worker stays free.
+pub async fn normalize_blocking(
+    bytes: Vec<u8>,
+    format: ImageFormat,
+    size: u32,
+) -> Result<Vec<u8>, ErrorMessage> {
+    web::block(move || normalize(&bytes, format, size))
+        .await
+        .map_err(|_| ErrorMessage::InvalidImage)?
+}
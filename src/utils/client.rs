@@ -0,0 +1,82 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::HttpRequest;
+
+/// The address of the client that sent `req`. That is the peer, unless the
+/// peer is one of `trusted_proxies`: then it is the client the proxy reports
+/// in `Forwarded` or `X-Forwarded-For`. Those proxies must overwrite the
+/// headers rather than append to what the client sent, or clients can pick
+/// their own address. `None` when it cannot be told.
+pub fn address(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    parse(req.connection_info().realip_remote_addr()?)
+}
+
+/// Reads an address with or without a port, as found in forwarding headers.
+fn parse(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| value.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test::TestRequest};
+
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1";
+
+    fn request(peer: &str) -> TestRequest {
+        TestRequest::default().peer_addr(format!("{}:4000", peer).parse().unwrap())
+    }
+
+    #[test]
+    fn forwarding_headers_are_ignored_from_untrusted_peers() {
+        let req = request("203.0.113.7")
+            .insert_header(("x-forwarded-for", "198.51.100.1"))
+            .to_http_request();
+        assert_eq!(
+            address(&req, &[PROXY.parse().unwrap()]),
+            Some("203.0.113.7".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_report_the_client() {
+        let proxies = [PROXY.parse().unwrap()];
+        let req = request(PROXY)
+            .insert_header(("x-forwarded-for", "198.51.100.1, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(
+            address(&req, &proxies),
+            Some("198.51.100.1".parse().unwrap())
+        );
+
+        let req = request(PROXY)
+            .insert_header((header::FORWARDED, "for=\"[2001:db8::1]:4711\""))
+            .to_http_request();
+        assert_eq!(
+            address(&req, &proxies),
+            Some("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn unknown_without_a_peer_or_a_usable_header() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(address(&req, &[]), None);
+
+        let req = request(PROXY)
+            .insert_header(("x-forwarded-for", "unknown"))
+            .to_http_request();
+        assert_eq!(address(&req, &[PROXY.parse().unwrap()]), None);
+    }
+}
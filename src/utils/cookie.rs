@@ -0,0 +1,69 @@
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+
+use crate::config::{CookieConfig, SameSitePolicy};
+
+fn build(
+    config: &CookieConfig,
+    name: String,
+    value: String,
+    max_age: Duration,
+    http_only: bool,
+) -> Cookie<'static> {
+    let mut builder = Cookie::build(name, value)
+        .path(config.path.clone())
+        .http_only(http_only)
+        .secure(config.secure)
+        .same_site(match config.same_site {
+            SameSitePolicy::Strict => SameSite::Strict,
+            SameSitePolicy::Lax => SameSite::Lax,
+            SameSitePolicy::None => SameSite::None,
+        })
+        .max_age(max_age);
+
+    if let Some(domain) = &config.domain {
+        builder = builder.domain(domain.clone());
+    }
+
+    builder.finish()
+}
+
+/// The auth cookie carrying `token`. It expires together with the token,
+/// `max_age_seconds` being the same lifetime the JWT was issued with.
+pub fn auth_cookie(config: &CookieConfig, token: String, max_age_seconds: i64) -> Cookie<'static> {
+    build(
+        config,
+        config.cookie_name(),
+        token,
+        Duration::seconds(max_age_seconds),
+        true,
+    )
+}
+
+/// A cookie that makes the browser drop the auth cookie. Name, path and domain
+/// must match the ones it was set with or the browser keeps the old one.
+pub fn removal_cookie(config: &CookieConfig) -> Cookie<'static> {
+    build(
+        config,
+        config.cookie_name(),
+        String::new(),
+        Duration::seconds(-1),
+        true,
+    )
+}
+
+/// The CSRF cookie shares the auth cookie's scope but is not `HttpOnly`, since
+/// the frontend has to read it.
+pub fn csrf_cookie(
+    config: &CookieConfig,
+    name: String,
+    token: String,
+    max_age_seconds: i64,
+) -> Cookie<'static> {
+    build(
+        config,
+        name,
+        token,
+        Duration::seconds(max_age_seconds),
+        false,
+    )
+}
@@ -0,0 +1,69 @@
+use crate::config::EmailConfig;
+
+/// Brings an email address into the form it is stored and looked up in:
+/// surrounding whitespace is dropped and the domain, which is
+/// case-insensitive, is lowercased. The local part is only lowercased when
+/// `email.lowercase_local_part` is set, since strictly it is case-sensitive.
+///
+/// Every place an email enters the system goes through here, so register,
+/// login and lookups agree on what the same address is.
+pub fn normalize(email: &str, config: &EmailConfig) -> String {
+    let email = email.trim();
+
+    if config.lowercase_local_part {
+        return email.to_lowercase();
+    }
+
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, domain.to_lowercase()),
+        None => email.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(lowercase_local_part: bool) -> EmailConfig {
+        EmailConfig {
+            lowercase_local_part,
+        }
+    }
+
+    #[test]
+    fn trims_surrounding_whitespace() {
+        assert_eq!(
+            normalize("  bob@example.com\t\n", &config(false)),
+            "bob@example.com"
+        );
+    }
+
+    #[test]
+    fn lowercases_the_domain_only() {
+        assert_eq!(
+            normalize("Bob.Smith@EXAMPLE.Com", &config(false)),
+            "Bob.Smith@example.com"
+        );
+    }
+
+    #[test]
+    fn splits_on_the_last_at_sign() {
+        assert_eq!(
+            normalize("\"Bob@Home\"@Example.COM", &config(false)),
+            "\"Bob@Home\"@example.com"
+        );
+    }
+
+    #[test]
+    fn lowercase_local_part_lowercases_everything() {
+        assert_eq!(
+            normalize(" Bob.Smith@EXAMPLE.com ", &config(true)),
+            "bob.smith@example.com"
+        );
+    }
+
+    #[test]
+    fn leaves_addresses_without_a_domain_alone() {
+        assert_eq!(normalize(" Bob ", &config(false)), "Bob");
+    }
+}
@@ -1,2 +1,8 @@
+pub mod api_key;
+pub mod authorization;
+pub mod avatar;
+pub mod client;
+pub mod cookie;
+pub mod email;
 pub mod password;
 pub mod token;
@@ -1,14 +1,53 @@
+use std::time::Instant;
+
+use actix_web::web;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, KeyId, Params, ParamsBuilder, Version,
 };
+use sha2::{Digest, Sha256};
 
-use crate::error::ErrorMessage;
+use crate::{config::PasswordConfig, error::ErrorMessage, metrics::METRICS};
 
 const MAX_PASSWORD_LENGTH: usize = 64;
 
-pub fn hash(password: impl Into<String>) -> Result<String, ErrorMessage> {
-    let password = password.into();
+/// Length of the pepper fingerprint stored as the `keyid` of a peppered hash.
+const KEYID_LEN: usize = 4;
+
+/// Identifies the pepper in the PHC string without revealing it, so a hash
+/// records whether, and with which pepper, it was made.
+fn keyid(pepper: &str) -> Vec<u8> {
+    Sha256::digest(pepper.as_bytes())[..KEYID_LEN].to_vec()
+}
+
+fn hasher(config: &PasswordConfig) -> Result<Argon2<'_>, ErrorMessage> {
+    let mut params = ParamsBuilder::new();
+    params
+        .m_cost(config.memory_kib)
+        .t_cost(config.iterations)
+        .p_cost(config.parallelism);
+    if let Some(pepper) = &config.pepper {
+        params.keyid(KeyId::new(&keyid(pepper)).map_err(|_| ErrorMessage::HashingError)?);
+    }
+    let params = params.build().map_err(|_| ErrorMessage::HashingError)?;
+
+    verifier(config.pepper.as_deref(), params)
+}
+
+fn verifier(pepper: Option<&str>, params: Params) -> Result<Argon2<'_>, ErrorMessage> {
+    match pepper {
+        Some(pepper) => Argon2::new_with_secret(
+            pepper.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            params,
+        )
+        .map_err(|_| ErrorMessage::HashingError),
+        None => Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)),
+    }
+}
+
+fn check_length(password: &str) -> Result<(), ErrorMessage> {
     if password.is_empty() {
         return Err(ErrorMessage::EmptyPassword);
     }
@@ -17,32 +56,131 @@ pub fn hash(password: impl Into<String>) -> Result<String, ErrorMessage> {
         return Err(ErrorMessage::ExceededMaxPasswordLength(MAX_PASSWORD_LENGTH));
     }
 
+    Ok(())
+}
+
+pub fn hash(password: impl Into<String>, config: &PasswordConfig) -> Result<String, ErrorMessage> {
+    let password = password.into();
+    check_length(&password)?;
+
     let salt = SaltString::generate(&mut OsRng);
 
-    let argon2 = Argon2::default();
-    let hashed_password = argon2
+    let started = Instant::now();
+    let hashed_password = hasher(config)?
         .hash_password(password.as_bytes(), &salt)
         .map_err(|_| ErrorMessage::HashingError)?
         .to_string();
+    METRICS.password_hash("hash", started.elapsed());
 
     Ok(hashed_password)
 }
 
-pub fn compare(password: &str, hashed_password: &str) -> Result<bool, ErrorMessage> {
-    if password.is_empty() {
-        return Err(ErrorMessage::EmptyPassword);
-    }
-
-    if password.len() > MAX_PASSWORD_LENGTH {
-        return Err(ErrorMessage::ExceededMaxPasswordLength(MAX_PASSWORD_LENGTH));
-    }
+pub fn compare(
+    password: &str,
+    hashed_password: &str,
+    config: &PasswordConfig,
+) -> Result<bool, ErrorMessage> {
+    check_length(password)?;
 
     let parsed_hash =
         PasswordHash::new(hashed_password).map_err(|_| ErrorMessage::InvalidHashFormat)?;
 
-    let password_matches = Argon2::default()
-        .verify_password(password.as_bytes(), &parsed_hash)
-        .map_or(false, |_| true);
+    // Verification reads the cost parameters from the stored hash, so hashes
+    // created with older settings still verify. A hash with a `keyid` was
+    // peppered; one without was made before the pepper was configured, or by
+    // a version that did not record it, so both ways are tried.
+    let peppers: &[Option<&str>] = match (config.pepper.as_deref(), has_keyid(&parsed_hash)) {
+        (Some(pepper), true) => &[Some(pepper)],
+        (Some(pepper), false) => &[None, Some(pepper)],
+        (None, _) => &[None],
+    };
+
+    let started = Instant::now();
+    let mut password_matches = false;
+    for pepper in peppers {
+        if verifier(*pepper, Params::default())?
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok()
+        {
+            password_matches = true;
+            break;
+        }
+    }
+    METRICS.password_hash("verify", started.elapsed());
 
     Ok(password_matches)
 }
+
+fn has_keyid(hash: &PasswordHash<'_>) -> bool {
+    hash.params.get("keyid").is_some()
+}
+
+/// Returns `true` when `hashed_password` was produced with a different
+/// algorithm, version, cost parameters or pepper than the ones currently
+/// configured. Hashes from before the pepper was enabled are upgraded this way.
+pub fn needs_rehash(hashed_password: &str, config: &PasswordConfig) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hashed_password) else {
+        return true;
+    };
+
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident()
+        || parsed_hash.version != Some(Version::V0x13.into())
+    {
+        return true;
+    }
+
+    let expected_keyid = config.pepper.as_deref().map(keyid).unwrap_or_default();
+    match Params::try_from(&parsed_hash) {
+        Ok(params) => {
+            params.m_cost() != config.memory_kib
+                || params.t_cost() != config.iterations
+                || params.p_cost() != config.parallelism
+                || params.keyid() != expected_keyid.as_slice()
+        }
+        Err(_) => true,
+    }
+}
+
+/// Runs [`hash`] on the blocking thread pool so the actix worker stays free.
+pub async fn hash_blocking(
+    password: impl Into<String>,
+    config: &PasswordConfig,
+) -> Result<String, ErrorMessage> {
+    let password = password.into();
+    let config = config.clone();
+
+    web::block(move || hash(password, &config))
+        .await
+        .map_err(|_| ErrorMessage::HashingError)?
+}
+
+/// Spends as long as [`compare_blocking`] would on a hash made with the
+/// current settings, for when there is no hash to compare with. Answering an
+/// unknown email faster than a wrong password would reveal which emails have
+/// accounts.
+pub async fn dummy_compare_blocking(password: impl Into<String>, config: &PasswordConfig) {
+    let password = password.into();
+    let config = config.clone();
+
+    // Hashing runs the same Argon2 computation as verifying.
+    let _ = web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        hasher(&config).map(|argon2| argon2.hash_password(password.as_bytes(), &salt).is_ok())
+    })
+    .await;
+}
+
+/// Runs [`compare`] on the blocking thread pool so the actix worker stays free.
+pub async fn compare_blocking(
+    password: impl Into<String>,
+    hashed_password: impl Into<String>,
+    config: &PasswordConfig,
+) -> Result<bool, ErrorMessage> {
+    let password = password.into();
+    let hashed_password = hashed_password.into();
+    let config = config.clone();
+
+    web::block(move || compare(&password, &hashed_password, &config))
+        .await
+        .map_err(|_| ErrorMessage::HashingError)?
+}